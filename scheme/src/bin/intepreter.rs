@@ -8,17 +8,29 @@ struct Cli {
     path: Option<std::path::PathBuf>,
     /// Interpret some string
     expr: Option<String>,
+    /// Compile to bytecode and run it on a virtual machine instead of walking the syntax tree
+    #[clap(long)]
+    vm: bool,
+    /// Analyze each form once before running it instead of walking the syntax tree
+    #[clap(long, conflicts_with = "vm")]
+    analyze: bool,
 }
 
 fn main() {
     let args = Cli::parse();
     let mut interpreter = scheme::interpreter::Interpreter::new();
+    if args.vm {
+        interpreter.set_backend(scheme::interpreter::Backend::Vm);
+    }
+    if args.analyze {
+        interpreter.set_backend(scheme::interpreter::Backend::Analyzer);
+    }
     if let Some(expr) = args.expr {
         let v = interpreter.interpret(&expr).unwrap();
-        println!("{:?}", v);
+        println!("{}", v);
     } else if let Some(path) = args.path {
         let v = interpreter.interpret_file(path).unwrap();
-        println!("{:?}", v);
+        println!("{}", v);
     } else {
         interpreter.interpret_repl();
     }
@@ -0,0 +1,833 @@
+use std::{cell::OnceCell, collections::HashSet, rc::Rc};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    environment::Environment,
+    equivalence,
+    gc::{self, Gc},
+    procedure::{Closure, Params},
+    symbol::Symbol,
+    Interpreter,
+};
+
+/// A form analyzed once for all the times it runs: its special forms are told apart,
+/// its syntax is checked and its variables are resolved.
+#[derive(Debug)]
+pub enum Node {
+    Const(LispVal),
+    /// a variable at a known position: the `slot`-th binding of the frame `depth`
+    /// frames out
+    Local {
+        depth: usize,
+        slot: usize,
+        name: Symbol,
+    },
+    /// a variable looked up by name, skipping the `depth` innermost frames which are
+    /// known not to bind it
+    Free {
+        depth: usize,
+        name: Symbol,
+    },
+    Define(Symbol, Rc<Node>),
+    Lambda(Rc<Lambda>),
+    CaseLambda(Vec<Rc<Lambda>>),
+    /// `(if test then [otherwise])`
+    If(Rc<Node>, Rc<Node>, Option<Rc<Node>>),
+    /// a non-empty sequence, the last node of which is in tail position
+    Sequence(Vec<Rc<Node>>),
+    And(Vec<Rc<Node>>),
+    Or(Vec<Rc<Node>>),
+    /// the clauses of a `cond`, as a test (none for `else`) and what a true test leads
+    /// to (none for the value of the test itself)
+    Cond(Vec<(Option<Rc<Node>>, Option<Consequent>)>),
+    /// the key and the clauses of a `case`, with their data (none for `else`)
+    Case(Rc<Node>, Vec<(Option<Vec<LispVal>>, Consequent)>),
+    /// `(do ((var init [step]) ...) (test expr ...) command ...)`, which binds its
+    /// variables in a new frame each iteration. Variables without a step keep their
+    /// value, their step is the variable itself
+    Do {
+        variables: Vec<Symbol>,
+        inits: Vec<Rc<Node>>,
+        steps: Vec<Rc<Node>>,
+        test: Rc<Node>,
+        /// the expressions after the test, if any
+        result: Option<Rc<Node>>,
+        commands: Vec<Rc<Node>>,
+    },
+    /// `name` is what the procedure was called by, for error messages
+    Call {
+        name: Symbol,
+        operator: Rc<Node>,
+        operands: Vec<Rc<Node>>,
+    },
+    /// a form left to the tree-walker
+    Interpret(LispVal),
+}
+
+/// What a selected `cond` or `case` clause evaluates.
+#[derive(Debug)]
+pub enum Consequent {
+    Body(Rc<Node>),
+    /// `=> receiver`, which is called with the value of the test or the key
+    Receiver(Rc<Node>),
+}
+
+/// A `lambda` expression, analyzed once for all the closures it makes.
+#[derive(Debug)]
+pub struct Lambda {
+    params: Params,
+    body: Rc<[LispVal]>,
+    analyzed: Rc<Node>,
+}
+
+impl Lambda {
+    pub fn instantiate(&self, env: Environment) -> Closure {
+        Closure {
+            params: self.params.clone(),
+            body: self.body.clone(),
+            env,
+            code: OnceCell::new(),
+            analyzed: OnceCell::from(self.analyzed.clone()),
+        }
+    }
+}
+
+/// Analyzes a top-level form. Nothing is known of the environment it runs in, so
+/// the variables it refers to outside its own lambdas are looked up by name.
+pub fn analyze(form: &LispVal) -> Result<Node, String> {
+    Analyzer::default().expr(form)
+}
+
+/// Analyzes the body of a closure made by the tree-walker, resolving its parameters.
+/// A body with a syntax error is left to the tree-walker, to fail when it runs.
+pub fn analyze_body(params: &Params, body: &[LispVal]) -> Node {
+    let mut analyzer = Analyzer {
+        frames: vec![Frame::new(params, body)],
+    };
+    analyzer.sequence(body).unwrap_or_else(|_| {
+        Node::Sequence(
+            body.iter()
+                .map(|v| Rc::new(Node::Interpret(v.clone())))
+                .collect(),
+        )
+    })
+}
+
+/// The forms the analysis leaves to the tree-walker. Most of them bind variables
+/// in frames of their own, which the analysis would need to model to resolve them.
+const INTERPRETED_FORMS: &[&str] = &[
+    "define-record-type",
+    "define-values",
+    "parameterize",
+    "let-values",
+    "receive",
+    "import",
+    "define-library",
+    "include",
+    "delay",
+    "delay-force",
+    "stream-cons",
+    "cons-stream",
+];
+
+/// The forms that bind variables the analysis cannot name in the frame they run in.
+const OPENING_FORMS: &[&str] = &[
+    "define-record-type",
+    "define-values",
+    "import",
+    "define-library",
+    "include",
+];
+
+/// What the analysis knows of a frame its code runs in.
+struct Frame {
+    /// the parameters, in the order `bind_params` binds them
+    params: Vec<Symbol>,
+    /// the variables the body may define in the frame, after the parameters
+    defined: HashSet<Symbol>,
+    /// whether the body may also define variables the analysis cannot name
+    open: bool,
+}
+
+impl Frame {
+    fn new(params: &Params, body: &[LispVal]) -> Frame {
+        let params = params
+            .required
+            .iter()
+            .copied()
+            .chain(params.optional.iter().map(|(var, _)| *var))
+            .chain(params.named.iter().map(|(_, var, _)| *var))
+            .chain(params.rest)
+            .collect();
+        Frame::binding(params, body)
+    }
+
+    /// The frame of code binding `params`, like a `do` loop.
+    fn binding<'a>(params: Vec<Symbol>, code: impl IntoIterator<Item = &'a LispVal>) -> Frame {
+        let mut frame = Frame {
+            params,
+            defined: HashSet::new(),
+            open: false,
+        };
+        code.into_iter().for_each(|v| frame.scan(v));
+        frame
+    }
+
+    /// Notes the definitions anywhere in `v`. Those of nested lambdas are noted as
+    /// well, which only costs some lookups by name.
+    fn scan(&mut self, v: &LispVal) {
+        let LispVal::List(l) = v else {
+            return;
+        };
+        match l.as_slice() {
+            [LispVal::Atom(s), ..] if s == "quote" => {}
+            [LispVal::Atom(s), target, ..] if s == "define" || s == "define*" => {
+                match target {
+                    LispVal::Atom(name) => {
+                        self.defined.insert(*name);
+                    }
+                    LispVal::List(signature) => {
+                        if let Some(LispVal::Atom(name)) = signature.first() {
+                            self.defined.insert(*name);
+                        }
+                    }
+                    _ => {}
+                }
+                l[2..].iter().for_each(|v| self.scan(v));
+            }
+            [LispVal::Atom(s), ..] if OPENING_FORMS.contains(&s.name()) => self.open = true,
+            l => l.iter().for_each(|v| self.scan(v)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Analyzer {
+    /// the frames of the lambdas being analyzed, innermost last
+    frames: Vec<Frame>,
+}
+
+impl Analyzer {
+    fn resolve(&self, name: Symbol) -> Node {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if frame.open || frame.defined.contains(&name) {
+                return Node::Free { depth, name };
+            }
+            if let Some(slot) = frame.params.iter().rposition(|p| *p == name) {
+                return Node::Local { depth, slot, name };
+            }
+        }
+        Node::Free {
+            depth: self.frames.len(),
+            name,
+        }
+    }
+
+    fn expr(&mut self, v: &LispVal) -> Result<Node, String> {
+        match v {
+            LispVal::Atom(s) => Ok(self.resolve(*s)),
+            LispVal::List(l) => self.list(v, l),
+            v => Ok(Node::Const(v.clone())),
+        }
+    }
+
+    fn exprs(&mut self, v: &[LispVal]) -> Result<Vec<Rc<Node>>, String> {
+        v.iter().map(|v| self.expr(v).map(Rc::new)).collect()
+    }
+
+    fn sequence(&mut self, body: &[LispVal]) -> Result<Node, String> {
+        if body.is_empty() {
+            return Err("empty body".to_string());
+        }
+        Ok(Node::Sequence(self.exprs(body)?))
+    }
+
+    fn list(&mut self, form: &LispVal, v: &[LispVal]) -> Result<Node, String> {
+        let Some((operator, operands)) = v.split_first() else {
+            return Ok(Node::Const(LispVal::List(Vec::new())));
+        };
+        let LispVal::Atom(s) = operator else {
+            return self.application(Symbol::intern("procedure"), operator, operands);
+        };
+        match (s.name(), operands) {
+            ("quote", [datum]) => Ok(Node::Const(datum.to_datum())),
+            ("quote", _) => Err("quote expects exactly one datum".to_string()),
+            ("if", []) => Err("if must have a condition".to_string()),
+            ("if", [test, then]) => Ok(Node::If(
+                Rc::new(self.expr(test)?),
+                Rc::new(self.expr(then)?),
+                None,
+            )),
+            ("if", [test, then, otherwise]) => Ok(Node::If(
+                Rc::new(self.expr(test)?),
+                Rc::new(self.expr(then)?),
+                Some(Rc::new(self.expr(otherwise)?)),
+            )),
+            ("if", _) => Err("if must have 2 or 3 arguments".to_string()),
+            ("define", [LispVal::Atom(name), value]) => {
+                Ok(Node::Define(*name, Rc::new(self.expr(value)?)))
+            }
+            ("define", [LispVal::Atom(_), ..]) => {
+                Err("define expects a variable and a value".to_string())
+            }
+            ("define", [LispVal::List(_), ..]) => self.define_function(operands, false),
+            ("define", _) => Err("unknown define".to_string()),
+            ("define*", _) => self.define_function(operands, true),
+            ("lambda" | "lambda*", _) => {
+                let (formals, body) = operands
+                    .split_first()
+                    .ok_or("lambda must have a parameter list")?;
+                Ok(Node::Lambda(self.lambda(formals, body, s == "lambda*")?))
+            }
+            ("case-lambda", clauses) => clauses
+                .iter()
+                .map(|clause| match clause {
+                    LispVal::List(clause) if !clause.is_empty() => {
+                        self.lambda(&clause[0], &clause[1..], false)
+                    }
+                    _ => Err(format!("case-lambda: bad clause {}", clause)),
+                })
+                .collect::<Result<Vec<_>, String>>()
+                .map(Node::CaseLambda),
+            ("begin", []) => Ok(Node::Const(LispVal::Unspecified)),
+            ("begin", body) => self.sequence(body),
+            ("and", tests) => Ok(Node::And(self.exprs(tests)?)),
+            ("or", tests) => Ok(Node::Or(self.exprs(tests)?)),
+            ("when" | "unless", v) => {
+                let [test, body @ ..] = v else {
+                    return Err(format!("{} expects a test", s));
+                };
+                if body.is_empty() {
+                    return Err(format!("{} expects at least one expression", s));
+                }
+                let test = Rc::new(self.expr(test)?);
+                let body = Rc::new(self.sequence(body)?);
+                let skip = Rc::new(Node::Const(LispVal::Unspecified));
+                Ok(match s == "when" {
+                    true => Node::If(test, body, Some(skip)),
+                    false => Node::If(test, skip, Some(body)),
+                })
+            }
+            ("cond", clauses) => self.cond(clauses),
+            ("case", v) => self.case(v),
+            ("do", v) => self.do_loop(v),
+            (s, _) if INTERPRETED_FORMS.contains(&s) => Ok(Node::Interpret(form.clone())),
+            (s, operands) => self.application(Symbol::intern(s), operator, operands),
+        }
+    }
+
+    fn application(
+        &mut self,
+        name: Symbol,
+        operator: &LispVal,
+        operands: &[LispVal],
+    ) -> Result<Node, String> {
+        Ok(Node::Call {
+            name,
+            operator: Rc::new(self.expr(operator)?),
+            operands: self.exprs(operands)?,
+        })
+    }
+
+    fn lambda(
+        &mut self,
+        formals: &LispVal,
+        body: &[LispVal],
+        extended: bool,
+    ) -> Result<Rc<Lambda>, String> {
+        let params = Params::from_formals(formals, extended)?;
+        if body.is_empty() {
+            return Err("procedure body must not be empty".to_string());
+        }
+        self.frames.push(Frame::new(&params, body));
+        let analyzed = self.sequence(body);
+        self.frames.pop();
+        Ok(Rc::new(Lambda {
+            params,
+            body: body.into(),
+            analyzed: Rc::new(analyzed?),
+        }))
+    }
+
+    /// `(define (name . formals) body ...)`, or `define*` when `extended`.
+    fn define_function(&mut self, v: &[LispVal], extended: bool) -> Result<Node, String> {
+        let Some(LispVal::List(signature)) = v.first() else {
+            return Err("define function must have signatures".to_string());
+        };
+        let Some((LispVal::Atom(name), formals)) = signature.split_first() else {
+            return Err("define function must have a name".to_string());
+        };
+        let lambda = self.lambda(&LispVal::List(formals.to_vec()), &v[1..], extended)?;
+        Ok(Node::Define(*name, Rc::new(Node::Lambda(lambda))))
+    }
+
+    /// The clause of a `cond` or `case` after its test or data.
+    fn consequent(&mut self, who: &str, body: &[LispVal]) -> Result<Consequent, String> {
+        match body {
+            [LispVal::Atom(arrow), receiver] if arrow == "=>" => {
+                Ok(Consequent::Receiver(Rc::new(self.expr(receiver)?)))
+            }
+            [LispVal::Atom(arrow), ..] if arrow == "=>" => Err(format!(
+                "{}: => must be followed by exactly one receiver",
+                who
+            )),
+            body => Ok(Consequent::Body(Rc::new(self.sequence(body)?))),
+        }
+    }
+
+    fn cond(&mut self, clauses: &[LispVal]) -> Result<Node, String> {
+        let mut analyzed = Vec::new();
+        for (i, clause) in clauses.iter().enumerate() {
+            let LispVal::List(clause) = clause else {
+                return Err(format!("cond: bad clause {}", clause));
+            };
+            let (test, body) = clause
+                .split_first()
+                .ok_or("cond: clauses must not be empty")?;
+            analyzed.push(match test {
+                LispVal::Atom(s) if s == "else" => {
+                    if i + 1 != clauses.len() {
+                        return Err("cond: else must be the last clause".to_string());
+                    }
+                    if body.is_empty() {
+                        return Err("cond: else clause must have expressions".to_string());
+                    }
+                    (None, Some(Consequent::Body(Rc::new(self.sequence(body)?))))
+                }
+                test => {
+                    let test = Rc::new(self.expr(test)?);
+                    match body.is_empty() {
+                        true => (Some(test), None),
+                        false => (Some(test), Some(self.consequent("cond", body)?)),
+                    }
+                }
+            });
+        }
+        Ok(Node::Cond(analyzed))
+    }
+
+    fn case(&mut self, v: &[LispVal]) -> Result<Node, String> {
+        let (key, clauses) = v.split_first().ok_or("case expects a key")?;
+        let key = Rc::new(self.expr(key)?);
+        let clauses = clauses
+            .iter()
+            .map(|clause| {
+                let LispVal::List(clause) = clause else {
+                    return Err(format!("case: bad clause {}", clause));
+                };
+                let data = match clause.first() {
+                    Some(LispVal::Atom(s)) if s == "else" => None,
+                    Some(LispVal::List(data)) => Some(data.iter().map(LispVal::to_datum).collect()),
+                    _ => {
+                        return Err(format!(
+                            "case: bad clause {}",
+                            LispVal::List(clause.clone())
+                        ))
+                    }
+                };
+                Ok((data, self.consequent("case", &clause[1..])?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Node::Case(key, clauses))
+    }
+
+    fn do_loop(&mut self, v: &[LispVal]) -> Result<Node, String> {
+        let [LispVal::List(specs), LispVal::List(exit), commands @ ..] = v else {
+            return Err("do expects variable specs and an exit clause".to_string());
+        };
+        if exit.is_empty() {
+            return Err("do expects an exit test".to_string());
+        }
+        let specs = specs
+            .iter()
+            .map(|spec| match spec {
+                LispVal::List(spec) => match spec.as_slice() {
+                    [LispVal::Atom(var), init] => Ok((*var, init, None)),
+                    [LispVal::Atom(var), init, step] => Ok((*var, init, Some(step))),
+                    _ => Err(format!(
+                        "do: bad variable spec {}",
+                        LispVal::List(spec.clone())
+                    )),
+                },
+                _ => Err(format!("do: bad variable spec {}", spec)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let inits = specs
+            .iter()
+            .map(|(_, init, _)| self.expr(init).map(Rc::new))
+            .collect::<Result<Vec<_>, String>>()?;
+        let variables = specs.iter().map(|(var, ..)| *var).collect::<Vec<_>>();
+        let steps = specs.iter().filter_map(|(_, _, step)| *step);
+        let code = exit.iter().chain(commands).chain(steps);
+        self.frames.push(Frame::binding(variables.clone(), code));
+        let analyzed = self.do_body(&specs, exit, commands);
+        self.frames.pop();
+        let (steps, test, result, commands) = analyzed?;
+        Ok(Node::Do {
+            variables,
+            inits,
+            steps,
+            test,
+            result,
+            commands,
+        })
+    }
+
+    /// The steps, exit clause and commands of a `do` loop, analyzed in its frame.
+    fn do_body(
+        &mut self,
+        specs: &[(Symbol, &LispVal, Option<&LispVal>)],
+        exit: &[LispVal],
+        commands: &[LispVal],
+    ) -> Result<DoBody, String> {
+        let steps = specs
+            .iter()
+            .map(|(var, _, step)| match step {
+                Some(step) => self.expr(step).map(Rc::new),
+                None => Ok(Rc::new(self.resolve(*var))),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let (test, result) = exit.split_first().ok_or("do expects an exit test")?;
+        let test = Rc::new(self.expr(test)?);
+        let result = match result.is_empty() {
+            true => None,
+            false => Some(Rc::new(self.sequence(result)?)),
+        };
+        Ok((steps, test, result, self.exprs(commands)?))
+    }
+}
+
+/// The steps, test, result and commands of a `do` loop.
+type DoBody = (Vec<Rc<Node>>, Rc<Node>, Option<Rc<Node>>, Vec<Rc<Node>>);
+
+/// The outcome of one execution step: a value, or a node in tail position that is
+/// left for `exec` to execute in the current environment.
+enum Step {
+    Value(LispVal),
+    Exec(Rc<Node>),
+}
+
+impl Interpreter {
+    /// Analyzes `form`, reporting its syntax errors before any of it runs, and
+    /// executes it in the current environment.
+    pub(super) fn eval_analyzed(&mut self, form: &LispVal) -> Result<LispVal, String> {
+        let node = Rc::new(analyze(form)?);
+        self.exec(&node)
+    }
+
+    /// Executes `node` in the current environment, which is restored afterwards. Like
+    /// `eval`, it loops over the nodes in tail position rather than recursing.
+    pub(super) fn exec(&mut self, node: &Rc<Node>) -> Result<LispVal, String> {
+        if let Some(value) = self.exec_leaf(node) {
+            return value;
+        }
+        let depth = self.meter.enter()?;
+        let caller_env = self.env.clone();
+        let mut step = self.exec_step(node);
+        let result = loop {
+            match step {
+                Ok(Step::Exec(next)) => {
+                    gc::maybe_collect();
+                    step = self.exec_step(&next)
+                }
+                Ok(Step::Value(value)) => break Ok(value),
+                Err(e) => break Err(e),
+            }
+        };
+        self.env = caller_env;
+        self.meter.leave(depth);
+        result
+    }
+
+    /// The value of a constant or a variable, which `exec` needs not nest an evaluation
+    /// for, or `None` for other nodes.
+    fn exec_leaf(&mut self, node: &Node) -> Option<Result<LispVal, String>> {
+        let value = match node {
+            Node::Const(v) => Ok(v.clone()),
+            Node::Local { depth, slot, name } => self
+                .env
+                .get(*depth, *slot)
+                .ok_or_else(|| format!("unknown atom {}", name.name())),
+            Node::Free { depth, name } => self
+                .env
+                .lookup_from(*depth, *name)
+                .ok_or_else(|| format!("unknown atom {}", name.name())),
+            _ => return None,
+        };
+        Some(self.meter.tick().and(value))
+    }
+
+    fn exec_step(&mut self, node: &Node) -> Result<Step, String> {
+        if let Some(value) = self.exec_leaf(node) {
+            return value.map(Step::Value);
+        }
+        self.meter.tick()?;
+        let value = match node {
+            Node::Const(_) | Node::Local { .. } | Node::Free { .. } => {
+                unreachable!("exec_leaf executes constants and variables")
+            }
+            Node::Define(name, value) => {
+                let value = self.exec(value)?;
+                self.env.new_binding(*name, value.clone());
+                value
+            }
+            Node::Lambda(lambda) => {
+                LispVal::Function(Gc::new(lambda.instantiate(self.env.clone())))
+            }
+            Node::CaseLambda(clauses) => LispVal::CaseLambda(Gc::new(
+                clauses
+                    .iter()
+                    .map(|lambda| lambda.instantiate(self.env.clone()))
+                    .collect(),
+            )),
+            Node::If(test, then, otherwise) => {
+                return match (self.exec(test)? != LispVal::Bool(false), otherwise) {
+                    (true, _) => Ok(Step::Exec(then.clone())),
+                    (false, Some(otherwise)) => Ok(Step::Exec(otherwise.clone())),
+                    (false, None) => Err("Unspecified return value".to_string()),
+                }
+            }
+            Node::Sequence(body) => {
+                let (last, init) = body.split_last().ok_or("empty body")?;
+                for node in init {
+                    self.exec(node)?;
+                }
+                return Ok(Step::Exec(last.clone()));
+            }
+            Node::And(tests) | Node::Or(tests) => {
+                let conjunction = matches!(node, Node::And(_));
+                let Some((last, init)) = tests.split_last() else {
+                    return Ok(Step::Value(LispVal::Bool(conjunction)));
+                };
+                for test in init {
+                    let value = self.exec(test)?;
+                    if (value == LispVal::Bool(false)) == conjunction {
+                        return Ok(Step::Value(value));
+                    }
+                }
+                return Ok(Step::Exec(last.clone()));
+            }
+            Node::Cond(clauses) => {
+                for (test, consequent) in clauses {
+                    let value = match test {
+                        Some(test) => self.exec(test)?,
+                        None => LispVal::Bool(true),
+                    };
+                    if value == LispVal::Bool(false) {
+                        continue;
+                    }
+                    return match consequent {
+                        Some(consequent) => self.exec_consequent("cond", consequent, value),
+                        None => Ok(Step::Value(value)),
+                    };
+                }
+                LispVal::Unspecified
+            }
+            Node::Case(key, clauses) => {
+                let key = self.exec(key)?;
+                for (data, consequent) in clauses {
+                    let matches = data
+                        .as_ref()
+                        .is_none_or(|data| data.iter().any(|d| equivalence::eqv(d, &key)));
+                    if matches {
+                        return self.exec_consequent("case", consequent, key);
+                    }
+                }
+                LispVal::Unspecified
+            }
+            Node::Do {
+                variables,
+                inits,
+                steps,
+                test,
+                result,
+                commands,
+            } => {
+                let outer = self.env.clone();
+                let mut values = inits
+                    .iter()
+                    .map(|node| self.exec(node))
+                    .collect::<Result<Vec<LispVal>, String>>()?;
+                loop {
+                    gc::maybe_collect();
+                    self.env = outer.extend();
+                    for (var, value) in variables.iter().zip(values) {
+                        self.env.new_binding(*var, value);
+                    }
+                    if self.exec(test)? != LispVal::Bool(false) {
+                        return Ok(match result {
+                            Some(result) => Step::Exec(result.clone()),
+                            None => Step::Value(LispVal::Unspecified),
+                        });
+                    }
+                    for command in commands {
+                        self.exec(command)?;
+                    }
+                    values = steps
+                        .iter()
+                        .map(|node| self.exec(node))
+                        .collect::<Result<Vec<LispVal>, String>>()?;
+                }
+            }
+            Node::Call {
+                name,
+                operator,
+                operands,
+            } => {
+                let procedure = self.exec(operator)?;
+                let operands = operands
+                    .iter()
+                    .map(|node| self.exec(node))
+                    .collect::<Result<Vec<LispVal>, String>>()?;
+                return self.enter(name.name(), &procedure, operands);
+            }
+            Node::Interpret(form) => self.eval(form)?,
+        };
+        Ok(Step::Value(value))
+    }
+
+    fn exec_consequent(
+        &mut self,
+        who: &str,
+        consequent: &Consequent,
+        value: LispVal,
+    ) -> Result<Step, String> {
+        match consequent {
+            Consequent::Body(body) => Ok(Step::Exec(body.clone())),
+            Consequent::Receiver(receiver) => {
+                let receiver = self.exec(receiver)?;
+                self.enter(who, &receiver, vec![value])
+            }
+        }
+    }
+
+    /// Applies `procedure`, entering the body of a closure in tail position.
+    fn enter(
+        &mut self,
+        operator: &str,
+        procedure: &LispVal,
+        operands: Vec<LispVal>,
+    ) -> Result<Step, String> {
+        let f = match procedure {
+            LispVal::Function(f) => f,
+            LispVal::CaseLambda(clauses) => Self::select_clause(operator, clauses, operands.len())?,
+            // the operands are handed over rather than copied
+            LispVal::Primitive { name, arity } if arity.accepts(operands.len()) => {
+                return self.apply_primitive(*name, operands).map(Step::Value)
+            }
+            _ => {
+                return self
+                    .apply_procedure(operator, procedure, &operands)
+                    .map(Step::Value)
+            }
+        };
+        f.arity().check(operator, operands.len())?;
+        self.env = f.env.extend();
+        self.bind_params(operator, &f.params, &operands)?;
+        Ok(Step::Exec(f.analyzed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    use super::*;
+
+    fn analyze_source(source: &str) -> Result<Node, String> {
+        analyze(&Parser::new(source).read().unwrap().unwrap())
+    }
+
+    /// The variables of `node` in order, as `name@depth.slot` when resolved and
+    /// `name@depth` when looked up by name.
+    fn variables(node: &Node, out: &mut Vec<String>) {
+        match node {
+            Node::Local { depth, slot, name } => out.push(format!("{}@{}.{}", name, depth, slot)),
+            Node::Free { depth, name } => out.push(format!("{}@{}", name, depth)),
+            Node::Define(_, node) => variables(node, out),
+            Node::Lambda(lambda) => variables(&lambda.analyzed, out),
+            Node::Sequence(nodes) => nodes.iter().for_each(|node| variables(node, out)),
+            Node::Do {
+                inits,
+                steps,
+                test,
+                commands,
+                ..
+            } => inits
+                .iter()
+                .chain(steps)
+                .chain([test])
+                .chain(commands)
+                .for_each(|node| variables(node, out)),
+            Node::Call {
+                operator, operands, ..
+            } => {
+                variables(operator, out);
+                operands.iter().for_each(|node| variables(node, out));
+            }
+            _ => {}
+        }
+    }
+
+    fn variables_of(source: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        variables(&analyze_source(source).unwrap(), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_lexical_addresses() {
+        assert_eq!(
+            variables_of("(lambda (x y . z) (lambda (y) (list x y z w)))"),
+            ["list@2", "x@1.0", "y@0.0", "z@1.2", "w@2"]
+        );
+        assert_eq!(variables_of("(f x)"), ["f@0", "x@0"]);
+        // definitions in a body may shadow a parameter, so variables they define are
+        // looked up by name, from the frame they are defined in
+        assert_eq!(
+            variables_of("(define (f x) (define y x) (lambda (z) (+ x y z)))"),
+            ["x@0.0", "+@2", "x@1.0", "y@1", "z@0.0"]
+        );
+        assert_eq!(variables_of("(lambda (x) (define x 2) x)"), ["x@0"]);
+        // the variables of a `do` loop are bound in a frame of their own
+        assert_eq!(
+            variables_of("(lambda (n) (do ((i 0 (+ i 1)) (j n)) ((= i j)) (display n)))"),
+            [
+                "n@0.0",
+                "+@2",
+                "i@0.0",
+                "j@0.1",
+                "=@2",
+                "i@0.0",
+                "j@0.1",
+                "display@2",
+                "n@1.0"
+            ]
+        );
+        assert_eq!(
+            variables_of("(lambda (x) (import (scheme base)) (lambda () x))"),
+            ["x@1"]
+        );
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            analyze_source("(lambda (x) (if))").unwrap_err(),
+            "if must have a condition"
+        );
+        assert_eq!(
+            analyze_source("(define (f) (cond (else 1) (#t 2)))").unwrap_err(),
+            "cond: else must be the last clause"
+        );
+        assert_eq!(
+            analyze_source("(lambda (n) (do ((i 0)) ()))").unwrap_err(),
+            "do expects an exit test"
+        );
+        assert!(matches!(
+            analyze_source("(receive (a) 1 (#t))"),
+            Ok(Node::Interpret(_))
+        ));
+    }
+}
@@ -0,0 +1,650 @@
+use std::{cell::OnceCell, rc::Rc};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    environment::Environment,
+    procedure::{Closure, Params},
+    symbol::Symbol,
+};
+
+/// An instruction of the stack machine in `vm`. Every expression leaves exactly one
+/// value on the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// pushes a constant of the chunk
+    Const(usize),
+    /// pushes the value of a variable
+    Lookup(Symbol),
+    /// binds a variable in the innermost frame to the value on top, which stays there
+    Define(Symbol),
+    Pop,
+    /// pushes the value on top again
+    Dup,
+    /// exchanges the two values on top
+    Swap,
+    Jump(usize),
+    /// pops the value on top and jumps if it is `#f`
+    JumpIfFalse(usize),
+    /// jumps if the value on top is `#f`, which stays there, and pops it otherwise
+    JumpIfFalseOrPop(usize),
+    /// jumps if the value on top is not `#f`, which stays there, and pops it otherwise
+    JumpIfTrueOrPop(usize),
+    /// pushes whether the value on top, which stays there, is `eqv?` to one of the
+    /// elements of a constant list of the chunk
+    Memv(usize),
+    /// pushes a closure of a lambda of the chunk over the current environment
+    Closure(usize),
+    /// pushes a `case-lambda` of `count` lambdas of the chunk from `start` on
+    CaseLambda {
+        start: usize,
+        count: usize,
+    },
+    /// pushes a promise of a constant expression of the chunk over the current
+    /// environment, for `delay`, or for `delay-force` when `force`
+    Delay {
+        expr: usize,
+        force: bool,
+    },
+    /// pops the promise of a cdr and the car below it, which is a promise too unless
+    /// `eager`, and pushes a stream pair of them
+    StreamCons {
+        eager: bool,
+    },
+    /// pops as many values as there are variables in a list of the chunk and binds them
+    /// in a new frame, which replaces the innermost one of the current environment
+    /// when `again`
+    Bind {
+        variables: usize,
+        again: bool,
+    },
+    /// leaves the innermost frame of the current environment
+    Leave,
+    /// calls the procedure below the `argc` values on top with them as arguments;
+    /// `name` is what the procedure was called by, for error messages
+    Call {
+        argc: usize,
+        name: Symbol,
+    },
+    /// like `Call`, but the caller returns what the procedure returns
+    TailCall {
+        argc: usize,
+        name: Symbol,
+    },
+    Return,
+    /// evaluates a constant of the chunk with the tree-walker, for the forms that are
+    /// not compiled
+    Eval(usize),
+    Fail(&'static str),
+}
+
+/// A `lambda` expression, compiled once for all the closures it makes.
+#[derive(Debug)]
+pub struct Lambda {
+    params: Params,
+    body: Rc<[LispVal]>,
+    code: Rc<Chunk>,
+}
+
+impl Lambda {
+    pub fn instantiate(&self, env: Environment) -> Closure {
+        Closure {
+            params: self.params.clone(),
+            body: self.body.clone(),
+            env,
+            code: OnceCell::from(self.code.clone()),
+            analyzed: OnceCell::new(),
+        }
+    }
+}
+
+/// The compiled code of a body or top-level form.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<LispVal>,
+    pub lambdas: Vec<Lambda>,
+    /// the variables of the frames that `Bind` makes
+    pub variables: Vec<Vec<Symbol>>,
+}
+
+/// Compiles a top-level form.
+pub fn compile(form: &LispVal) -> Chunk {
+    compile_body(std::slice::from_ref(form))
+}
+
+/// Compiles a non-empty body, the last expression of which is in tail position.
+pub fn compile_body(body: &[LispVal]) -> Chunk {
+    let mut compiler = Compiler::default();
+    compiler.sequence(body, true);
+    compiler.emit(Op::Return);
+    compiler.chunk
+}
+
+/// The forms the compiler leaves to the tree-walker: definitions and imports, which
+/// mostly run once at top level, and the forms that bind multiple values or a dynamic
+/// extent.
+const INTERPRETED_FORMS: &[&str] = &[
+    "define-record-type",
+    "define-values",
+    "parameterize",
+    "let-values",
+    "receive",
+    "import",
+    "define-library",
+    "include",
+];
+
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.code.len() - 1
+    }
+
+    /// Makes the jump at `at` go to the next instruction emitted.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.code.len();
+        self.chunk.code[at] = match self.chunk.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::JumpIfFalseOrPop(_) => Op::JumpIfFalseOrPop(target),
+            Op::JumpIfTrueOrPop(_) => Op::JumpIfTrueOrPop(target),
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+
+    fn constant(&mut self, v: LispVal) -> usize {
+        self.chunk.constants.push(v);
+        self.chunk.constants.len() - 1
+    }
+
+    fn push(&mut self, v: LispVal) {
+        let i = self.constant(v);
+        self.emit(Op::Const(i));
+    }
+
+    /// Leaves `form` to the tree-walker, which also reports its syntax errors.
+    fn interpret(&mut self, form: &LispVal) {
+        let i = self.constant(form.clone());
+        self.emit(Op::Eval(i));
+    }
+
+    fn sequence(&mut self, body: &[LispVal], tail: bool) {
+        let Some((last, init)) = body.split_last() else {
+            self.emit(Op::Fail("empty body"));
+            return;
+        };
+        for v in init {
+            self.expr(v, false);
+            self.emit(Op::Pop);
+        }
+        self.expr(last, tail);
+    }
+
+    fn expr(&mut self, v: &LispVal, tail: bool) {
+        match v {
+            LispVal::Atom(s) => {
+                self.emit(Op::Lookup(*s));
+            }
+            LispVal::List(l) => self.list(v, l, tail),
+            v => self.push(v.clone()),
+        }
+    }
+
+    fn list(&mut self, form: &LispVal, v: &[LispVal], tail: bool) {
+        let Some((operator, operands)) = v.split_first() else {
+            return self.push(LispVal::List(Vec::new()));
+        };
+        let LispVal::Atom(s) = operator else {
+            return self.application(Symbol::intern("procedure"), operator, operands, tail);
+        };
+        match (s.name(), operands) {
+            ("quote", [datum]) => self.push(datum.to_datum()),
+            ("if", [test, then]) => {
+                self.expr(test, false);
+                let otherwise = self.emit(Op::JumpIfFalse(0));
+                self.expr(then, tail);
+                let end = self.emit(Op::Jump(0));
+                self.patch(otherwise);
+                self.emit(Op::Fail("Unspecified return value"));
+                self.patch(end);
+            }
+            ("if", [test, then, otherwise]) => {
+                self.expr(test, false);
+                let jump = self.emit(Op::JumpIfFalse(0));
+                self.expr(then, tail);
+                let end = self.emit(Op::Jump(0));
+                self.patch(jump);
+                self.expr(otherwise, tail);
+                self.patch(end);
+            }
+            ("define", [LispVal::Atom(name), value]) => {
+                self.expr(value, false);
+                self.emit(Op::Define(*name));
+            }
+            ("define" | "define*", [LispVal::List(signature), body @ ..]) => {
+                match signature.split_first() {
+                    Some((LispVal::Atom(name), formals)) => {
+                        let formals = LispVal::List(formals.to_vec());
+                        if !self.lambda(&formals, body, s == "define*") {
+                            return self.interpret(form);
+                        }
+                        self.emit(Op::Define(*name));
+                    }
+                    _ => self.interpret(form),
+                }
+            }
+            ("lambda" | "lambda*", [formals, body @ ..]) => {
+                if !self.lambda(formals, body, s == "lambda*") {
+                    self.interpret(form)
+                }
+            }
+            ("case-lambda", clauses) => {
+                if !self.case_lambda(clauses) {
+                    self.interpret(form)
+                }
+            }
+            ("begin", []) => self.push(LispVal::Unspecified),
+            ("begin", body) => self.sequence(body, tail),
+            ("and", tests) => self.junction(tests, true, tail),
+            ("or", tests) => self.junction(tests, false, tail),
+            ("when", [test, body @ ..]) if !body.is_empty() => {
+                self.expr(test, false);
+                let skip = self.emit(Op::JumpIfFalse(0));
+                self.sequence(body, tail);
+                let end = self.emit(Op::Jump(0));
+                self.patch(skip);
+                self.push(LispVal::Unspecified);
+                self.patch(end);
+            }
+            ("unless", [test, body @ ..]) if !body.is_empty() => {
+                self.expr(test, false);
+                let run = self.emit(Op::JumpIfFalse(0));
+                self.push(LispVal::Unspecified);
+                let end = self.emit(Op::Jump(0));
+                self.patch(run);
+                self.sequence(body, tail);
+                self.patch(end);
+            }
+            ("cond", clauses) => {
+                if !self.cond(clauses, tail) {
+                    self.interpret(form)
+                }
+            }
+            ("case", [key, clauses @ ..]) => {
+                if !self.case(key, clauses, tail) {
+                    self.interpret(form)
+                }
+            }
+            ("do", [LispVal::List(specs), LispVal::List(exit), commands @ ..]) => {
+                if !self.do_loop(specs, exit, commands, tail) {
+                    self.interpret(form)
+                }
+            }
+            ("delay" | "delay-force", [expr]) => {
+                let expr = self.constant(expr.clone());
+                let force = s == "delay-force";
+                self.emit(Op::Delay { expr, force });
+            }
+            ("stream-cons" | "cons-stream", [car, cdr]) => {
+                let eager = s == "cons-stream";
+                match eager {
+                    true => self.expr(car, false),
+                    false => {
+                        let expr = self.constant(car.clone());
+                        self.emit(Op::Delay { expr, force: false });
+                    }
+                }
+                let expr = self.constant(cdr.clone());
+                self.emit(Op::Delay { expr, force: false });
+                self.emit(Op::StreamCons { eager });
+            }
+            (
+                "quote" | "if" | "define" | "define*" | "lambda" | "lambda*" | "when" | "unless"
+                | "case" | "do" | "delay" | "delay-force" | "stream-cons" | "cons-stream",
+                _,
+            ) => self.interpret(form),
+            (s, _) if INTERPRETED_FORMS.contains(&s) => self.interpret(form),
+            (s, operands) => self.application(Symbol::intern(s), operator, operands, tail),
+        }
+    }
+
+    fn application(&mut self, name: Symbol, operator: &LispVal, operands: &[LispVal], tail: bool) {
+        self.expr(operator, false);
+        for operand in operands {
+            self.expr(operand, false);
+        }
+        let argc = operands.len();
+        self.emit(match tail {
+            true => Op::TailCall { argc, name },
+            false => Op::Call { argc, name },
+        });
+    }
+
+    /// Compiles a lambda, or a `lambda*` when `extended`, unless its syntax is wrong.
+    fn lambda(&mut self, formals: &LispVal, body: &[LispVal], extended: bool) -> bool {
+        if !self.add_lambda(formals, body, extended) {
+            return false;
+        }
+        self.emit(Op::Closure(self.chunk.lambdas.len() - 1));
+        true
+    }
+
+    fn add_lambda(&mut self, formals: &LispVal, body: &[LispVal], extended: bool) -> bool {
+        let Ok(params) = Params::from_formals(formals, extended) else {
+            return false;
+        };
+        if body.is_empty() {
+            return false;
+        }
+        self.chunk.lambdas.push(Lambda {
+            params,
+            body: body.into(),
+            code: Rc::new(compile_body(body)),
+        });
+        true
+    }
+
+    /// Compiles a `case-lambda`, unless its syntax is wrong.
+    fn case_lambda(&mut self, clauses: &[LispVal]) -> bool {
+        let start = self.chunk.lambdas.len();
+        for clause in clauses {
+            let LispVal::List(clause) = clause else {
+                return false;
+            };
+            let Some((formals, body)) = clause.split_first() else {
+                return false;
+            };
+            if !self.add_lambda(formals, body, false) {
+                return false;
+            }
+        }
+        let count = clauses.len();
+        self.emit(Op::CaseLambda { start, count });
+        true
+    }
+
+    /// Calls the procedure on top with the value below it, for the `=>` clauses of
+    /// `cond` and `case`.
+    fn receive(&mut self, who: &str, tail: bool) {
+        self.emit(Op::Swap);
+        let (argc, name) = (1, Symbol::intern(who));
+        self.emit(match tail {
+            true => Op::TailCall { argc, name },
+            false => Op::Call { argc, name },
+        });
+    }
+
+    /// `and` when `conjunction`, `or` otherwise.
+    fn junction(&mut self, tests: &[LispVal], conjunction: bool, tail: bool) {
+        let Some((last, init)) = tests.split_last() else {
+            return self.push(LispVal::Bool(conjunction));
+        };
+        let mut exits = Vec::new();
+        for test in init {
+            self.expr(test, false);
+            exits.push(self.emit(match conjunction {
+                true => Op::JumpIfFalseOrPop(0),
+                false => Op::JumpIfTrueOrPop(0),
+            }));
+        }
+        self.expr(last, tail);
+        exits.into_iter().for_each(|exit| self.patch(exit));
+    }
+
+    /// Compiles a `cond`, unless its syntax is wrong.
+    fn cond(&mut self, clauses: &[LispVal], tail: bool) -> bool {
+        let mut parsed = Vec::new();
+        for (i, clause) in clauses.iter().enumerate() {
+            let LispVal::List(clause) = clause else {
+                return false;
+            };
+            parsed.push(match clause.split_first() {
+                Some((LispVal::Atom(s), body)) if s == "else" => {
+                    if i + 1 != clauses.len() || body.is_empty() {
+                        return false;
+                    }
+                    Clause::Else(body)
+                }
+                Some((test, [LispVal::Atom(arrow), receiver])) if arrow == "=>" => {
+                    Clause::Receiver(test, receiver)
+                }
+                Some((_, [LispVal::Atom(arrow), ..])) if arrow == "=>" => return false,
+                Some((test, body)) => Clause::Body(test, body),
+                None => return false,
+            });
+        }
+        let mut exits = Vec::new();
+        let mut has_else = false;
+        for clause in parsed {
+            match clause {
+                Clause::Else(body) => {
+                    self.sequence(body, tail);
+                    has_else = true;
+                }
+                Clause::Body(test, []) => {
+                    self.expr(test, false);
+                    exits.push(self.emit(Op::JumpIfTrueOrPop(0)));
+                }
+                Clause::Body(test, body) => {
+                    self.expr(test, false);
+                    let next = self.emit(Op::JumpIfFalse(0));
+                    self.sequence(body, tail);
+                    exits.push(self.emit(Op::Jump(0)));
+                    self.patch(next);
+                }
+                Clause::Receiver(test, receiver) => {
+                    self.expr(test, false);
+                    self.emit(Op::Dup);
+                    let next = self.emit(Op::JumpIfFalse(0));
+                    self.expr(receiver, false);
+                    self.receive("cond", tail);
+                    exits.push(self.emit(Op::Jump(0)));
+                    self.patch(next);
+                    self.emit(Op::Pop);
+                }
+            }
+        }
+        if !has_else {
+            self.push(LispVal::Unspecified);
+        }
+        exits.into_iter().for_each(|exit| self.patch(exit));
+        true
+    }
+
+    /// Compiles a `case`, unless its syntax is wrong. The key stays on the stack until a
+    /// clause is chosen.
+    fn case(&mut self, key: &LispVal, clauses: &[LispVal], tail: bool) -> bool {
+        let mut parsed = Vec::new();
+        for clause in clauses {
+            let LispVal::List(clause) = clause else {
+                return false;
+            };
+            let data = match clause.first() {
+                Some(LispVal::Atom(s)) if s == "else" => None,
+                Some(LispVal::List(data)) => Some(data.iter().map(LispVal::to_datum).collect()),
+                _ => return false,
+            };
+            parsed.push((data, &clause[1..]));
+        }
+        self.expr(key, false);
+        let mut exits = Vec::new();
+        let mut has_else = false;
+        for (data, body) in parsed {
+            let next = data.map(|data| {
+                let i = self.constant(LispVal::List(data));
+                self.emit(Op::Memv(i));
+                self.emit(Op::JumpIfFalse(0))
+            });
+            match body {
+                [LispVal::Atom(arrow), receiver] if arrow == "=>" => {
+                    self.expr(receiver, false);
+                    self.receive("case", tail);
+                }
+                body => {
+                    self.emit(Op::Pop);
+                    self.sequence(body, tail);
+                }
+            }
+            let Some(next) = next else {
+                // the clauses after `else` are never chosen
+                has_else = true;
+                break;
+            };
+            exits.push(self.emit(Op::Jump(0)));
+            self.patch(next);
+        }
+        if !has_else {
+            self.emit(Op::Pop);
+            self.push(LispVal::Unspecified);
+        }
+        exits.into_iter().for_each(|exit| self.patch(exit));
+        true
+    }
+
+    /// Compiles a `do` loop, unless its syntax is wrong. Each iteration binds the
+    /// variables in a fresh frame, which the loop leaves when it ends, unless it is in
+    /// tail position and returning leaves it anyway.
+    fn do_loop(
+        &mut self,
+        specs: &[LispVal],
+        exit: &[LispVal],
+        commands: &[LispVal],
+        tail: bool,
+    ) -> bool {
+        let Some((test, result)) = exit.split_first() else {
+            return false;
+        };
+        let mut parsed = Vec::new();
+        for spec in specs {
+            let LispVal::List(spec) = spec else {
+                return false;
+            };
+            parsed.push(match spec.as_slice() {
+                [LispVal::Atom(var), init] => (*var, init, None),
+                [LispVal::Atom(var), init, step] => (*var, init, Some(step)),
+                _ => return false,
+            });
+        }
+        for (_, init, _) in &parsed {
+            self.expr(init, false);
+        }
+        self.chunk
+            .variables
+            .push(parsed.iter().map(|(var, ..)| *var).collect());
+        let variables = self.chunk.variables.len() - 1;
+        self.emit(Op::Bind {
+            variables,
+            again: false,
+        });
+        let start = self.chunk.code.len();
+        self.expr(test, false);
+        let run = self.emit(Op::JumpIfFalse(0));
+        match result.is_empty() {
+            true => self.push(LispVal::Unspecified),
+            false => self.sequence(result, tail),
+        }
+        if !tail {
+            self.emit(Op::Leave);
+        }
+        let end = self.emit(Op::Jump(0));
+        self.patch(run);
+        for command in commands {
+            self.expr(command, false);
+            self.emit(Op::Pop);
+        }
+        for (var, _, step) in &parsed {
+            match step {
+                Some(step) => self.expr(step, false),
+                None => {
+                    self.emit(Op::Lookup(*var));
+                }
+            }
+        }
+        self.emit(Op::Bind {
+            variables,
+            again: true,
+        });
+        self.emit(Op::Jump(start));
+        self.patch(end);
+        true
+    }
+}
+
+/// A clause of `cond`.
+enum Clause<'a> {
+    Else(&'a [LispVal]),
+    Body(&'a LispVal, &'a [LispVal]),
+    /// `(test => receiver)`
+    Receiver(&'a LispVal, &'a LispVal),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    use super::*;
+
+    fn compile_source(source: &str) -> Chunk {
+        compile(&Parser::new(source).read().unwrap().unwrap())
+    }
+
+    #[test]
+    fn test_compile() {
+        let chunk = compile_source("(if (f x) 1 (g))");
+        let f = Symbol::intern("f");
+        let g = Symbol::intern("g");
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Lookup(f),
+                Op::Lookup(Symbol::intern("x")),
+                Op::Call { argc: 1, name: f },
+                Op::JumpIfFalse(6),
+                Op::Const(0),
+                Op::Jump(8),
+                Op::Lookup(g),
+                Op::TailCall { argc: 0, name: g },
+                Op::Return,
+            ]
+        );
+        assert_eq!(chunk.constants, vec![LispVal::Integer(1)]);
+    }
+
+    #[test]
+    fn test_compile_case() {
+        let chunk = compile_source("(case x ((1 2) 'small) (else => f))");
+        let f = Symbol::intern("f");
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Lookup(Symbol::intern("x")),
+                Op::Memv(0),
+                Op::JumpIfFalse(6),
+                Op::Pop,
+                Op::Const(1),
+                Op::Jump(9),
+                Op::Lookup(f),
+                Op::Swap,
+                Op::TailCall {
+                    argc: 1,
+                    name: Symbol::intern("case")
+                },
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interpreted_forms() {
+        let chunk = compile_source("(define-values (a b) (values 1 2))");
+        assert_eq!(chunk.code, vec![Op::Eval(0), Op::Return]);
+        let chunk = compile_source("(case x (1 'one))");
+        assert_eq!(chunk.code, vec![Op::Eval(0), Op::Return]);
+    }
+}
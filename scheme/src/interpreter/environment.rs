@@ -1,55 +1,211 @@
+use std::{cell::RefCell, fmt::Debug};
+
 use crate::parser::parser::LispVal;
 
-#[derive(Debug, Clone)]
-struct EnvFrame(Vec<(String, LispVal)>);
+use super::{
+    gc::{self, Gc, Trace, Tracer},
+    symbol::{Symbol, SymbolMap},
+};
+
+/// Frames with more bindings than this, like the ones of primitives and top-level
+/// definitions, are indexed rather than scanned.
+const INDEXED_FRAME_SIZE: usize = 16;
+
+struct EnvFrame {
+    bindings: RefCell<Vec<(Symbol, LispVal)>>,
+    /// the position of the latest binding of each variable, for large frames
+    index: RefCell<SymbolMap<usize>>,
+    parent: Option<Environment>,
+}
 
 impl EnvFrame {
-    pub(crate) fn new() -> EnvFrame {
-        EnvFrame(Vec::new())
+    pub(crate) fn new(parent: Option<Environment>) -> EnvFrame {
+        EnvFrame {
+            bindings: RefCell::new(Vec::new()),
+            index: RefCell::new(SymbolMap::default()),
+            parent,
+        }
     }
 
-    pub(crate) fn lookup(&self, key: &str) -> Option<&LispVal> {
-        self.0
+    pub(crate) fn lookup(&self, key: Symbol) -> Option<LispVal> {
+        let bindings = self.bindings.borrow();
+        if bindings.len() > INDEXED_FRAME_SIZE {
+            let i = *self.index.borrow().get(&key)?;
+            return Some(bindings[i].1.clone());
+        }
+        bindings
             .iter()
             .rev()
-            .find(|entry| entry.0.eq(key))
-            .map(|pair| &pair.1)
+            .find(|entry| entry.0 == key)
+            .map(|pair| pair.1.clone())
+    }
+
+    fn push(&self, key: Symbol, value: LispVal) {
+        let mut bindings = self.bindings.borrow_mut();
+        bindings.push((key, value));
+        let mut index = self.index.borrow_mut();
+        match bindings.len() {
+            n if n <= INDEXED_FRAME_SIZE => {}
+            n if n == INDEXED_FRAME_SIZE + 1 => {
+                index.extend(bindings.iter().enumerate().map(|(i, (key, _))| (*key, i)))
+            }
+            n => {
+                index.insert(key, n - 1);
+            }
+        }
+    }
+}
+
+impl Trace for EnvFrame {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.bindings.try_borrow() {
+            Ok(bindings) => {
+                tracer.owns(bindings.capacity() * std::mem::size_of::<(Symbol, LispVal)>());
+                bindings.iter().for_each(|(_, value)| value.trace(tracer))
+            }
+            Err(_) => tracer.fail(),
+        }
+        if let Some(parent) = &self.parent {
+            parent.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        self.bindings.borrow_mut().clear();
+        self.index.borrow_mut().clear();
+    }
+}
+
+/// Frees the values bound in the frame and the frames around it in a loop rather than
+/// recursively, as closures hold the frame they were made in.
+impl Drop for EnvFrame {
+    fn drop(&mut self) {
+        let bindings = std::mem::take(self.bindings.get_mut());
+        gc::release(
+            bindings
+                .into_iter()
+                .map(|(_, value)| value)
+                .chain(self.parent.take().map(LispVal::Environment)),
+        );
+    }
+}
+
+impl Debug for EnvFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bindings = self.bindings.borrow();
+        let bindings = bindings
+            .iter()
+            .map(|(key, value)| (key, Bound(value)))
+            .collect::<Vec<_>>();
+        f.debug_tuple("EnvFrame").field(&bindings).finish()
+    }
+}
+
+/// Environments bound in a frame are not printed, the frame may well be part of them.
+struct Bound<'a>(&'a LispVal);
+
+impl Debug for Bound<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            LispVal::Environment(_) => write!(f, "{}", self.0),
+            value => write!(f, "{:?}", value),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Environment(Vec<EnvFrame>);
+/// A chain of frames, innermost first. Clones share frames, which is how closures
+/// capture the environment they are created in. Environments are only equal to
+/// themselves.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Environment(Gc<EnvFrame>);
 
 impl Environment {
     pub fn new() -> Environment {
-        let mut env = Environment(Vec::new());
-        env.new_frame();
-        env
+        Environment(Gc::new(EnvFrame::new(None)))
     }
 
-    pub fn lookup(&self, key: &str) -> Option<&LispVal> {
-        self.0
-            .iter()
-            .rev()
-            .find_map(|frame: &EnvFrame| frame.lookup(key))
+    pub fn lookup(&self, key: impl Into<Symbol>) -> Option<LispVal> {
+        let key = key.into();
+        let mut env = Some(self);
+        while let Some(Environment(frame)) = env {
+            if let Some(value) = frame.lookup(key) {
+                return Some(value);
+            }
+            env = frame.parent.as_ref();
+        }
+        None
+    }
+
+    /// Like `lookup`, but skips the `depth` innermost frames.
+    pub fn lookup_from(&self, depth: usize, key: Symbol) -> Option<LispVal> {
+        self.ancestor(depth)?.lookup(key)
+    }
+
+    /// The value of the `slot`-th binding of the frame `depth` frames out, for the
+    /// variables whose position the analysis knows.
+    pub fn get(&self, depth: usize, slot: usize) -> Option<LispVal> {
+        let bindings = self.ancestor(depth)?.0.bindings.borrow();
+        bindings.get(slot).map(|(_, value)| value.clone())
+    }
+
+    fn ancestor(&self, depth: usize) -> Option<&Environment> {
+        let mut env = self;
+        for _ in 0..depth {
+            env = env.0.parent.as_ref()?;
+        }
+        Some(env)
+    }
+
+    /// A new environment whose innermost frame is empty and encloses this one.
+    pub fn extend(&self) -> Environment {
+        Environment(Gc::new(EnvFrame::new(Some(self.clone()))))
     }
 
     pub fn new_frame(&mut self) -> &mut Self {
-        self.0.push(EnvFrame::new());
+        *self = self.extend();
         self
     }
 
     pub fn pop_frame(&mut self) -> &mut Self {
-        self.0.pop();
+        if let Some(parent) = self.0.parent.clone() {
+            *self = parent;
+        }
         self
     }
 
-    pub fn new_binding(&mut self, key: String, value: LispVal) {
-        self.0
-            .last_mut()
-            .expect("no frame to bind to")
-            .0
-            .push((key, value));
+    pub fn new_binding(&self, key: impl Into<Symbol>, value: LispVal) {
+        self.0.push(key.into(), value);
+    }
+
+    /// The bindings of the innermost frame, oldest first.
+    pub fn bindings(&self) -> Vec<(Symbol, LispVal)> {
+        self.0.bindings.borrow().clone()
+    }
+
+    fn frames(&self) -> Vec<&EnvFrame> {
+        let mut frames = vec![&*self.0];
+        while let Some(parent) = &frames[frames.len() - 1].parent {
+            frames.push(&*parent.0);
+        }
+        frames
+    }
+
+    pub fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(&self.0)
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new()
+    }
+}
+
+impl Debug for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Environment")
+            .field(&self.frames().into_iter().rev().collect::<Vec<_>>())
+            .finish()
     }
 }
 
@@ -65,14 +221,41 @@ mod tests {
             .new_binding("a".to_string(), LispVal::Integer(2));
         assert_eq!(
             env.lookup("a"),
-            Some(&LispVal::Integer(2)),
+            Some(LispVal::Integer(2)),
             "should find inner binding"
         );
         env.pop_frame();
         assert_eq!(
             env.lookup("a"),
-            Some(&LispVal::Integer(1)),
+            Some(LispVal::Integer(1)),
             "should find outer binding"
         );
     }
+
+    #[test]
+    fn test_extend_shares_frames() {
+        let env = Environment::new();
+        let inner = env.extend();
+        env.new_binding("b", LispVal::Integer(1));
+        assert_eq!(inner.lookup("b"), Some(LispVal::Integer(1)));
+        assert_eq!(
+            format!("{:?}", inner),
+            "Environment([EnvFrame([(\"b\", Integer(1))]), EnvFrame([])])"
+        );
+    }
+
+    #[test]
+    fn test_environment_bound_in_itself() {
+        let env = Environment::new();
+        env.new_binding("env", LispVal::Environment(env.clone()));
+        assert_eq!(env.lookup("env"), Some(LispVal::Environment(env.clone())));
+        assert_ne!(
+            LispVal::Environment(env.clone()),
+            LispVal::Environment(Environment::new())
+        );
+        assert_eq!(
+            format!("{:?}", env),
+            "Environment([EnvFrame([(\"env\", #<environment>)])])"
+        );
+    }
 }
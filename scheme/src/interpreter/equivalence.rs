@@ -0,0 +1,80 @@
+use std::hash::{Hash, Hasher};
+
+use crate::parser::parser::LispVal;
+
+/// `eq?`. Integers and characters are immediate values here, so `eq?` cannot be told
+/// apart from `eqv?`.
+pub(crate) fn eq(a: &LispVal, b: &LispVal) -> bool {
+    eqv(a, b)
+}
+
+/// `eqv?`. Pairs, strings, hash tables, records and procedures are compared by
+/// identity. Lists written in the source are copied on every use and have no
+/// identity, so they are compared element-wise with `eqv?`, which keeps `(eqv? l l)`
+/// true.
+pub(crate) fn eqv(a: &LispVal, b: &LispVal) -> bool {
+    match (a, b) {
+        (LispVal::Pair(a), LispVal::Pair(b)) => a.ptr_eq(b),
+        (LispVal::Pair(_), _) | (_, LispVal::Pair(_)) => false,
+        (LispVal::String(a), LispVal::String(b)) => a.identity() == b.identity(),
+        (LispVal::List(a), LispVal::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| eqv(a, b))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// `equal?`. The equality of values already compares strings by content, lists
+/// structurally and everything else like `eqv?`.
+pub(crate) fn equal(a: &LispVal, b: &LispVal) -> bool {
+    a == b
+}
+
+/// A hash consistent with `eqv?`: pairs and strings are hashed by identity.
+pub(crate) fn hash_eqv<H: Hasher>(v: &LispVal, state: &mut H) {
+    match v {
+        LispVal::Pair(p) => p.hash(state),
+        LispVal::String(s) => s.identity().hash(state),
+        LispVal::List(l) => {
+            l.len().hash(state);
+            for x in l {
+                hash_eqv(x, state);
+            }
+        }
+        v => v.hash(state),
+    }
+}
+
+/// Builds `eq?`, `eqv?` and `equal?` from the predicate they are named after.
+pub(super) fn predicate(
+    who: &'static str,
+    f: fn(&LispVal, &LispVal) -> bool,
+) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        let [a, b] = v.as_slice() else {
+            return Err(format!("{} expects 2 arguments", who));
+        };
+        Ok(LispVal::Bool(f(a, b)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::strings::LispString;
+
+    use super::*;
+
+    #[test]
+    fn test_equivalence() {
+        let s = LispVal::String(LispString::immutable("a"));
+        let t = LispVal::String(LispString::immutable("a"));
+        assert!(eqv(&s, &s.clone()));
+        assert!(!eqv(&s, &t));
+        assert!(equal(&s, &t));
+        let l = LispVal::List(vec![LispVal::Integer(1), s.clone()]);
+        let m = LispVal::List(vec![LispVal::Integer(1), t]);
+        assert!(eq(&l, &l.clone()));
+        assert!(!eqv(&l, &m));
+        assert!(equal(&l, &m));
+    }
+}
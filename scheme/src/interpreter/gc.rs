@@ -0,0 +1,520 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    ops::Deref,
+    rc::{Rc, Weak},
+};
+
+use crate::parser::parser::LispVal;
+
+use super::limits::LimitExceeded;
+
+/// Implemented by everything a `Gc` handle can point to.
+pub trait Trace {
+    /// Reports to `tracer` every handle this object holds, directly or inside plain
+    /// values such as lists, and the memory it holds outside the heap. A handle must be
+    /// reported once per reference held.
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Drops the values held by a garbage object to break the cycles it is part of.
+    /// Cycles always go through interior mutability, so only objects with a `RefCell`
+    /// need to do anything.
+    fn clear(&self) {}
+}
+
+/// Collects the handles reported by `Trace::trace`.
+pub struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(usize),
+    /// set when an object could not be traced because it is mutably borrowed
+    failed: bool,
+    /// what the objects traced hold outside the heap, when measuring the live ones
+    payload: Option<&'a mut Payload>,
+}
+
+/// The memory live objects hold outside the heap, such as the elements of lists.
+#[derive(Default)]
+struct Payload {
+    bytes: usize,
+    /// the storage shared between objects that was counted, so it is counted once
+    shared: HashSet<usize>,
+}
+
+impl Tracer<'_> {
+    pub fn visit<T: Trace + 'static>(&mut self, gc: &Gc<T>) {
+        (self.visit)(gc.identity())
+    }
+
+    /// Reports `bytes` of memory the object owns, like the elements of a list.
+    pub fn owns(&mut self, bytes: usize) {
+        if let Some(payload) = &mut self.payload {
+            payload.bytes = payload.bytes.saturating_add(bytes);
+        }
+    }
+
+    /// Reports `bytes` of memory identified by `identity`, which several objects may
+    /// share, like the characters of a string.
+    pub fn shares(&mut self, identity: usize, bytes: usize) {
+        if let Some(payload) = &mut self.payload {
+            if payload.shared.insert(identity) {
+                payload.bytes = payload.bytes.saturating_add(bytes);
+            }
+        }
+    }
+
+    /// Gives up on the current collection, for objects whose contents are borrowed.
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+}
+
+/// A shared reference to an object of the heap. Handles are counted like `Rc`, so
+/// acyclic garbage is freed right away; cycles are found by `collect`.
+pub struct Gc<T: Trace + 'static>(Rc<T>);
+
+impl<T: Trace + 'static> Gc<T> {
+    pub fn new(value: T) -> Gc<T> {
+        let object = Rc::new(value);
+        let weak: Weak<dyn Trace> = Rc::downgrade(&object) as Weak<dyn Trace>;
+        HEAP.with(|heap| heap.borrow_mut().register(weak, object_size::<T>()));
+        Gc(object)
+    }
+
+    /// The address of the object, which identifies it as long as it is alive.
+    pub fn identity(&self) -> usize {
+        Rc::as_ptr(&self.0) as *const () as usize
+    }
+
+    pub fn ptr_eq(&self, other: &Gc<T>) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// The object itself when this is its only handle, as when freeing long chains of
+    /// objects without recursing.
+    pub fn into_inner(self) -> Option<T> {
+        Rc::into_inner(self.0)
+    }
+}
+
+impl<T: Trace + 'static> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        Gc(self.0.clone())
+    }
+}
+
+impl<T: Trace + 'static> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Objects of the heap are only equal to themselves.
+impl<T: Trace + 'static> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl<T: Trace + 'static> Eq for Gc<T> {}
+
+impl<T: Trace + 'static> PartialOrd for Gc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Trace + 'static> Ord for Gc<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl<T: Trace + 'static> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl<T: Trace + Debug + 'static> Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Every object allocated on this thread, and when to look for cycles among them.
+/// Sizes are in bytes, counting the memory objects hold outside the heap.
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    /// the size of what was allocated since the last collection
+    allocated: usize,
+    /// the size of the objects found alive by the last collection
+    live: usize,
+    /// how large the heap may grow
+    limit: Option<usize>,
+}
+
+/// Collections run once this many bytes were allocated, or as many as were alive after
+/// the last collection if that is more.
+const MIN_COLLECTION_INTERVAL: usize = 1 << 20;
+
+/// The memory each object takes besides its value: the reference counts and its entry
+/// in the list of objects.
+const OBJECT_OVERHEAD: usize = 2 * size_of::<usize>() + size_of::<Weak<dyn Trace>>();
+
+impl Heap {
+    fn register(&mut self, object: Weak<dyn Trace>, size: usize) {
+        self.objects.push(object);
+        self.allocated = self.allocated.saturating_add(size);
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            objects: Vec::new(),
+            allocated: 0,
+            live: 0,
+            limit: None,
+        })
+    };
+}
+
+/// Collects cycles when enough objects were allocated since the last collection. Only
+/// call this where no `RefCell` of the heap is mutably borrowed.
+pub fn maybe_collect() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated >= MIN_COLLECTION_INTERVAL.max(heap.live)
+    });
+    if due {
+        collect();
+    }
+}
+
+/// An upper bound of the size of the heap: what was alive after the last collection
+/// and what was allocated since.
+pub fn heap_size() -> usize {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.live.saturating_add(heap.allocated)
+    })
+}
+
+/// The size an object holding a `T` is charged on the heap.
+pub fn object_size<T>() -> usize {
+    OBJECT_OVERHEAD + size_of::<T>()
+}
+
+/// Bounds the size of the heap of this thread, or lifts the bound, returning the bound
+/// it replaces.
+pub fn set_limit(limit: Option<usize>) -> Option<usize> {
+    HEAP.with(|heap| std::mem::replace(&mut heap.borrow_mut().limit, limit))
+}
+
+/// Makes sure `bytes` more fit on the heap, collecting if needed, for primitives about
+/// to allocate that much. `reserve(0)` checks that the heap is within its limit.
+pub fn reserve(bytes: usize) -> Result<(), String> {
+    let Some(limit) = HEAP.with(|heap| heap.borrow().limit) else {
+        return Ok(());
+    };
+    if heap_size().saturating_add(bytes) > limit && collect().saturating_add(bytes) > limit {
+        return Err(LimitExceeded::Heap.to_string());
+    }
+    Ok(())
+}
+
+/// Counts `bytes` allocated outside of objects, like the characters of a string, until
+/// the next collection measures what is still alive.
+pub fn charge(bytes: usize) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated = heap.allocated.saturating_add(bytes);
+    })
+}
+
+/// An empty vector with room for `len` elements, reserved and charged on the heap, for
+/// primitives making strings and lists as long as the program asks for. Running out of
+/// memory is an error rather than an abort.
+pub fn vec_with_capacity<T>(len: usize) -> Result<Vec<T>, String> {
+    let bytes = len.saturating_mul(size_of::<T>());
+    reserve(bytes)?;
+    let mut v = Vec::new();
+    v.try_reserve_exact(len)
+        .map_err(|_| LimitExceeded::Heap.to_string())?;
+    charge(bytes);
+    Ok(v)
+}
+
+/// Frees the objects only reachable from each other, returning the size of the heap
+/// left.
+///
+/// Handles held by Rust code are invisible, so roots are found by elimination: the
+/// references an object receives from other objects are subtracted from its reference
+/// count, and whatever is left over comes from outside the heap. Everything reachable
+/// from those objects is alive, the rest is garbage.
+pub fn collect() -> usize {
+    let objects = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>()
+    });
+    let index = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (Rc::as_ptr(object) as *const () as usize, i))
+        .collect::<HashMap<_, _>>();
+    // minus one for the handle in `objects`
+    let mut external = objects
+        .iter()
+        .map(|object| Rc::strong_count(object) - 1)
+        .collect::<Vec<_>>();
+    let mut failed = false;
+    for object in &objects {
+        let mut visit = |id: usize| {
+            if let Some(&i) = index.get(&id) {
+                external[i] -= 1;
+            }
+        };
+        let mut tracer = Tracer {
+            visit: &mut visit,
+            failed: false,
+            payload: None,
+        };
+        object.trace(&mut tracer);
+        failed |= tracer.failed;
+    }
+    if failed {
+        drop(objects);
+        return finish(heap_size());
+    }
+    let mut alive = external.iter().map(|n| *n > 0).collect::<Vec<_>>();
+    let mut pending = (0..objects.len()).filter(|i| alive[*i]).collect::<Vec<_>>();
+    let mut payload = Payload::default();
+    while let Some(i) = pending.pop() {
+        let mut children = Vec::new();
+        let mut visit = |id: usize| children.push(id);
+        objects[i].trace(&mut Tracer {
+            visit: &mut visit,
+            failed: false,
+            payload: Some(&mut payload),
+        });
+        for child in children {
+            if let Some(&j) = index.get(&child) {
+                if !alive[j] {
+                    alive[j] = true;
+                    pending.push(j);
+                }
+            }
+        }
+    }
+    let mut live = payload.bytes;
+    for (object, alive) in objects.iter().zip(&alive) {
+        if *alive {
+            live = live.saturating_add(OBJECT_OVERHEAD + std::mem::size_of_val(&**object));
+        } else {
+            object.clear();
+        }
+    }
+    drop(objects);
+    finish(live)
+}
+
+thread_local! {
+    /// The values waiting to be dropped by the `release` running, if one is.
+    static RELEASED: RefCell<Option<Vec<LispVal>>> = const { RefCell::new(None) };
+}
+
+/// Whether dropping `v` may drop other values it holds.
+fn nests(v: &LispVal) -> bool {
+    match v {
+        LispVal::List(l) | LispVal::Values(l) => !l.is_empty(),
+        LispVal::Pair(_)
+        | LispVal::Function(_)
+        | LispVal::CaseLambda(_)
+        | LispVal::Environment(_)
+        | LispVal::Promise(_)
+        | LispVal::StreamPair(_)
+        | LispVal::HashTable(_)
+        | LispVal::Record(_)
+        | LispVal::Parameter(_) => true,
+        _ => false,
+    }
+}
+
+/// Drops the values an object held, for the `Drop` of objects holding values. The
+/// values dropped meanwhile are queued rather than dropped in a nested call, so that
+/// long chains of objects, like nested lists, records or closures, are freed in a loop
+/// instead of overflowing the stack.
+pub fn release(values: impl IntoIterator<Item = LispVal>) {
+    let mut values = values.into_iter().filter(nests).peekable();
+    if values.peek().is_none() {
+        return;
+    }
+    let running = RELEASED.try_with(|queue| {
+        let mut queue = queue.borrow_mut();
+        match &mut *queue {
+            Some(queue) => {
+                queue.extend(&mut values);
+                true
+            }
+            None => {
+                *queue = Some(Vec::new());
+                false
+            }
+        }
+    });
+    // dropped normally if the thread is exiting, or queued above
+    if running != Ok(false) {
+        return;
+    }
+    let mut pending = values.collect::<Vec<_>>();
+    loop {
+        while let Some(v) = pending.pop() {
+            match v {
+                LispVal::List(l) | LispVal::Values(l) => pending.extend(l),
+                v => drop(v),
+            }
+        }
+        pending = RELEASED
+            .with(|queue| queue.borrow_mut().as_mut().map(std::mem::take))
+            .unwrap_or_default();
+        if pending.is_empty() {
+            break;
+        }
+    }
+    RELEASED.with(|queue| queue.borrow_mut().take());
+}
+
+fn finish(live: usize) -> usize {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated = 0;
+        heap.live = live;
+    });
+    live
+}
+
+impl Trace for LispVal {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            LispVal::List(v) | LispVal::Values(v) => {
+                tracer.owns(v.capacity() * size_of::<LispVal>());
+                v.iter().for_each(|x| x.trace(tracer))
+            }
+            LispVal::Pair(p) => tracer.visit(p),
+            LispVal::Function(f) => tracer.visit(f),
+            LispVal::CaseLambda(clauses) => tracer.visit(clauses),
+            LispVal::Environment(env) => env.trace(tracer),
+            LispVal::Promise(p) => p.trace(tracer),
+            LispVal::StreamPair(p) => {
+                p.car.trace(tracer);
+                p.cdr.trace(tracer);
+            }
+            LispVal::HashTable(t) => t.trace(tracer),
+            LispVal::Record(r) => r.trace(tracer),
+            LispVal::Parameter(p) => p.trace(tracer),
+            LispVal::String(s) => tracer.shares(s.identity(), s.len() * size_of::<char>()),
+            LispVal::Port(p) => p.trace(tracer),
+            LispVal::Atom(_)
+            | LispVal::Symbol(_)
+            | LispVal::Keyword(_)
+            | LispVal::Integer(_)
+            | LispVal::Bool(_)
+            | LispVal::Char(_)
+            | LispVal::RecordProcedure(_)
+            | LispVal::Eof
+            | LispVal::Unspecified
+            | LispVal::Primitive { .. } => {}
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.owns(self.capacity() * size_of::<T>());
+        self.iter().for_each(|x| x.trace(tracer))
+    }
+}
+
+/// Slices shared by several objects, like the body of a procedure by its closures.
+impl<T: Trace> Trace for Rc<[T]> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.shares(self.as_ptr() as usize, size_of_val::<[T]>(self));
+        self.iter().for_each(|x| x.trace(tracer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// A mutable box, the simplest object that can be part of a cycle.
+    struct Node {
+        next: RefCell<Option<Gc<Node>>>,
+    }
+
+    impl Node {
+        fn new() -> Gc<Node> {
+            Gc::new(Node {
+                next: RefCell::new(None),
+            })
+        }
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            match self.next.try_borrow() {
+                Ok(next) => next.iter().for_each(|next| tracer.visit(next)),
+                Err(_) => tracer.fail(),
+            }
+        }
+
+        fn clear(&self) {
+            self.next.borrow_mut().take();
+        }
+    }
+
+    #[test]
+    fn test_collect_cycles() {
+        let baseline = collect();
+        let a = Node::new();
+        let b = Node::new();
+        *b.next.borrow_mut() = Some(a.clone());
+        *a.next.borrow_mut() = Some(b.clone());
+        let weak = Rc::downgrade(&a.0);
+        // held from the stack, so both are alive
+        let size = object_size::<Node>();
+        assert_eq!(collect(), baseline + 2 * size);
+        drop(b);
+        assert_eq!(collect(), baseline + 2 * size);
+        drop(a);
+        assert!(weak.upgrade().is_some(), "a cycle is not freed by counting");
+        assert_eq!(collect(), baseline);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_no_collection_while_borrowed() {
+        let garbage = Node::new();
+        *garbage.next.borrow_mut() = Some(garbage.clone());
+        let weak = Rc::downgrade(&garbage.0);
+        drop(garbage);
+        let borrowed = Node::new();
+        let guard = borrowed.next.borrow_mut();
+        collect();
+        assert!(weak.upgrade().is_some());
+        drop(guard);
+        collect();
+        assert!(weak.upgrade().is_none());
+    }
+}
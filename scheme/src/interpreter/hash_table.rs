@@ -0,0 +1,343 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    equivalence,
+    gc::{Gc, Trace, Tracer},
+    pair, Interpreter,
+};
+
+/// The equivalence predicate a hash table compares its keys with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Equivalence {
+    Eq,
+    Eqv,
+    Equal,
+    String,
+}
+
+/// A key together with the equivalence of the table it lives in, so that
+/// `Hash` and `Eq` agree with the Scheme predicate.
+#[derive(Clone)]
+struct Key {
+    equivalence: Equivalence,
+    value: LispVal,
+}
+
+impl Key {
+    fn new(equivalence: Equivalence, value: LispVal) -> Result<Key, String> {
+        if equivalence == Equivalence::String && value.as_string().is_none() {
+            return Err(format!("{} is not a string key", value));
+        }
+        Ok(Key { equivalence, value })
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.equivalence {
+            Equivalence::Eq | Equivalence::Eqv => equivalence::hash_eqv(&self.value, state),
+            Equivalence::Equal | Equivalence::String => self.value.hash(state),
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        match self.equivalence {
+            Equivalence::Eq => equivalence::eq(&self.value, &other.value),
+            Equivalence::Eqv => equivalence::eqv(&self.value, &other.value),
+            Equivalence::Equal | Equivalence::String => {
+                equivalence::equal(&self.value, &other.value)
+            }
+        }
+    }
+}
+
+impl Eq for Key {}
+
+/// A mutable hash table. Clones refer to the same table.
+#[derive(Clone)]
+pub struct HashTable {
+    equivalence: Equivalence,
+    entries: Gc<RefCell<HashMap<Key, LispVal>>>,
+}
+
+impl Trace for RefCell<HashMap<Key, LispVal>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.try_borrow() {
+            Ok(entries) => {
+                tracer.owns(entries.capacity() * std::mem::size_of::<(Key, LispVal)>());
+                entries.iter().for_each(|(key, value)| {
+                    key.value.trace(tracer);
+                    value.trace(tracer);
+                })
+            }
+            Err(_) => tracer.fail(),
+        }
+    }
+
+    fn clear(&self) {
+        self.borrow_mut().clear();
+    }
+}
+
+impl HashTable {
+    pub fn new(equivalence: Equivalence) -> HashTable {
+        HashTable {
+            equivalence,
+            entries: Gc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    pub fn get(&self, key: &LispVal) -> Result<Option<LispVal>, String> {
+        let key = Key::new(self.equivalence, key.clone())?;
+        Ok(self.entries.borrow().get(&key).cloned())
+    }
+
+    pub fn insert(&self, key: LispVal, value: LispVal) -> Result<(), String> {
+        let key = Key::new(self.equivalence, key)?;
+        self.entries.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &LispVal) -> Result<(), String> {
+        let key = Key::new(self.equivalence, key.clone())?;
+        self.entries.borrow_mut().remove(&key);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().is_empty()
+    }
+
+    /// A snapshot of the entries, so the table may be modified while walking it.
+    pub fn entries(&self) -> Vec<(LispVal, LispVal)> {
+        self.entries
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.value.clone(), v.clone()))
+            .collect()
+    }
+
+    pub fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(&self.entries)
+    }
+
+    fn identity(&self) -> usize {
+        self.entries.identity()
+    }
+}
+
+/// Hash tables are only equal to themselves.
+impl PartialEq for HashTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for HashTable {}
+
+impl PartialOrd for HashTable {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HashTable {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for HashTable {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl Debug for HashTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HashTable({:?}, {})", self.equivalence, self.len())
+    }
+}
+
+fn table_arg<'a>(v: &'a [LispVal], who: &str) -> Result<&'a HashTable, String> {
+    match v.first() {
+        Some(LispVal::HashTable(t)) => Ok(t),
+        _ => Err(format!("{} expects a hash table as argument 1", who)),
+    }
+}
+
+pub(super) fn make_hash_table(
+    equivalence: Equivalence,
+) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        if !v.is_empty() {
+            return Err("hash table constructors expect no arguments".to_string());
+        }
+        Ok(LispVal::HashTable(HashTable::new(equivalence)))
+    }
+}
+
+/// `(make-hash-table [equivalence])`, where the equivalence is one of the
+/// `eq?`, `eqv?`, `equal?` and `string=?` procedures. Defaults to `equal?`.
+pub(super) fn make_hash_table_with(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let equivalence = match v.as_slice() {
+        [] => Equivalence::Equal,
+        [LispVal::Primitive { name, .. }] => match name.name() {
+            "eq?" => Equivalence::Eq,
+            "eqv?" => Equivalence::Eqv,
+            "equal?" => Equivalence::Equal,
+            "string=?" => Equivalence::String,
+            _ => return Err(format!("make-hash-table: unsupported equivalence {}", name)),
+        },
+        _ => return Err("make-hash-table expects an equivalence procedure".to_string()),
+    };
+    Ok(LispVal::HashTable(HashTable::new(equivalence)))
+}
+
+pub(super) fn is_hash_table(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("hash-table? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(matches!(x, LispVal::HashTable(_))))
+}
+
+pub(super) fn hash_table_ref_default(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-ref/default")?;
+    let [_, key, default] = v.as_slice() else {
+        return Err("hash-table-ref/default expects 3 arguments".to_string());
+    };
+    Ok(table.get(key)?.unwrap_or(default.clone()))
+}
+
+pub(super) fn hash_table_set(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-set!")?;
+    let [_, key, value] = v.as_slice() else {
+        return Err("hash-table-set! expects 3 arguments".to_string());
+    };
+    table.insert(key.clone(), value.clone())?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn hash_table_delete(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-delete!")?;
+    let [_, key] = v.as_slice() else {
+        return Err("hash-table-delete! expects 2 arguments".to_string());
+    };
+    table.remove(key)?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn hash_table_contains(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-contains?")?;
+    let [_, key] = v.as_slice() else {
+        return Err("hash-table-contains? expects 2 arguments".to_string());
+    };
+    Ok(LispVal::Bool(table.get(key)?.is_some()))
+}
+
+pub(super) fn hash_table_count(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-count")?;
+    Ok(LispVal::Integer(table.len() as i64))
+}
+
+pub(super) fn hash_table_keys(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-keys")?;
+    pair::try_list(table.entries().into_iter().map(|(k, _)| k).collect())
+}
+
+pub(super) fn hash_table_values(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-values")?;
+    pair::try_list(table.entries().into_iter().map(|(_, v)| v).collect())
+}
+
+pub(super) fn hash_table_to_alist(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table->alist")?;
+    pair::reserve(table.len().saturating_mul(2))?;
+    pair::try_list(
+        table
+            .entries()
+            .into_iter()
+            .map(|(k, v)| pair::cons(k, v))
+            .collect(),
+    )
+}
+
+/// `(hash-table-ref table key [failure])` calls the `failure` thunk when `key` is missing.
+pub(super) fn hash_table_ref(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-ref")?;
+    let (key, failure) = match v.as_slice() {
+        [_, key] => (key, None),
+        [_, key, failure] => (key, Some(failure)),
+        _ => return Err("hash-table-ref expects 2 or 3 arguments".to_string()),
+    };
+    match (table.get(key)?, failure) {
+        (Some(value), _) => Ok(value),
+        (None, Some(failure)) => interpreter.apply_procedure("hash-table-ref", failure, &[]),
+        (None, None) => Err(format!("hash-table-ref: no value for key {}", key)),
+    }
+}
+
+pub(super) fn hash_table_update(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-update!")?;
+    let (key, f, failure) = match v.as_slice() {
+        [_, key, f] => (key, f, None),
+        [_, key, f, failure] => (key, f, Some(failure)),
+        _ => return Err("hash-table-update! expects 3 or 4 arguments".to_string()),
+    };
+    let old = match (table.get(key)?, failure) {
+        (Some(value), _) => value,
+        (None, Some(failure)) => interpreter.apply_procedure("hash-table-update!", failure, &[])?,
+        (None, None) => return Err(format!("hash-table-update!: no value for key {}", key)),
+    };
+    let new = interpreter.apply_procedure("hash-table-update!", f, &[old])?;
+    table.insert(key.clone(), new)?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn hash_table_update_default(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-update!/default")?;
+    let [_, key, f, default] = v.as_slice() else {
+        return Err("hash-table-update!/default expects 4 arguments".to_string());
+    };
+    let old = table.get(key)?.unwrap_or(default.clone());
+    let new = interpreter.apply_procedure("hash-table-update!/default", f, &[old])?;
+    table.insert(key.clone(), new)?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn hash_table_walk(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let table = table_arg(&v, "hash-table-walk")?;
+    let [_, f] = v.as_slice() else {
+        return Err("hash-table-walk expects 2 arguments".to_string());
+    };
+    for (key, value) in table.entries() {
+        interpreter.apply_procedure("hash-table-walk", f, &[key, value])?;
+    }
+    Ok(LispVal::Unspecified)
+}
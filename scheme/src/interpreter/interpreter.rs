@@ -1,11 +1,229 @@
-use std::io::Write;
+use std::{
+    cell::OnceCell,
+    cmp::Ordering,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::parser::{parser::LispVal, Parser};
 
-use super::environment::Environment;
+use super::{
+    environment::Environment,
+    equivalence,
+    gc::{self, Gc},
+    hash_table::{self, Equivalence},
+    library::{self, Libraries},
+    limits::{self, InterruptHandle, Limits, Meter},
+    lists,
+    native::{FromLisp, IntoLispArgs, NativeFn},
+    pair::{self, Pair},
+    parameter,
+    port::{self, CurrentPorts, Port},
+    procedure::{Arity, Closure, Params},
+    promise::{self, Promise},
+    record::{RecordProcedure, RecordProcedureKind, RecordType},
+    sandbox::{self, Profile, CAPABILITIES},
+    stream::{self, StreamPair},
+    strings::{self, LispString},
+    symbol::{Symbol, SymbolMap},
+    values,
+};
+
+/// Every primitive bound in the initial environment, with the arguments it accepts.
+const PRIMITIVES: &[(&str, Arity)] = &[
+    ("+", Arity::at_least(0)),
+    ("*", Arity::at_least(0)),
+    ("-", Arity::at_least(1)),
+    ("car", Arity::exactly(1)),
+    ("cdr", Arity::exactly(1)),
+    ("cons", Arity::exactly(2)),
+    ("set-car!", Arity::exactly(2)),
+    ("set-cdr!", Arity::exactly(2)),
+    ("eq?", Arity::exactly(2)),
+    ("eqv?", Arity::exactly(2)),
+    ("equal?", Arity::exactly(2)),
+    ("not", Arity::exactly(1)),
+    ("=", Arity::at_least(1)),
+    (">", Arity::exactly(2)),
+    ("<", Arity::exactly(2)),
+    ("null?", Arity::exactly(1)),
+    ("pair?", Arity::exactly(1)),
+    ("list?", Arity::exactly(1)),
+    ("list", Arity::at_least(0)),
+    ("length", Arity::exactly(1)),
+    ("append", Arity::at_least(0)),
+    ("reverse", Arity::exactly(1)),
+    ("list-tail", Arity::exactly(2)),
+    ("list-ref", Arity::exactly(2)),
+    ("list-copy", Arity::exactly(1)),
+    ("last-pair", Arity::exactly(1)),
+    ("iota", Arity::between(1, 3)),
+    ("memq", Arity::exactly(2)),
+    ("memv", Arity::exactly(2)),
+    ("assq", Arity::exactly(2)),
+    ("assv", Arity::exactly(2)),
+    ("member", Arity::between(2, 3)),
+    ("assoc", Arity::between(2, 3)),
+    ("delete", Arity::between(2, 3)),
+    ("map", Arity::at_least(2)),
+    ("for-each", Arity::at_least(2)),
+    ("filter", Arity::exactly(2)),
+    ("reduce", Arity::exactly(3)),
+    ("fold", Arity::at_least(3)),
+    ("fold-left", Arity::at_least(3)),
+    ("fold-right", Arity::at_least(3)),
+    ("apply", Arity::at_least(2)),
+    ("procedure?", Arity::exactly(1)),
+    ("string?", Arity::exactly(1)),
+    ("make-string", Arity::between(1, 2)),
+    ("string", Arity::at_least(0)),
+    ("string-length", Arity::exactly(1)),
+    ("string-ref", Arity::exactly(2)),
+    ("string-set!", Arity::exactly(3)),
+    ("substring", Arity::exactly(3)),
+    ("string-append", Arity::at_least(0)),
+    ("string-copy", Arity::between(1, 3)),
+    ("string->list", Arity::between(1, 3)),
+    ("list->string", Arity::exactly(1)),
+    ("string->symbol", Arity::exactly(1)),
+    ("symbol->string", Arity::exactly(1)),
+    ("symbol?", Arity::exactly(1)),
+    ("symbol=?", Arity::at_least(2)),
+    ("string-upcase", Arity::exactly(1)),
+    ("string-downcase", Arity::exactly(1)),
+    ("string=?", Arity::at_least(2)),
+    ("string<?", Arity::at_least(2)),
+    ("string>?", Arity::at_least(2)),
+    ("string<=?", Arity::at_least(2)),
+    ("string>=?", Arity::at_least(2)),
+    ("string-ci=?", Arity::at_least(2)),
+    ("string-ci<?", Arity::at_least(2)),
+    ("string-ci>?", Arity::at_least(2)),
+    ("string-ci<=?", Arity::at_least(2)),
+    ("string-ci>=?", Arity::at_least(2)),
+    ("string-map", Arity::at_least(2)),
+    ("string-for-each", Arity::at_least(2)),
+    ("make-hash-table", Arity::between(0, 1)),
+    ("make-equal-hash-table", Arity::exactly(0)),
+    ("make-eq-hash-table", Arity::exactly(0)),
+    ("make-eqv-hash-table", Arity::exactly(0)),
+    ("make-string-hash-table", Arity::exactly(0)),
+    ("hash-table?", Arity::exactly(1)),
+    ("hash-table-ref", Arity::between(2, 3)),
+    ("hash-table-ref/default", Arity::exactly(3)),
+    ("hash-table-set!", Arity::exactly(3)),
+    ("hash-table-delete!", Arity::exactly(2)),
+    ("hash-table-contains?", Arity::exactly(2)),
+    ("hash-table-exists?", Arity::exactly(2)),
+    ("hash-table-count", Arity::exactly(1)),
+    ("hash-table-size", Arity::exactly(1)),
+    ("hash-table-keys", Arity::exactly(1)),
+    ("hash-table-values", Arity::exactly(1)),
+    ("hash-table->alist", Arity::exactly(1)),
+    ("hash-table-update!", Arity::between(3, 4)),
+    ("hash-table-update!/default", Arity::exactly(4)),
+    ("hash-table-walk", Arity::exactly(2)),
+    ("force", Arity::exactly(1)),
+    ("values", Arity::at_least(0)),
+    ("make-parameter", Arity::between(1, 2)),
+    ("eval", Arity::between(1, 2)),
+    ("interaction-environment", Arity::exactly(0)),
+    ("scheme-report-environment", Arity::exactly(1)),
+    ("null-environment", Arity::exactly(1)),
+    ("environment", Arity::at_least(0)),
+    ("call-with-values", Arity::exactly(2)),
+    ("make-promise", Arity::exactly(1)),
+    ("promise?", Arity::exactly(1)),
+    ("stream-null?", Arity::exactly(1)),
+    ("stream-pair?", Arity::exactly(1)),
+    ("stream?", Arity::exactly(1)),
+    ("stream-car", Arity::exactly(1)),
+    ("stream-cdr", Arity::exactly(1)),
+    ("stream-take", Arity::exactly(2)),
+    ("stream->list", Arity::between(1, 2)),
+    ("list->stream", Arity::exactly(1)),
+    ("display", Arity::between(1, 2)),
+    ("write", Arity::between(1, 2)),
+    ("write-string", Arity::between(1, 2)),
+    ("write-char", Arity::between(1, 2)),
+    ("newline", Arity::between(0, 1)),
+    ("flush-output-port", Arity::between(0, 1)),
+    ("read-line", Arity::between(0, 1)),
+    ("read-char", Arity::between(0, 1)),
+    ("peek-char", Arity::between(0, 1)),
+    ("char-ready?", Arity::between(0, 1)),
+    ("read", Arity::between(0, 1)),
+    ("json-read", Arity::between(0, 1)),
+    ("json-write", Arity::between(1, 2)),
+    ("open-input-string", Arity::exactly(1)),
+    ("open-output-string", Arity::exactly(0)),
+    ("get-output-string", Arity::exactly(1)),
+    ("open-input-file", Arity::exactly(1)),
+    ("open-output-file", Arity::exactly(1)),
+    ("close-port", Arity::exactly(1)),
+    ("close-input-port", Arity::exactly(1)),
+    ("close-output-port", Arity::exactly(1)),
+    ("eof-object", Arity::exactly(0)),
+    ("eof-object?", Arity::exactly(1)),
+    ("port?", Arity::exactly(1)),
+    ("input-port?", Arity::exactly(1)),
+    ("output-port?", Arity::exactly(1)),
+    ("call-with-port", Arity::exactly(2)),
+    ("call-with-output-file", Arity::exactly(2)),
+    ("call-with-input-file", Arity::exactly(2)),
+    ("call-with-output-string", Arity::exactly(1)),
+    ("with-output-to-string", Arity::exactly(1)),
+    ("load", Arity::exactly(1)),
+];
+
+type HigherOrderPrimitive = fn(&mut Interpreter, Vec<LispVal>) -> Result<LispVal, String>;
+
+/// The implementation of a primitive, looked up by name once and then cached.
+#[derive(Clone)]
+enum PrimitiveFn {
+    Plain(Rc<dyn Fn(Vec<LispVal>) -> Result<LispVal, String>>),
+    HigherOrder(HigherOrderPrimitive),
+}
+
+/// The outcome of one evaluation step: a value, or what is in tail position and left
+/// for `eval` to evaluate in the current environment. Expressions are borrowed from the
+/// code being evaluated rather than copied.
+enum Tail<'a> {
+    Value(LispVal),
+    Eval(&'a LispVal),
+    /// the body of a procedure called in tail position, which `eval` holds on to while
+    /// evaluating it
+    Enter(Rc<[LispVal]>),
+}
+
+/// How an interpreter evaluates programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// evaluates the syntax tree directly
+    #[default]
+    TreeWalker,
+    /// analyzes each top-level form and closure body once, resolving the variables it
+    /// can to their position in the frames, and executes the result
+    Analyzer,
+    /// compiles each top-level form and closure body to bytecode for a stack machine;
+    /// definitions of records and libraries, imports and the forms that bind multiple
+    /// values or parameters are still walked
+    Vm,
+}
 
 pub struct Interpreter {
-    env: Environment,
+    pub(super) env: Environment,
+    /// the frame of the primitives, which built-in libraries export from
+    pub(super) builtins: Environment,
+    /// the top-level environment of programs, the REPL and `load`
+    global: Environment,
+    pub(super) ports: CurrentPorts,
+    pub(super) libraries: Libraries,
+    backend: Backend,
+    primitives: SymbolMap<PrimitiveFn>,
+    pub(super) meter: Meter,
+    pub(super) profile: Profile,
 }
 
 const WELCOME: &str = "Welcome to a Scheme interpreter!";
@@ -15,14 +233,158 @@ const PROMPT: &str = "]=> ";
 /// This implementation always eagerly evaluates all expressions.
 impl Interpreter {
     pub fn new() -> Interpreter {
+        Interpreter::with_profile(Profile::default())
+    }
+
+    /// An interpreter whose programs may only do what `profile` grants.
+    pub fn with_profile(profile: Profile) -> Interpreter {
+        let builtins = Environment::new();
+        for (name, arity) in PRIMITIVES {
+            builtins.new_binding(
+                *name,
+                LispVal::Primitive {
+                    name: Symbol::intern(name),
+                    arity: *arity,
+                },
+            );
+        }
+        for name in ["stream-null", "the-empty-stream"] {
+            builtins.new_binding(name, LispVal::List(Vec::new()));
+        }
+        let ports = CurrentPorts::default();
+        for (name, parameter) in [
+            ("current-input-port", &ports.input),
+            ("current-output-port", &ports.output),
+            ("current-error-port", &ports.error),
+        ] {
+            builtins.new_binding(name, LispVal::Parameter(parameter.clone()));
+        }
+        // the primitives the profile denies are bound to a failure, so programs that
+        // merely refer to them still load
+        let mut primitives = SymbolMap::default();
+        for (name, capability) in CAPABILITIES {
+            if !profile.grants(*capability) {
+                let denied: Rc<dyn Fn(Vec<LispVal>) -> Result<LispVal, String>> =
+                    Rc::new(|_| Err(sandbox::denied(name)));
+                primitives.insert(Symbol::intern(name), PrimitiveFn::Plain(denied));
+            }
+        }
+        let global = builtins.extend();
         Interpreter {
-            env: Environment::new(),
+            env: global.clone(),
+            builtins,
+            global,
+            ports,
+            libraries: Libraries::default(),
+            backend: Backend::default(),
+            primitives,
+            meter: Meter::default(),
+            profile,
         }
     }
 
-    pub fn interpret_file(&mut self, path: std::path::PathBuf) -> Result<LispVal, String> {
-        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
-        self.interpret(&content)
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Bounds the resources evaluation may use from now on, with a full tank of fuel.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.meter.set_limits(limits);
+    }
+
+    /// The fuel left, when it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.meter.fuel()
+    }
+
+    /// A handle another thread can stop evaluation with.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.meter.interrupt_handle()
+    }
+
+    /// Binds `name` to a Rust function, converting its arguments with `FromLisp` and
+    /// what it returns with `IntoLisp`. It is bound with the primitives, so every
+    /// environment built from them sees it too.
+    pub fn define_native<Args>(&mut self, name: &str, f: impl NativeFn<Args>) {
+        let symbol = Symbol::intern(name);
+        let arity = f.arity();
+        let native: Rc<dyn Fn(Vec<LispVal>) -> Result<LispVal, String>> =
+            Rc::new(move |args| f.call(symbol.name(), &args));
+        self.primitives.insert(symbol, PrimitiveFn::Plain(native));
+        self.builtins.new_binding(
+            symbol,
+            LispVal::Primitive {
+                name: symbol,
+                arity,
+            },
+        );
+    }
+
+    /// Calls the procedure bound to `name` at top level with Rust arguments, converting
+    /// what it returns.
+    pub fn call<R: FromLisp>(&mut self, name: &str, args: impl IntoLispArgs) -> Result<R, String> {
+        let procedure = self.get::<LispVal>(name)?;
+        let result = self.limited(|interpreter| {
+            interpreter.apply_procedure(name, &procedure, &args.into_lisp_args())
+        });
+        self.ports.output()?.flush()?;
+        let value = result?;
+        R::from_lisp(&value).ok_or_else(|| {
+            format!(
+                "{} returned {}, which is not of type {}",
+                name,
+                value,
+                R::type_name()
+            )
+        })
+    }
+
+    /// The value of the top-level variable `name`.
+    pub fn get<T: FromLisp>(&self, name: &str) -> Result<T, String> {
+        let value = self
+            .global
+            .lookup(name)
+            .ok_or_else(|| format!("unknown atom {}", name))?;
+        T::from_lisp(&value).ok_or_else(|| {
+            format!(
+                "{} is {}, which is not of type {}",
+                name,
+                value,
+                T::type_name()
+            )
+        })
+    }
+
+    /// Adds a directory to search for the `.sld` files of imported libraries.
+    pub fn add_library_path(&mut self, dir: impl Into<PathBuf>) {
+        self.libraries.search_path.push(dir.into());
+    }
+
+    /// Makes the current input port read from `input` instead of stdin.
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        self.ports
+            .input
+            .replace(LispVal::Port(Port::from_reader(input)));
+    }
+
+    /// Makes the current output port write to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.ports
+            .output
+            .replace(LispVal::Port(Port::from_writer(output)));
+    }
+
+    /// Makes the current error port write to `output` instead of stderr.
+    pub fn set_error_output(&mut self, output: impl Write + 'static) {
+        self.ports
+            .error
+            .replace(LispVal::Port(Port::from_writer(output)));
+    }
+
+    pub fn interpret_file(&mut self, path: PathBuf) -> Result<LispVal, String> {
+        let result = self.limited(|interpreter| interpreter.load_file(&path));
+        self.ports.output()?.flush()?;
+        result
     }
 
     pub fn interpret_repl(&mut self) {
@@ -38,141 +400,759 @@ impl Interpreter {
             }
             let result = self.interpret(&input);
             match result {
-                Ok(v) => println!("\n{:?}\n", v),
+                Ok(v) => println!("\n{}\n", v),
                 Err(e) => println!("\nError: {}\n", e),
             }
         }
     }
 
+    /// Evaluates every form in `s`, returning the value of the last one.
     pub fn interpret(&mut self, s: &str) -> Result<LispVal, String> {
-        let ast = Parser::new(s).parse();
-        self.eval(&ast)
+        let result = self.limited(|interpreter| interpreter.eval_source(s));
+        self.ports.output()?.flush()?;
+        result
+    }
+
+    /// Runs `f` under the heap limit of this interpreter, with its deadline and interrupts
+    /// watched by the primitives that run long. The heap is shared by the interpreters of
+    /// the thread, so what the thread had is restored afterwards.
+    fn limited<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = gc::set_limit(self.meter.max_heap());
+        let watch = limits::set_watch(Some(self.meter.watch()));
+        let result = f(self);
+        limits::set_watch(watch);
+        gc::set_limit(outer);
+        result
+    }
+
+    pub(super) fn eval_source(&mut self, source: &str) -> Result<LispVal, String> {
+        let mut parser = Parser::with_max_nesting(source, self.meter.max_depth());
+        let mut value = LispVal::Unspecified;
+        while let Some(form) = parser.read().map_err(|e| e.to_string())? {
+            value = self.eval_form(&form)?;
+        }
+        Ok(value)
+    }
+
+    /// Evaluates a top-level form with the chosen backend.
+    fn eval_form(&mut self, form: &LispVal) -> Result<LispVal, String> {
+        match self.backend {
+            Backend::TreeWalker => self.eval(form),
+            Backend::Analyzer => self.eval_analyzed(form),
+            Backend::Vm => self.execute(form),
+        }
+    }
+
+    /// Evaluates the forms of a file in the top-level environment. Files it includes
+    /// and libraries it imports are looked up in its directory first.
+    pub(super) fn load_file(&mut self, path: &Path) -> Result<LispVal, String> {
+        let source =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        self.libraries.source_dirs.push(dir);
+        let caller_env = std::mem::replace(&mut self.env, self.global.clone());
+        let result = self.eval_source(&source);
+        self.env = caller_env;
+        self.libraries.source_dirs.pop();
+        result
+    }
+
+    /// Evaluates `v` in the current environment. Expressions in tail position are
+    /// evaluated in a loop rather than recursively, so tail calls run in constant
+    /// stack space; the environment they switch to is restored on the way out.
+    pub(super) fn eval(&mut self, v: &LispVal) -> Result<LispVal, String> {
+        let depth = self.meter.enter()?;
+        let caller_env = self.env.clone();
+        let step = self.eval_step(v);
+        let mut outcome = self.eval_tail(step);
+        while let Ok(Tail::Enter(body)) = outcome {
+            let step = self.eval_sequence(&body);
+            outcome = self.eval_tail(step);
+        }
+        self.env = caller_env;
+        self.meter.leave(depth);
+        match outcome {
+            Ok(Tail::Value(value)) => Ok(value),
+            Ok(_) => unreachable!("eval_tail returns values and bodies only"),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Evaluates the expressions left in tail position by `step`, until there is a value
+    /// or a procedure body to enter.
+    fn eval_tail(&mut self, mut step: Result<Tail<'_>, String>) -> Result<Tail<'static>, String> {
+        loop {
+            match step {
+                Ok(Tail::Eval(next)) => {
+                    gc::maybe_collect();
+                    step = self.eval_step(next)
+                }
+                Ok(Tail::Value(value)) => return Ok(Tail::Value(value)),
+                Ok(Tail::Enter(body)) => return Ok(Tail::Enter(body)),
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    fn eval(&mut self, v: &LispVal) -> Result<LispVal, String> {
+    fn eval_step<'a>(&mut self, v: &'a LispVal) -> Result<Tail<'a>, String> {
+        self.meter.tick()?;
         match v {
-            LispVal::Atom(s) => self.eval_atom(s),
+            LispVal::Atom(s) => self.eval_atom(*s).map(Tail::Value),
             LispVal::List(v) => self.eval_list(v),
-            i @ LispVal::Integer(_) => Ok(i.clone()),
-            b @ LispVal::Bool(_) => Ok(b.clone()),
-            _ => todo!(),
+            i @ LispVal::Integer(_) => Ok(Tail::Value(i.clone())),
+            b @ LispVal::Bool(_) => Ok(Tail::Value(b.clone())),
+            c @ LispVal::Char(_) => Ok(Tail::Value(c.clone())),
+            s @ LispVal::String(_) => Ok(Tail::Value(s.clone())),
+            k @ LispVal::Keyword(_) => Ok(Tail::Value(k.clone())),
+            // values spliced into code built at runtime, such as the promises of
+            // `stream-take`, evaluate to themselves
+            v => Ok(Tail::Value(v.clone())),
         }
     }
 
-    fn eval_atom(&self, s: &str) -> Result<LispVal, String> {
-        self.env
-            .lookup(s)
-            .cloned()
-            .ok_or(format!("unknown atom {}", s))
+    fn eval_atom(&self, s: Symbol) -> Result<LispVal, String> {
+        self.env.lookup(s).ok_or(format!("unknown atom {}", s))
     }
 
-    fn eval_list(&mut self, v: &Vec<LispVal>) -> Result<LispVal, String> {
-        match v.len() {
-            0 => Ok(LispVal::List(v.clone())),
-            _ => v
-                .split_first()
-                .ok_or("cannot split".to_string())
-                .and_then(|v| {
-                    let (LispVal::Atom(s), operands) = v else {
-                        return Err(format!("{:?} is not applicable to {:?}", v.0, v.1));
-                    };
-                    match s.as_str() {
-                        "quote" => return Ok(operands[0].clone()),
-                        "define" => return self.define_value(operands.to_vec()),
-                        "if" => return self.eval_if(operands.to_vec()),
-                        "cond" => return self.eval_cond(operands.to_vec()),
-                        _ => {}
-                    };
-                    self.apply(s, operands)
-                }),
+    fn eval_list<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let Some((operator, operands)) = v.split_first() else {
+            return Ok(Tail::Value(LispVal::List(Vec::new())));
+        };
+        let LispVal::Atom(s) = operator else {
+            return self.eval_application("procedure", operator, operands);
+        };
+        let value = match s.name() {
+            "quote" => match operands {
+                [datum] => Ok(datum.to_datum()),
+                _ => Err("quote expects exactly one datum".to_string()),
+            },
+            "define" => self.define_value(operands),
+            "lambda" => self.eval_lambda(operands, false),
+            "lambda*" => self.eval_lambda(operands, true),
+            "define*" => self.define_function(operands, true),
+            "case-lambda" => self.eval_case_lambda(operands),
+            "if" => return self.eval_if(operands),
+            "cond" => return self.eval_cond(operands),
+            "and" => return self.eval_and(operands),
+            "or" => return self.eval_or(operands),
+            "when" => return self.eval_when(operands, true),
+            "unless" => return self.eval_when(operands, false),
+            "case" => return self.eval_case(operands),
+            "do" => return self.eval_do(operands),
+            "define-record-type" => self.define_record_type(operands),
+            "define-values" => self.define_values(operands),
+            "parameterize" => parameter::parameterize(self, operands),
+            "let-values" => return self.eval_let_values(operands),
+            "receive" => return self.eval_receive(operands),
+            "begin" if operands.is_empty() => Ok(LispVal::Unspecified),
+            "begin" => return self.eval_sequence(operands),
+            "import" => library::import(self, operands),
+            "define-library" => library::define_library(self, operands),
+            "include" => library::include(self, operands),
+            "delay" => self.eval_delay(operands, Promise::delay),
+            "delay-force" => self.eval_delay(operands, Promise::delay_force),
+            "stream-cons" => self.eval_stream_cons(operands, false),
+            "cons-stream" => self.eval_stream_cons(operands, true),
+            _ => return self.eval_application(s.name(), operator, operands),
+        };
+        value.map(Tail::Value)
+    }
+
+    fn eval_application(
+        &mut self,
+        name: &str,
+        operator: &LispVal,
+        operands: &[LispVal],
+    ) -> Result<Tail<'static>, String> {
+        let procedure = self.eval(operator)?;
+        let evaluated_operands = operands
+            .iter()
+            .map(|v| self.eval(v))
+            .collect::<Result<Vec<LispVal>, String>>()?;
+        self.tail_apply(name, &procedure, &evaluated_operands)
+    }
+
+    /// `(lambda (param ...) body ...)`, `(lambda (param ... . rest) body ...)` or
+    /// `(lambda args body ...)`. `extended` also allows the optional and named
+    /// parameters of `lambda*`.
+    fn eval_lambda(&mut self, v: &[LispVal], extended: bool) -> Result<LispVal, String> {
+        let (formals, body) = v.split_first().ok_or("lambda must have a parameter list")?;
+        Ok(LispVal::Function(Gc::new(
+            self.make_closure(formals, body, extended)?,
+        )))
+    }
+
+    /// `(case-lambda (formals body ...) ...)`
+    fn eval_case_lambda(&mut self, v: &[LispVal]) -> Result<LispVal, String> {
+        let clauses = v
+            .iter()
+            .map(|clause| match clause {
+                LispVal::List(clause) if !clause.is_empty() => {
+                    self.make_closure(&clause[0], &clause[1..], false)
+                }
+                _ => Err(format!("case-lambda: bad clause {}", clause)),
+            })
+            .collect::<Result<Vec<Closure>, String>>()?;
+        Ok(LispVal::CaseLambda(Gc::new(clauses)))
+    }
+
+    fn make_closure(
+        &self,
+        formals: &LispVal,
+        body: &[LispVal],
+        extended: bool,
+    ) -> Result<Closure, String> {
+        if body.is_empty() {
+            return Err("procedure body must not be empty".to_string());
         }
+        Ok(Closure {
+            params: Params::from_formals(formals, extended)?,
+            body: body.into(),
+            env: self.env.clone(),
+            code: OnceCell::new(),
+            analyzed: OnceCell::new(),
+        })
     }
 
-    fn eval_if(&mut self, to_vec: Vec<LispVal>) -> Result<LispVal, String> {
-        let (cond, branches) = to_vec.split_first().ok_or("if must have a condition")?;
-        let cond = self.eval(cond)?; // shadowed
-        match branches.len() {
-            1 => self.eval_if_only(cond, to_vec),
-            2 => self.eval_if_else(cond, to_vec),
-            _ => Err("if must have 2 or 3 arguments".to_string()),
+    /// `(let-values (((formals) expr) ...) body ...)`: the expressions are evaluated in the
+    /// outer environment and their values bound like the arguments of a lambda.
+    fn eval_let_values<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let [LispVal::List(bindings), body @ ..] = v else {
+            return Err("let-values expects bindings and a body".to_string());
+        };
+        let bindings = bindings
+            .iter()
+            .map(|binding| match binding {
+                LispVal::List(binding) => match binding.as_slice() {
+                    [formals, expr] => {
+                        Ok((Params::from_formals(formals, false)?, self.eval(expr)?))
+                    }
+                    _ => Err(format!(
+                        "let-values: bad binding {}",
+                        LispVal::List(binding.clone())
+                    )),
+                },
+                _ => Err(format!("let-values: bad binding {}", binding)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        self.env = self.env.extend();
+        for (params, value) in bindings {
+            self.bind_values("let-values", &params, value)?;
+        }
+        self.eval_sequence(body)
+    }
+
+    /// `(receive formals expr body ...)` from SRFI 8.
+    fn eval_receive<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let [formals, expr, body @ ..] = v else {
+            return Err("receive expects formals, an expression and a body".to_string());
+        };
+        let params = Params::from_formals(formals, false)?;
+        let value = self.eval(expr)?;
+        self.env = self.env.extend();
+        self.bind_values("receive", &params, value)?;
+        self.eval_sequence(body)
+    }
+
+    /// `(define-values formals expr)`
+    fn define_values(&mut self, v: &[LispVal]) -> Result<LispVal, String> {
+        let [formals, expr] = v else {
+            return Err("define-values expects formals and an expression".to_string());
+        };
+        let params = Params::from_formals(formals, false)?;
+        let value = self.eval(expr)?;
+        self.bind_values("define-values", &params, value)?;
+        Ok(LispVal::Unspecified)
+    }
+
+    fn bind_values(
+        &mut self,
+        operator: &str,
+        params: &Params,
+        value: LispVal,
+    ) -> Result<(), String> {
+        let values = values::into_vec(value);
+        params.arity().check(operator, values.len())?;
+        self.bind_params(operator, params, &values)
+    }
+
+    /// Evaluates a sequence of expressions, returning the value of the last one.
+    pub(super) fn eval_body(&mut self, body: &[LispVal]) -> Result<LispVal, String> {
+        let (last, init) = body.split_last().ok_or("empty body")?;
+        for v in init {
+            self.eval(v)?;
+        }
+        self.eval(last)
+    }
+
+    /// Like `eval_body`, but leaves the last expression in tail position.
+    fn eval_sequence<'a>(&mut self, body: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let (last, init) = body.split_last().ok_or("empty body")?;
+        for v in init {
+            self.eval(v)?;
         }
+        Ok(Tail::Eval(last))
+    }
+
+    /// `(delay expr)` and `(delay-force expr)`
+    fn eval_delay(
+        &mut self,
+        v: &[LispVal],
+        promise: fn(LispVal, Environment) -> Promise,
+    ) -> Result<LispVal, String> {
+        let [expr] = v else {
+            return Err("delay expects 1 expression".to_string());
+        };
+        Ok(LispVal::Promise(promise(expr.clone(), self.env.clone())))
+    }
+
+    /// `(stream-cons a b)` delays both expressions, the SICP `(cons-stream a b)`
+    /// evaluates `a` right away.
+    fn eval_stream_cons(&mut self, v: &[LispVal], eager_car: bool) -> Result<LispVal, String> {
+        let [car, cdr] = v else {
+            return Err("stream-cons expects 2 expressions".to_string());
+        };
+        let car = match eager_car {
+            true => Promise::done(self.eval(car)?),
+            false => Promise::delay(car.clone(), self.env.clone()),
+        };
+        Ok(LispVal::StreamPair(StreamPair {
+            car,
+            cdr: Promise::delay(cdr.clone(), self.env.clone()),
+        }))
     }
 
-    fn eval_if_only(&mut self, cond: LispVal, to_vec: Vec<LispVal>) -> Result<LispVal, String> {
-        match cond {
-            LispVal::Bool(false) => Err("Unspecified return value".to_string()),
-            _ => self.eval(&to_vec[1]),
+    /// Evaluates `v` in `env` instead of the current environment.
+    pub(super) fn eval_in(&mut self, v: &LispVal, env: Environment) -> Result<LispVal, String> {
+        let caller_env = std::mem::replace(&mut self.env, env);
+        let result = self.eval(v);
+        self.env = caller_env;
+        result
+    }
+
+    fn eval_if<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        match v {
+            [cond, then] => match self.eval(cond)? {
+                LispVal::Bool(false) => Err("Unspecified return value".to_string()),
+                _ => Ok(Tail::Eval(then)),
+            },
+            [cond, then, otherwise] => match self.eval(cond)? {
+                LispVal::Bool(false) => Ok(Tail::Eval(otherwise)),
+                _ => Ok(Tail::Eval(then)),
+            },
+            [] => Err("if must have a condition".to_string()),
+            _ => Err("if must have 2 or 3 arguments".to_string()),
         }
     }
 
-    fn eval_if_else(&mut self, cond: LispVal, to_vec: Vec<LispVal>) -> Result<LispVal, String> {
-        match cond {
-            LispVal::Bool(false) => self.eval(&to_vec[2]),
-            _ => self.eval(&to_vec[1]),
+    /// `(and test ...)` stops at the first false test, the last one is in tail position.
+    fn eval_and<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let Some((last, init)) = v.split_last() else {
+            return Ok(Tail::Value(LispVal::Bool(true)));
+        };
+        for test in init {
+            let value = self.eval(test)?;
+            if value == LispVal::Bool(false) {
+                return Ok(Tail::Value(value));
+            }
         }
+        Ok(Tail::Eval(last))
     }
 
-    fn eval_cond(&mut self, operands: Vec<LispVal>) -> Result<LispVal, String> {
-        for branch in operands {
-            if let Some(result) = self.eval_cond_branch(branch) {
-                return Ok(result);
+    /// `(or test ...)` returns the first true value, the last test is in tail position.
+    fn eval_or<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let Some((last, init)) = v.split_last() else {
+            return Ok(Tail::Value(LispVal::Bool(false)));
+        };
+        for test in init {
+            let value = self.eval(test)?;
+            if value != LispVal::Bool(false) {
+                return Ok(Tail::Value(value));
             }
         }
-        return Err("No matching condition".to_string());
+        Ok(Tail::Eval(last))
     }
 
-    fn eval_cond_branch(&mut self, branch: LispVal) -> Option<LispVal> {
-        let LispVal::List(v) = branch else {
-            return None;
+    /// `(when test expr ...)`, or `(unless test expr ...)` when `expected` is false.
+    fn eval_when<'a>(&mut self, v: &'a [LispVal], expected: bool) -> Result<Tail<'a>, String> {
+        let who = if expected { "when" } else { "unless" };
+        let [test, body @ ..] = v else {
+            return Err(format!("{} expects a test", who));
         };
-        let (cond, body) = v.split_first()?;
-        match cond {
-            LispVal::Atom(s) if s.eq("else") => self.eval(&body[0]).ok(),
-            l @ LispVal::List(_) => match self.eval(l) {
-                Ok(LispVal::Bool(true)) => self.eval(&body[0]).ok(),
-                _ => None,
-            },
-            _ => None,
+        if body.is_empty() {
+            return Err(format!("{} expects at least one expression", who));
+        }
+        match (self.eval(test)? != LispVal::Bool(false)) == expected {
+            true => self.eval_sequence(body),
+            false => Ok(Tail::Value(LispVal::Unspecified)),
         }
     }
 
-    fn apply(&mut self, operator: &str, operands: &[LispVal]) -> Result<LispVal, String> {
-        let evaluated_operands = operands
+    /// `(case key ((datum ...) expr ...) ... (else expr ...))`. A clause may also be
+    /// `((datum ...) => receiver)`, which calls the receiver with the key.
+    fn eval_case<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let (key, clauses) = v.split_first().ok_or("case expects a key")?;
+        let key = self.eval(key)?;
+        for clause in clauses {
+            let LispVal::List(clause) = clause else {
+                return Err(format!("case: bad clause {}", clause));
+            };
+            let matches = match clause.first() {
+                Some(LispVal::Atom(s)) if s == "else" => true,
+                Some(LispVal::List(data)) => {
+                    data.iter().any(|d| equivalence::eqv(&d.to_datum(), &key))
+                }
+                _ => {
+                    return Err(format!(
+                        "case: bad clause {}",
+                        LispVal::List(clause.clone())
+                    ))
+                }
+            };
+            if !matches {
+                continue;
+            }
+            return match &clause[1..] {
+                [LispVal::Atom(arrow), receiver] if arrow == "=>" => {
+                    let receiver = self.eval(receiver)?;
+                    self.tail_apply("case", &receiver, &[key])
+                }
+                body => self.eval_sequence(body),
+            };
+        }
+        Ok(Tail::Value(LispVal::Unspecified))
+    }
+
+    /// `(do ((var init [step]) ...) (test expr ...) command ...)`. Each iteration binds
+    /// the variables in a fresh frame, so closures created in the loop keep their values.
+    fn eval_do<'a>(&mut self, v: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        let [LispVal::List(specs), LispVal::List(exit), commands @ ..] = v else {
+            return Err("do expects variable specs and an exit clause".to_string());
+        };
+        let (test, result) = exit.split_first().ok_or("do expects an exit test")?;
+        let specs = specs
             .iter()
-            .map(|v| self.eval(v))
+            .map(|spec| match spec {
+                LispVal::List(spec) => match spec.as_slice() {
+                    [LispVal::Atom(var), init] => Ok((*var, init, None)),
+                    [LispVal::Atom(var), init, step] => Ok((*var, init, Some(step))),
+                    _ => Err(format!(
+                        "do: bad variable spec {}",
+                        LispVal::List(spec.clone())
+                    )),
+                },
+                _ => Err(format!("do: bad variable spec {}", spec)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let outer = self.env.clone();
+        let mut values = specs
+            .iter()
+            .map(|(_, init, _)| self.eval(init))
             .collect::<Result<Vec<LispVal>, String>>()?;
-        match Self::lookup_primitives(operator) {
-            Ok(f) => f(evaluated_operands),
-            Err(_) => self.apply_func(operator, evaluated_operands.as_slice()),
+        loop {
+            self.env = outer.extend();
+            for ((var, ..), value) in specs.iter().zip(values) {
+                self.env.new_binding(*var, value);
+            }
+            if self.eval(test)? != LispVal::Bool(false) {
+                return match result.is_empty() {
+                    true => Ok(Tail::Value(LispVal::Unspecified)),
+                    false => self.eval_sequence(result),
+                };
+            }
+            for command in commands {
+                self.eval(command)?;
+            }
+            values = specs
+                .iter()
+                .map(|(var, _, step)| match step {
+                    Some(step) => self.eval(step),
+                    None => self.eval_atom(*var),
+                })
+                .collect::<Result<Vec<LispVal>, String>>()?;
+        }
+    }
+
+    /// `(cond (test expr ...) ... (else expr ...))`. Any value but `#f` selects a clause;
+    /// a clause without expressions yields the value of its test, and
+    /// `(test => receiver)` calls the receiver with it.
+    fn eval_cond<'a>(&mut self, clauses: &'a [LispVal]) -> Result<Tail<'a>, String> {
+        for (i, clause) in clauses.iter().enumerate() {
+            let LispVal::List(clause) = clause else {
+                return Err(format!("cond: bad clause {}", clause));
+            };
+            let (test, body) = clause
+                .split_first()
+                .ok_or("cond: clauses must not be empty")?;
+            let value = match test {
+                LispVal::Atom(s) if s == "else" => {
+                    if i + 1 != clauses.len() {
+                        return Err("cond: else must be the last clause".to_string());
+                    }
+                    if body.is_empty() {
+                        return Err("cond: else clause must have expressions".to_string());
+                    }
+                    return self.eval_sequence(body);
+                }
+                test => self.eval(test)?,
+            };
+            if value == LispVal::Bool(false) {
+                continue;
+            }
+            return match body {
+                [] => Ok(Tail::Value(value)),
+                [LispVal::Atom(arrow), receiver] if arrow == "=>" => {
+                    let receiver = self.eval(receiver)?;
+                    self.tail_apply("cond", &receiver, &[value])
+                }
+                [LispVal::Atom(arrow), ..] if arrow == "=>" => {
+                    Err("cond: => must be followed by exactly one receiver".to_string())
+                }
+                body => self.eval_sequence(body),
+            };
+        }
+        Ok(Tail::Value(LispVal::Unspecified))
+    }
+
+    /// Calls any procedure value: closures, primitives and record procedures alike.
+    /// `operator` is the name the procedure was called by, used in error messages.
+    pub(super) fn apply_procedure(
+        &mut self,
+        operator: &str,
+        procedure: &LispVal,
+        operands: &[LispVal],
+    ) -> Result<LispVal, String> {
+        match procedure {
+            LispVal::Function(f) => self.apply_closure(operator, f, operands),
+            LispVal::CaseLambda(clauses) => {
+                let f = Self::select_clause(operator, clauses, operands.len())?;
+                self.apply_closure(operator, f, operands)
+            }
+            LispVal::Primitive { name, arity } => {
+                arity.check(name.name(), operands.len())?;
+                self.apply_primitive(*name, operands.to_vec())
+            }
+            LispVal::RecordProcedure(p) => p.apply(operands),
+            LispVal::Parameter(p) => {
+                Arity::exactly(0).check(operator, operands.len())?;
+                Ok(p.value())
+            }
+            _ => Err(format!("{} is not applicable to {:?}", procedure, operands)),
         }
     }
 
-    fn apply_func(&mut self, operator: &str, operands: &[LispVal]) -> Result<LispVal, String> {
-        let LispVal::Function { params, body } = self
-            .env
-            .lookup(operator)
-            .cloned()
-            .ok_or(format!("unknown function {}", operator))?
-        else {
-            return Err(format!("{} is not a function", operator));
+    /// Like `apply_procedure`, but a closure body is entered in tail position instead of
+    /// being evaluated recursively.
+    fn tail_apply(
+        &mut self,
+        operator: &str,
+        procedure: &LispVal,
+        operands: &[LispVal],
+    ) -> Result<Tail<'static>, String> {
+        let f = match procedure {
+            LispVal::Function(f) => f,
+            LispVal::CaseLambda(clauses) => Self::select_clause(operator, clauses, operands.len())?,
+            _ => {
+                return self
+                    .apply_procedure(operator, procedure, operands)
+                    .map(Tail::Value)
+            }
         };
-        if params.len() != operands.len() {
-            return Err(format!(
-                "function {} expects {} arguments, but got {}",
-                operator,
-                params.len(),
-                operands.len()
-            ));
+        f.arity().check(operator, operands.len())?;
+        self.env = f.env.extend();
+        self.bind_params(operator, &f.params, operands)?;
+        Ok(Tail::Enter(f.body.clone()))
+    }
+
+    /// The first `case-lambda` clause accepting `n` arguments.
+    pub(super) fn select_clause<'a>(
+        operator: &str,
+        clauses: &'a [Closure],
+        n: usize,
+    ) -> Result<&'a Closure, String> {
+        clauses.iter().find(|f| f.arity().accepts(n)).ok_or(format!(
+            "{} expects {} arguments, but got {}",
+            operator,
+            clauses
+                .iter()
+                .map(|f| f.arity().to_string())
+                .collect::<Vec<String>>()
+                .join(" or "),
+            n
+        ))
+    }
+
+    fn apply_closure(
+        &mut self,
+        operator: &str,
+        f: &Closure,
+        operands: &[LispVal],
+    ) -> Result<LispVal, String> {
+        f.arity().check(operator, operands.len())?;
+        let caller_env = std::mem::replace(&mut self.env, f.env.extend());
+        let result = self
+            .bind_params(operator, &f.params, operands)
+            .and_then(|_| match self.backend {
+                Backend::TreeWalker => self.eval_body(&f.body),
+                Backend::Analyzer => self.exec(&f.analyzed()),
+                Backend::Vm => self.run(f.code()),
+            });
+        self.env = caller_env;
+        result
+    }
+
+    /// Binds the arguments in the current environment. Defaults are evaluated there too,
+    /// so they can refer to the parameters before them.
+    pub(super) fn bind_params(
+        &mut self,
+        operator: &str,
+        params: &Params,
+        operands: &[LispVal],
+    ) -> Result<(), String> {
+        let (required, mut rest) = operands.split_at(params.required.len());
+        for (param, operand) in params.required.iter().zip(required) {
+            self.env.new_binding(*param, operand.clone());
         }
-        self.env.new_frame();
-        for (param, operand) in params.iter().zip(operands.iter()) {
-            self.env.new_binding(param.clone(), operand.clone());
+        for (param, default) in &params.optional {
+            let value = match rest.split_first() {
+                // once named parameters are expected, a keyword ends the positional ones
+                Some((LispVal::Keyword(_), _)) if !params.named.is_empty() => self.eval(default)?,
+                Some((operand, tail)) => {
+                    rest = tail;
+                    operand.clone()
+                }
+                None => self.eval(default)?,
+            };
+            self.env.new_binding(*param, value);
         }
-        let result = self.eval(&body[0]);
-        self.env.pop_frame();
+        if !params.named.is_empty() {
+            let mut supplied = Vec::new();
+            while let [LispVal::Keyword(key), value, tail @ ..] = rest {
+                if !params.named.iter().any(|(k, ..)| k == key) {
+                    match params.rest {
+                        Some(_) => break,
+                        None => return Err(format!("{}: unknown keyword {}:", operator, key)),
+                    }
+                }
+                supplied.push((*key, value.clone()));
+                rest = tail;
+            }
+            for (key, param, default) in &params.named {
+                let value = match supplied.iter().find(|(k, _)| k == key) {
+                    Some((_, value)) => value.clone(),
+                    None => self.eval(default)?,
+                };
+                self.env.new_binding(*param, value);
+            }
+        }
+        match params.rest {
+            Some(param) => self.env.new_binding(param, pair::list(rest.to_vec())),
+            None if !rest.is_empty() => {
+                return Err(format!(
+                    "{}: unexpected arguments {}",
+                    operator,
+                    LispVal::List(rest.to_vec())
+                ))
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub(super) fn apply_primitive(
+        &mut self,
+        name: Symbol,
+        operands: Vec<LispVal>,
+    ) -> Result<LispVal, String> {
+        let f = match self.primitives.get(&name) {
+            Some(f) => f.clone(),
+            None => {
+                let f = match Self::lookup_primitives(name.name()) {
+                    Ok(f) => PrimitiveFn::Plain(Rc::from(f)),
+                    Err(_) => match Self::lookup_higher_order_primitives(name.name()) {
+                        Some(f) => PrimitiveFn::HigherOrder(f),
+                        None => return Err(format!("unknown primitive {}", name)),
+                    },
+                };
+                self.primitives.insert(name, f.clone());
+                f
+            }
+        };
+        match f {
+            PrimitiveFn::Plain(f) => f(operands),
+            PrimitiveFn::HigherOrder(f) => f(self, operands),
+        }
+    }
+
+    /// `(apply f arg ... list)`
+    fn apply_lisp(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        let (Some(rest), [f, args @ .., _]) = (v.last().and_then(pair::items), v.as_slice()) else {
+            return Err("apply expects a procedure and a list of arguments".to_string());
+        };
+        self.apply_procedure("apply", f, &[args, &rest].concat())
+    }
+
+    /// `(eval expr [environment])`, in the interaction environment by default.
+    fn eval_lisp(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        let (expr, env) = match v.as_slice() {
+            [expr] => (expr, self.global.clone()),
+            [expr, LispVal::Environment(env)] => (expr, env.clone()),
+            [_, env] => return Err(format!("eval: {} is not an environment", env)),
+            _ => return Err("eval expects an expression and an environment".to_string()),
+        };
+        let caller_env = std::mem::replace(&mut self.env, env);
+        let result = self.eval_form(&expr.to_code());
+        self.env = caller_env;
         result
     }
 
+    /// The top-level environment, where definitions made by `eval` persist.
+    fn interaction_environment(&mut self, _: Vec<LispVal>) -> Result<LispVal, String> {
+        Ok(LispVal::Environment(self.global.clone()))
+    }
+
+    /// A fresh environment with every primitive bound, for R5RS and R7RS alike.
+    fn scheme_report_environment(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        match v.as_slice() {
+            [LispVal::Integer(5 | 7)] => Ok(LispVal::Environment(self.builtins.extend())),
+            [version] => Err(format!(
+                "scheme-report-environment: unsupported version {}",
+                version
+            )),
+            _ => Err("scheme-report-environment expects a version".to_string()),
+        }
+    }
+
+    /// A fresh environment with nothing bound but the syntax, which is available everywhere.
+    fn null_environment(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        match v.as_slice() {
+            [LispVal::Integer(5 | 7)] => Ok(LispVal::Environment(Environment::new())),
+            [version] => Err(format!("null-environment: unsupported version {}", version)),
+            _ => Err("null-environment expects a version".to_string()),
+        }
+    }
+
+    fn not(v: Vec<LispVal>) -> Result<LispVal, String> {
+        let [x] = v.as_slice() else {
+            return Err("not expects 1 argument".to_string());
+        };
+        Ok(LispVal::Bool(*x == LispVal::Bool(false)))
+    }
+
+    fn is_procedure(v: Vec<LispVal>) -> Result<LispVal, String> {
+        let [x] = v.as_slice() else {
+            return Err("procedure? expects 1 argument".to_string());
+        };
+        Ok(LispVal::Bool(matches!(
+            x,
+            LispVal::Function(_)
+                | LispVal::CaseLambda(_)
+                | LispVal::Primitive { .. }
+                | LispVal::RecordProcedure(_)
+                | LispVal::Parameter(_)
+        )))
+    }
+
     fn foldable_primitive(
         f: impl Fn(LispVal, &LispVal) -> Result<LispVal, String>,
         default_val: LispVal,
@@ -180,18 +1160,19 @@ impl Interpreter {
         move |v| v.iter().try_fold(default_val.clone(), &f)
     }
 
+    /// Lifts a checked integer operation, whose overflow is an error.
     fn lift_int_funcs(
-        f: fn(i64, i64) -> i64,
+        f: fn(i64, i64) -> Option<i64>,
     ) -> impl Fn(LispVal, &LispVal) -> Result<LispVal, String> {
         move |acc, lisp_int| {
             lisp_int
                 .to_integer()
-                .ok_or(format!("Cannot add {:?}", lisp_int))
+                .ok_or_else(|| format!("Cannot add {:?}", lisp_int))
                 .and_then(|i| {
                     let Some(ac_int) = acc.to_integer() else {
                         return Err(format!("Cannot add {:?}", acc));
                     };
-                    Ok(f(ac_int, i))
+                    f(ac_int, i).ok_or_else(|| "integer overflow".to_string())
                 })
                 .map(LispVal::Integer)
         }
@@ -199,29 +1180,29 @@ impl Interpreter {
 
     /// special forms
 
-    fn add_int(acc: i64, i: i64) -> i64 {
-        acc + i
+    fn add_int(acc: i64, i: i64) -> Option<i64> {
+        acc.checked_add(i)
     }
 
-    fn mul_int(acc: i64, i: i64) -> i64 {
-        acc * i
+    fn mul_int(acc: i64, i: i64) -> Option<i64> {
+        acc.checked_mul(i)
     }
 
-    fn sub_int(acc: i64, i: i64) -> i64 {
-        acc - i
+    fn sub_int(acc: i64, i: i64) -> Option<i64> {
+        acc.checked_sub(i)
     }
 
     fn eq_lisp(v: Vec<LispVal>) -> Result<LispVal, String> {
         let mut iter = v.iter();
         let first_int = iter
             .next()
-            .ok_or("Cannot compare empty list".to_string())?
+            .ok_or_else(|| "Cannot compare empty list".to_string())?
             .to_integer()
-            .ok_or("Cannot compare non-integer".to_string())?;
+            .ok_or_else(|| "Cannot compare non-integer".to_string())?;
         for i in iter {
             let i = i
                 .to_integer()
-                .ok_or("Cannot compare non-integer".to_string())?;
+                .ok_or_else(|| "Cannot compare non-integer".to_string())?;
             if i != first_int {
                 return Ok(LispVal::Bool(false));
             }
@@ -234,14 +1215,14 @@ impl Interpreter {
         let mut iter = v.iter();
         let first_int = iter
             .next()
-            .ok_or("Cannot compare empty list".to_string())?
+            .ok_or_else(|| "Cannot compare empty list".to_string())?
             .to_integer()
-            .ok_or("Cannot compare non-integer".to_string())?;
+            .ok_or_else(|| "Cannot compare non-integer".to_string())?;
         let second_int = iter
             .next()
-            .ok_or("Cannot compare empty list".to_string())?
+            .ok_or_else(|| "Cannot compare empty list".to_string())?
             .to_integer()
-            .ok_or("Cannot compare non-integer".to_string())?;
+            .ok_or_else(|| "Cannot compare non-integer".to_string())?;
         Ok(LispVal::Bool(first_int > second_int))
     }
 
@@ -249,14 +1230,14 @@ impl Interpreter {
         let mut iter = v.iter();
         let first_int = iter
             .next()
-            .ok_or("Cannot compare empty list".to_string())?
+            .ok_or_else(|| "Cannot compare empty list".to_string())?
             .to_integer()
-            .ok_or("Cannot compare non-integer".to_string())?;
+            .ok_or_else(|| "Cannot compare non-integer".to_string())?;
         let second_int = iter
             .next()
-            .ok_or("Cannot compare empty list".to_string())?
+            .ok_or_else(|| "Cannot compare empty list".to_string())?
             .to_integer()
-            .ok_or("Cannot compare non-integer".to_string())?;
+            .ok_or_else(|| "Cannot compare non-integer".to_string())?;
         Ok(LispVal::Bool(first_int < second_int))
     }
 
@@ -271,77 +1252,154 @@ impl Interpreter {
     }
 
     fn car_list(v: Vec<LispVal>) -> Result<LispVal, String> {
-        v.first()
-            .ok_or("Cannot take car of empty list".to_string())
-            .and_then(|v| {
-                let LispVal::List(v) = v else {
-                    return Err("Cannot take car of non-list".to_string());
-                };
-                Ok(v)
-            })
-            .and_then(|v| {
-                v.first()
-                    .ok_or("Cannot take car of empty list".to_string())
-                    .cloned()
-            })
+        match v.first() {
+            Some(LispVal::Pair(p)) => Ok(p.car()),
+            Some(LispVal::List(l)) => l
+                .first()
+                .cloned()
+                .ok_or("Cannot take car of empty list".to_string()),
+            Some(_) => Err("Cannot take car of non-list".to_string()),
+            None => Err("Cannot take car of empty list".to_string()),
+        }
     }
 
     fn cdr_list(v: Vec<LispVal>) -> Result<LispVal, String> {
-        v.first()
-            .ok_or("Cannot take cdr of empty list".to_string())
-            .and_then(|v| {
-                let LispVal::List(v) = v else {
-                    return Err("Cannot take cdr of non-list".to_string());
-                };
-                Ok(v)
-            })
-            .map(|v| LispVal::List(v[1..].to_vec()))
+        match v.first() {
+            Some(LispVal::Pair(p)) => Ok(p.cdr()),
+            Some(LispVal::List(l)) if !l.is_empty() => Ok(LispVal::List(l[1..].to_vec())),
+            Some(LispVal::List(_)) | None => Err("Cannot take cdr of empty list".to_string()),
+            Some(_) => Err("Cannot take cdr of non-list".to_string()),
+        }
     }
 
+    /// The cdr can be anything, `(cons 1 2)` is the pair `(1 . 2)`.
     fn cons_list(v: Vec<LispVal>) -> Result<LispVal, String> {
-        v.split_first()
-            .ok_or("Cannot take cons of empty list".to_string())
-            .and_then(|(v, v2)| {
-                let LispVal::List(mut v2) = v2[0].clone() else {
-                    return Err("Cannot take cons of non-list".to_string());
-                };
-                v2.insert(0, v.clone());
-                Ok(LispVal::List(v2))
+        let [car, cdr] = v.as_slice() else {
+            return Err("cons expects 2 arguments".to_string());
+        };
+        Ok(pair::cons(car.clone(), cdr.clone()))
+    }
+
+    fn is_symbol(v: Vec<LispVal>) -> Result<LispVal, String> {
+        let [x] = v.as_slice() else {
+            return Err("symbol? expects 1 argument".to_string());
+        };
+        Ok(LispVal::Bool(x.as_symbol().is_some()))
+    }
+
+    fn symbol_to_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+        let [LispVal::Symbol(s)] = v.as_slice() else {
+            return Err("symbol->string expects a symbol".to_string());
+        };
+        Ok(LispVal::String(LispString::immutable(s.name())))
+    }
+
+    fn symbol_eq(v: Vec<LispVal>) -> Result<LispVal, String> {
+        let symbols = v
+            .iter()
+            .map(|s| {
+                s.as_symbol()
+                    .ok_or(format!("symbol=? expects symbols, got {}", s))
             })
+            .collect::<Result<Vec<Symbol>, String>>()?;
+        if symbols.len() < 2 {
+            return Err("symbol=? expects at least 2 arguments".to_string());
+        }
+        Ok(LispVal::Bool(symbols.windows(2).all(|w| w[0] == w[1])))
     }
 
-    fn define_value(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
-        match &v[0] {
-            LispVal::Atom(s) => {
-                let val = self.eval(&v[1])?;
-                self.env.new_binding(s.clone(), val.clone());
+    fn define_value(&mut self, v: &[LispVal]) -> Result<LispVal, String> {
+        match v {
+            [LispVal::Atom(s), value] => {
+                let val = self.eval(value)?;
+                self.env.new_binding(*s, val.clone());
                 Ok(val)
             }
-            LispVal::List(_) => self.define_function(v),
+            [LispVal::Atom(_), ..] => Err("define expects a variable and a value".to_string()),
+            [LispVal::List(_), ..] => self.define_function(v, false),
             _ => Err("unknown define".to_string()),
         }
     }
 
-    fn define_function(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
-        let LispVal::List(signature) = &v[0] else {
-            return Err("define function must have signatures".to_string());
+    /// `(define-record-type <name> (constructor field ...) predicate (field accessor [modifier]) ...)`
+    fn define_record_type(&mut self, v: &[LispVal]) -> Result<LispVal, String> {
+        let [LispVal::Atom(name), constructor, LispVal::Atom(predicate), fields @ ..] = v else {
+            return Err(
+                "define-record-type expects a name, a constructor and a predicate".to_string(),
+            );
         };
-        let LispVal::Atom(name) = &signature[0] else {
-            return Err("define function must have a name".to_string());
+        let atom = |v: &LispVal| match v {
+            LispVal::Atom(s) => Ok(*s),
+            _ => Err(format!("define-record-type: {} is not an identifier", v)),
         };
-        let params = signature[1..]
+        let fields = fields
             .iter()
-            .map(|v| match v {
-                LispVal::Atom(s) => Ok(s.clone()),
-                _ => Err("define function signature must be atoms".to_string()),
+            .map(|spec| match spec {
+                LispVal::List(spec) if (2..=3).contains(&spec.len()) => spec
+                    .iter()
+                    .map(atom)
+                    .collect::<Result<Vec<Symbol>, String>>(),
+                _ => Err(format!("define-record-type: bad field spec {}", spec)),
             })
-            .collect::<Result<Vec<String>, String>>();
-        let body = v[1..].to_vec();
-        let val = LispVal::Function {
-            params: params?,
-            body,
+            .collect::<Result<Vec<Vec<Symbol>>, String>>()?;
+        let rtd = RecordType::new(*name, fields.iter().map(|spec| spec[0]).collect());
+        let procedure = |name: Symbol, kind: RecordProcedureKind| {
+            (
+                name,
+                LispVal::RecordProcedure(RecordProcedure {
+                    name,
+                    rtd: rtd.clone(),
+                    kind,
+                }),
+            )
+        };
+
+        let mut procedures = vec![procedure(*predicate, RecordProcedureKind::Predicate)];
+        if let LispVal::List(constructor) = constructor {
+            let (name, args) = constructor
+                .split_first()
+                .ok_or("define-record-type: empty constructor spec")?;
+            let indices = args
+                .iter()
+                .map(|arg| {
+                    rtd.field_index(atom(arg)?)
+                        .ok_or(format!("define-record-type: unknown field {}", arg))
+                })
+                .collect::<Result<Vec<usize>, String>>()?;
+            procedures.push(procedure(
+                atom(name)?,
+                RecordProcedureKind::Constructor(indices),
+            ));
+        } else {
+            let all_fields = (0..fields.len()).collect();
+            procedures.push(procedure(
+                atom(constructor)?,
+                RecordProcedureKind::Constructor(all_fields),
+            ));
+        }
+        for (i, spec) in fields.iter().enumerate() {
+            procedures.push(procedure(spec[1], RecordProcedureKind::Accessor(i)));
+            if let Some(modifier) = spec.get(2) {
+                procedures.push(procedure(*modifier, RecordProcedureKind::Modifier(i)));
+            }
+        }
+        for (name, procedure) in procedures {
+            self.env.new_binding(name, procedure);
+        }
+        Ok(LispVal::Unspecified)
+    }
+
+    /// `(define (name . formals) body ...)`, or `define*` when `extended`.
+    fn define_function(&mut self, v: &[LispVal], extended: bool) -> Result<LispVal, String> {
+        let Some(LispVal::List(signature)) = v.first() else {
+            return Err("define function must have signatures".to_string());
+        };
+        let Some((LispVal::Atom(name), params)) = signature.split_first() else {
+            return Err("define function must have a name".to_string());
         };
-        self.env.new_binding(name.clone(), val.clone());
+        let closure = self.make_closure(&LispVal::List(params.to_vec()), &v[1..], extended)?;
+        let val = LispVal::Function(Gc::new(closure));
+        self.env.new_binding(*name, val.clone());
         Ok(val)
     }
 
@@ -361,17 +1419,228 @@ impl Interpreter {
             "car" => Ok(Box::new(Self::car_list)),
             "cdr" => Ok(Box::new(Self::cdr_list)),
             "cons" => Ok(Box::new(Self::cons_list)),
-            "eq?" | "=" => Ok(Box::new(Self::eq_lisp)),
+            "set-car!" => Ok(Box::new(lists::set_pair_field("set-car!", Pair::set_car))),
+            "set-cdr!" => Ok(Box::new(lists::set_pair_field("set-cdr!", Pair::set_cdr))),
+            "null?" => Ok(Box::new(lists::is_null)),
+            "pair?" => Ok(Box::new(lists::is_pair)),
+            "list?" => Ok(Box::new(lists::is_list)),
+            "list" => Ok(Box::new(lists::list)),
+            "length" => Ok(Box::new(lists::length)),
+            "append" => Ok(Box::new(lists::append)),
+            "reverse" => Ok(Box::new(lists::reverse)),
+            "list-tail" => Ok(Box::new(lists::list_tail)),
+            "list-ref" => Ok(Box::new(lists::list_ref)),
+            "list-copy" => Ok(Box::new(lists::list_copy)),
+            "last-pair" => Ok(Box::new(lists::last_pair)),
+            "iota" => Ok(Box::new(lists::iota)),
+            "memq" => Ok(Box::new(lists::mem("memq"))),
+            "memv" => Ok(Box::new(lists::mem("memv"))),
+            "assq" => Ok(Box::new(lists::ass("assq"))),
+            "assv" => Ok(Box::new(lists::ass("assv"))),
+            "=" => Ok(Box::new(Self::eq_lisp)),
+            "eq?" => Ok(Box::new(equivalence::predicate("eq?", equivalence::eq))),
+            "eqv?" => Ok(Box::new(equivalence::predicate("eqv?", equivalence::eqv))),
+            "equal?" => Ok(Box::new(equivalence::predicate(
+                "equal?",
+                equivalence::equal,
+            ))),
             ">" => Ok(Box::new(Self::gt_lisp)),
             "<" => Ok(Box::new(Self::lt_lisp)),
+            "string?" => Ok(Box::new(strings::is_string)),
+            "make-string" => Ok(Box::new(strings::make_string)),
+            "string" => Ok(Box::new(strings::string)),
+            "string-length" => Ok(Box::new(strings::string_length)),
+            "string-ref" => Ok(Box::new(strings::string_ref)),
+            "string-set!" => Ok(Box::new(strings::string_set)),
+            "substring" => Ok(Box::new(strings::substring)),
+            "string-append" => Ok(Box::new(strings::string_append)),
+            "string-copy" => Ok(Box::new(strings::string_copy)),
+            "string->list" => Ok(Box::new(strings::string_to_list)),
+            "list->string" => Ok(Box::new(strings::list_to_string)),
+            "string->symbol" => Ok(Box::new(strings::string_to_symbol)),
+            "symbol->string" => Ok(Box::new(Self::symbol_to_string)),
+            "symbol?" => Ok(Box::new(Self::is_symbol)),
+            "symbol=?" => Ok(Box::new(Self::symbol_eq)),
+            "procedure?" => Ok(Box::new(Self::is_procedure)),
+            "make-promise" => Ok(Box::new(promise::make_promise)),
+            "promise?" => Ok(Box::new(promise::is_promise)),
+            "stream-null?" => Ok(Box::new(stream::is_stream_null)),
+            "stream-pair?" => Ok(Box::new(stream::is_stream_pair)),
+            "stream?" => Ok(Box::new(stream::is_stream)),
+            "stream-take" => Ok(Box::new(stream::stream_take)),
+            "list->stream" => Ok(Box::new(stream::list_to_stream)),
+            "open-input-string" => Ok(Box::new(port::open_input_string)),
+            "open-output-string" => Ok(Box::new(port::open_output_string)),
+            "get-output-string" => Ok(Box::new(port::get_output_string)),
+            "open-output-file" => Ok(Box::new(port::open_output_file)),
+            "close-port" | "close-input-port" | "close-output-port" => {
+                Ok(Box::new(port::close_port))
+            }
+            "eof-object" => Ok(Box::new(port::eof_object)),
+            "eof-object?" => Ok(Box::new(port::is_eof_object)),
+            "port?" => Ok(Box::new(port::is_port("port?", |_| true))),
+            "input-port?" => Ok(Box::new(port::is_port("input-port?", Port::is_input))),
+            "output-port?" => Ok(Box::new(port::is_port("output-port?", Port::is_output))),
+            "not" => Ok(Box::new(Self::not)),
+            "values" => Ok(Box::new(values::values)),
+            "make-hash-table" => Ok(Box::new(hash_table::make_hash_table_with)),
+            "make-equal-hash-table" => {
+                Ok(Box::new(hash_table::make_hash_table(Equivalence::Equal)))
+            }
+            "make-eq-hash-table" => Ok(Box::new(hash_table::make_hash_table(Equivalence::Eq))),
+            "make-eqv-hash-table" => Ok(Box::new(hash_table::make_hash_table(Equivalence::Eqv))),
+            "make-string-hash-table" => {
+                Ok(Box::new(hash_table::make_hash_table(Equivalence::String)))
+            }
+            "hash-table?" => Ok(Box::new(hash_table::is_hash_table)),
+            "hash-table-ref/default" => Ok(Box::new(hash_table::hash_table_ref_default)),
+            "hash-table-set!" => Ok(Box::new(hash_table::hash_table_set)),
+            "hash-table-delete!" => Ok(Box::new(hash_table::hash_table_delete)),
+            "hash-table-contains?" | "hash-table-exists?" => {
+                Ok(Box::new(hash_table::hash_table_contains))
+            }
+            "hash-table-count" | "hash-table-size" => Ok(Box::new(hash_table::hash_table_count)),
+            "hash-table-keys" => Ok(Box::new(hash_table::hash_table_keys)),
+            "hash-table-values" => Ok(Box::new(hash_table::hash_table_values)),
+            "hash-table->alist" => Ok(Box::new(hash_table::hash_table_to_alist)),
+            "string-upcase" => Ok(Box::new(strings::string_upcase)),
+            "string-downcase" => Ok(Box::new(strings::string_downcase)),
+            "string=?" => Ok(Box::new(strings::string_compare(
+                "string=?",
+                false,
+                Ordering::is_eq,
+            ))),
+            "string<?" => Ok(Box::new(strings::string_compare(
+                "string<?",
+                false,
+                Ordering::is_lt,
+            ))),
+            "string>?" => Ok(Box::new(strings::string_compare(
+                "string>?",
+                false,
+                Ordering::is_gt,
+            ))),
+            "string<=?" => Ok(Box::new(strings::string_compare(
+                "string<=?",
+                false,
+                Ordering::is_le,
+            ))),
+            "string>=?" => Ok(Box::new(strings::string_compare(
+                "string>=?",
+                false,
+                Ordering::is_ge,
+            ))),
+            "string-ci=?" => Ok(Box::new(strings::string_compare(
+                "string-ci=?",
+                true,
+                Ordering::is_eq,
+            ))),
+            "string-ci<?" => Ok(Box::new(strings::string_compare(
+                "string-ci<?",
+                true,
+                Ordering::is_lt,
+            ))),
+            "string-ci>?" => Ok(Box::new(strings::string_compare(
+                "string-ci>?",
+                true,
+                Ordering::is_gt,
+            ))),
+            "string-ci<=?" => Ok(Box::new(strings::string_compare(
+                "string-ci<=?",
+                true,
+                Ordering::is_le,
+            ))),
+            "string-ci>=?" => Ok(Box::new(strings::string_compare(
+                "string-ci>=?",
+                true,
+                Ordering::is_ge,
+            ))),
             _ => Err(format!("unknown primitive {}", s)),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Primitives that call back into the interpreter, e.g. to apply a procedure argument.
+    fn lookup_higher_order_primitives(s: &str) -> Option<HigherOrderPrimitive> {
+        match s {
+            "string-map" => Some(Self::string_map),
+            "string-for-each" => Some(Self::string_for_each),
+            "force" => Some(promise::force),
+            "call-with-values" => Some(values::call_with_values),
+            "eval" => Some(Self::eval_lisp),
+            "make-parameter" => Some(parameter::make_parameter),
+            "interaction-environment" => Some(Self::interaction_environment),
+            "scheme-report-environment" => Some(Self::scheme_report_environment),
+            "null-environment" => Some(Self::null_environment),
+            "environment" => Some(library::environment),
+            "load" => Some(library::load),
+            "display" => Some(port::display),
+            "write" => Some(port::write),
+            "write-string" => Some(port::write_string),
+            "write-char" => Some(port::write_char),
+            "newline" => Some(port::newline),
+            "flush-output-port" => Some(port::flush_output_port),
+            "read-line" => Some(port::read_line),
+            "read-char" => Some(port::read_char),
+            "peek-char" => Some(port::peek_char),
+            "char-ready?" => Some(port::char_ready),
+            "read" => Some(port::read),
+            "json-read" => Some(port::json_read),
+            "json-write" => Some(port::json_write),
+            "call-with-port" => Some(port::call_with_port),
+            "call-with-output-file" => Some(port::call_with_output_file),
+            "call-with-input-file" => Some(port::call_with_input_file),
+            "open-input-file" => Some(port::open_input_file),
+            "call-with-output-string" => Some(port::call_with_output_string),
+            "with-output-to-string" => Some(port::with_output_to_string),
+            "stream-car" => Some(stream::stream_car),
+            "stream-cdr" => Some(stream::stream_cdr),
+            "stream->list" => Some(stream::stream_to_list),
+            "member" => Some(lists::member),
+            "assoc" => Some(lists::assoc),
+            "delete" => Some(lists::delete),
+            "map" => Some(lists::map),
+            "for-each" => Some(lists::for_each),
+            "filter" => Some(lists::filter),
+            "reduce" => Some(lists::reduce),
+            "fold" => Some(lists::fold),
+            "fold-left" => Some(lists::fold_left),
+            "fold-right" => Some(lists::fold_right),
+            "hash-table-ref" => Some(hash_table::hash_table_ref),
+            "hash-table-update!" => Some(hash_table::hash_table_update),
+            "hash-table-update!/default" => Some(hash_table::hash_table_update_default),
+            "hash-table-walk" => Some(hash_table::hash_table_walk),
+            "apply" => Some(Self::apply_lisp),
+            _ => None,
+        }
+    }
+
+    fn string_map(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        let (f, strings) = v.split_first().ok_or("string-map expects a procedure")?;
+        let chars = strings::string_columns(strings, "string-map")?
+            .iter()
+            .map(|args| match self.apply_procedure("string-map", f, args)? {
+                LispVal::Char(c) => Ok(c),
+                other => Err(format!("string-map: {:?} is not a character", other)),
+            })
+            .collect::<Result<Vec<char>, String>>()?;
+        Ok(LispVal::String(LispString::mutable(chars)))
+    }
+
+    fn string_for_each(&mut self, v: Vec<LispVal>) -> Result<LispVal, String> {
+        let (f, strings) = v
+            .split_first()
+            .ok_or("string-for-each expects a procedure")?;
+        for args in strings::string_columns(strings, "string-for-each")? {
+            self.apply_procedure("string-for-each", f, &args)?;
+        }
+        Ok(LispVal::Unspecified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::limits::LimitExceeded;
+    use super::*;
 
     #[test]
     fn test_eval_integer() {
@@ -411,6 +1680,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_integer_overflow() {
+        for source in [
+            "(+ 9223372036854775807 1)",
+            "(* 4294967296 4294967296)",
+            "(- (- 0 9223372036854775807) 2)",
+            "(define (f n) (if (= n 0) 1 (* n (f (- n 1))))) (f 30)",
+        ] {
+            assert_eq!(
+                interpret_with_all_backends(source),
+                Err("integer overflow".to_string())
+            );
+        }
+        assert_eq!(
+            interpret_with_all_backends("(- (- 0 9223372036854775807) 1)"),
+            Ok(LispVal::Integer(i64::MIN))
+        );
+    }
+
     #[test]
     fn test_eval_car() {
         let interpreter = Interpreter::new().interpret("(car '(1 2 3))");
@@ -424,8 +1712,9 @@ mod tests {
     #[test]
     fn test_eval_cdr() {
         let interpreter = Interpreter::new().interpret("(cdr '(1 2 3))");
-        if let LispVal::List(v) = interpreter.unwrap() {
-            assert_eq!(v, vec![LispVal::Integer(2), LispVal::Integer(3)]);
+        if let LispVal::Pair(p) = interpreter.unwrap() {
+            assert_eq!(p.car(), LispVal::Integer(2));
+            assert_eq!(p.cdr(), LispVal::List(vec![LispVal::Integer(3)]));
         } else {
             unreachable!();
         }
@@ -434,18 +1723,14 @@ mod tests {
     #[test]
     fn test_eval_cons() {
         let interpreter = Interpreter::new().interpret("(cons 1 '(2 3))");
-        if let LispVal::List(v) = interpreter.unwrap() {
-            assert_eq!(
-                v,
-                vec![
-                    LispVal::Integer(1),
-                    LispVal::Integer(2),
-                    LispVal::Integer(3)
-                ]
-            );
-        } else {
-            unreachable!();
-        }
+        assert_eq!(
+            interpreter.unwrap(),
+            LispVal::List(vec![
+                LispVal::Integer(1),
+                LispVal::Integer(2),
+                LispVal::Integer(3)
+            ])
+        );
     }
 
     #[test]
@@ -463,8 +1748,8 @@ mod tests {
         let mut interpreter = Interpreter::new();
         interpreter.interpret("(define x 1)").unwrap();
         assert_eq!(
-            format!("{:?}", interpreter.env),
-            "Environment([EnvFrame([(\"x\", Integer(1))])])"
+            format!("{:?}", interpreter.env.bindings()),
+            "[(\"x\", Integer(1))]"
         )
     }
 
@@ -472,7 +1757,7 @@ mod tests {
     fn test_define_lookup() {
         let mut interpreter = Interpreter::new();
         interpreter.interpret("(define x 1)").unwrap();
-        assert_eq!(interpreter.env.lookup("x"), Some(&LispVal::Integer(1)))
+        assert_eq!(interpreter.env.lookup("x"), Some(LispVal::Integer(1)))
     }
 
     #[test]
@@ -487,8 +1772,8 @@ mod tests {
         let mut interpreter = Interpreter::new();
         interpreter.interpret("(define (add1 x) (+ x 1))").unwrap();
         assert_eq!(
-            format!("{:?}", interpreter.env),
-            "Environment([EnvFrame([(\"add1\", Function { params: [\"x\"], body: [List([Atom(\"+\"), Atom(\"x\"), Integer(1)])] })])])"
+            format!("{:?}", interpreter.env.bindings()),
+            "[(\"add1\", Function(Closure { params: Params { required: [\"x\"], optional: [], named: [], rest: None }, body: [List([Atom(\"+\"), Atom(\"x\"), Integer(1)])] }))]"
         )
     }
 
@@ -606,4 +1891,1804 @@ mod tests {
             Ok(LispVal::Integer(1))
         );
     }
+
+    #[test]
+    fn test_string_procedures() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret(r#"(string-length "hello")"#),
+            Ok(LispVal::Integer(5))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string-ref "hello" 1)"#),
+            Ok(LispVal::Char('e'))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string-append "foo" (substring "xbarx" 1 4))"#),
+            Ok(LispVal::String(LispString::immutable("foobar")))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string-upcase "abc")"#),
+            Ok(LispVal::String(LispString::immutable("ABC")))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string->list "ab")"#),
+            Ok(LispVal::List(vec![LispVal::Char('a'), LispVal::Char('b')]))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(substring "abc" 2 1)"#),
+            Err("substring: range [2, 1) is invalid for string of length 3".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string-copy "abc" 4)"#),
+            Err("string-copy: range [4, 3) is invalid for string of length 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_string_compare() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret(r#"(string=? "a" "a" "a")"#),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string<? "abc" "abd")"#),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret(r#"(string-ci=? "ABC" "abc")"#),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_string_set() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(r#"(define s (make-string 3 #\a))"#)
+            .unwrap();
+        interpreter.interpret(r#"(string-set! s 1 #\b)"#).unwrap();
+        assert_eq!(
+            interpreter.interpret("s"),
+            Ok(LispVal::String(LispString::immutable("aba")))
+        );
+        assert!(interpreter
+            .interpret(r#"(string-set! "literal" 0 #\b)"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_string_map_and_for_each() {
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret("(define (second a b) b)").unwrap();
+        assert_eq!(
+            interpreter.interpret(r#"(string-map second "abc" "xy")"#),
+            Ok(LispVal::String(LispString::immutable("xy")))
+        );
+        interpreter.interpret("(define s (make-string 1))").unwrap();
+        interpreter
+            .interpret("(define (remember c) (string-set! s 0 c))")
+            .unwrap();
+        interpreter
+            .interpret(r#"(string-for-each remember "xyz")"#)
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("s"),
+            Ok(LispVal::String(LispString::immutable("z")))
+        );
+    }
+
+    #[test]
+    fn test_quoted_symbol() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("'a"),
+            Ok(LispVal::Symbol(Symbol::intern("a")))
+        );
+        assert_eq!(
+            interpreter.interpret("(symbol? (car '(a 1)))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(symbol? \"a\")"),
+            Ok(LispVal::Bool(false))
+        );
+        assert_eq!(
+            interpreter
+                .interpret("'(a (b \"c\") #\\d)")
+                .unwrap()
+                .to_string(),
+            "(a (b \"c\") #\\d)"
+        );
+    }
+
+    #[test]
+    fn test_symbol_string_conversion() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(symbol=? 'abc (string->symbol \"abc\") 'abc)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(symbol->string 'abc)"),
+            Ok(LispVal::String(LispString::immutable("abc")))
+        );
+        assert!(interpreter.interpret("(symbol->string \"abc\")").is_err());
+    }
+
+    #[test]
+    fn test_hash_table() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define t (make-hash-table))")
+            .unwrap();
+        interpreter
+            .interpret("(hash-table-set! t '(a 1) 1)")
+            .unwrap();
+        interpreter
+            .interpret("(hash-table-set! t \"b\" 2)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref t '(a 1))"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref/default t \"b\" 0)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref/default t 'c 0)"),
+            Ok(LispVal::Integer(0))
+        );
+        assert!(interpreter.interpret("(hash-table-ref t 'c)").is_err());
+        assert_eq!(
+            interpreter.interpret("(hash-table-count t)"),
+            Ok(LispVal::Integer(2))
+        );
+    }
+
+    #[test]
+    fn test_hash_table_eqv_strings() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define t (make-eqv-hash-table))")
+            .unwrap();
+        interpreter.interpret("(define k (string #\\a))").unwrap();
+        interpreter.interpret("(hash-table-set! t k 1)").unwrap();
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref/default t k 0)"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref/default t (string #\\a) 0)"),
+            Ok(LispVal::Integer(0))
+        );
+        interpreter
+            .interpret("(define s (make-string-hash-table))")
+            .unwrap();
+        assert!(interpreter.interpret("(hash-table-set! s 'a 1)").is_err());
+    }
+
+    #[test]
+    fn test_hash_table_update_and_walk() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define t (make-hash-table))")
+            .unwrap();
+        interpreter.interpret("(define (add1 x) (+ x 1))").unwrap();
+        interpreter
+            .interpret("(hash-table-update!/default t 'a add1 0)")
+            .unwrap();
+        interpreter
+            .interpret("(hash-table-update!/default t 'a add1 0)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(hash-table->alist t)"),
+            Ok(LispVal::List(vec![pair::cons(
+                LispVal::Symbol(Symbol::intern("a")),
+                LispVal::Integer(2)
+            )]))
+        );
+        interpreter
+            .interpret("(define u (make-hash-table))")
+            .unwrap();
+        interpreter
+            .interpret("(define (copy k v) (hash-table-set! u v k))")
+            .unwrap();
+        interpreter.interpret("(hash-table-walk t copy)").unwrap();
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref u 2)"),
+            Ok(LispVal::Symbol(Symbol::intern("a")))
+        );
+    }
+
+    #[test]
+    fn test_define_record_type() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define-record-type <point> (make-point x y) point? (x point-x set-point-x!) (y point-y))",
+            )
+            .unwrap();
+        interpreter
+            .interpret("(define p (make-point 1 2))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(point-x p)"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(point-y p)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(interpreter.interpret("(point? p)"), Ok(LispVal::Bool(true)));
+        assert_eq!(
+            interpreter.interpret("(point? 1)"),
+            Ok(LispVal::Bool(false))
+        );
+        interpreter.interpret("(set-point-x! p 10)").unwrap();
+        assert_eq!(
+            interpreter.interpret("(point-x p)"),
+            Ok(LispVal::Integer(10))
+        );
+        assert_eq!(
+            interpreter.interpret("p").unwrap().to_string(),
+            "#<record point>"
+        );
+    }
+
+    #[test]
+    fn test_record_type_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define-record-type node (make-node value) node? (value node-value) (next node-next set-node-next!))")
+            .unwrap();
+        interpreter
+            .interpret("(define-record-type leaf (make-leaf) leaf?)")
+            .unwrap();
+        interpreter.interpret("(define n (make-node 1))").unwrap();
+        assert_eq!(
+            interpreter.interpret("(node-next n)"),
+            Ok(LispVal::Unspecified)
+        );
+        assert!(interpreter.interpret("(node-value (make-leaf))").is_err());
+        assert!(interpreter.interpret("(make-node 1 2)").is_err());
+    }
+
+    fn list_of(v: &[i64]) -> LispVal {
+        LispVal::List(v.iter().copied().map(LispVal::Integer).collect())
+    }
+
+    #[test]
+    fn test_list_procedures() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(list 1 2 3)"),
+            Ok(list_of(&[1, 2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(length '(1 2 3))"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(
+            interpreter.interpret("(append '(1) '() '(2 3))"),
+            Ok(list_of(&[1, 2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(reverse '(1 2 3))"),
+            Ok(list_of(&[3, 2, 1]))
+        );
+        assert_eq!(
+            interpreter.interpret("(list-tail '(1 2 3) 1)"),
+            Ok(list_of(&[2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(list-ref '(1 2 3) 2)"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(interpreter.interpret("(iota 3 1)"), Ok(list_of(&[1, 2, 3])));
+        assert_eq!(
+            interpreter.interpret("(last-pair '(1 2 3))"),
+            Ok(list_of(&[3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(null? '())"),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_list_search() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(memq 2 '(1 2 3))"),
+            Ok(list_of(&[2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(member '(1) '(1 (1) 2))"),
+            Ok(LispVal::List(vec![list_of(&[1]), LispVal::Integer(2)]))
+        );
+        assert_eq!(
+            interpreter
+                .interpret("(assq 'b '((a 1) (b 2)))")
+                .unwrap()
+                .to_string(),
+            "(b 2)"
+        );
+        assert_eq!(
+            interpreter.interpret("(assoc 3 '((a 1) (b 2)))"),
+            Ok(LispVal::Bool(false))
+        );
+        assert_eq!(
+            interpreter.interpret("(delete 2 '(1 2 3 2))"),
+            Ok(list_of(&[1, 3]))
+        );
+    }
+
+    #[test]
+    fn test_higher_order_list_procedures() {
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret("(define (add a b) (+ a b))").unwrap();
+        interpreter.interpret("(define (sub a b) (- a b))").unwrap();
+        interpreter.interpret("(define (big? x) (> x 1))").unwrap();
+        assert_eq!(
+            interpreter.interpret("(map add '(1 2 3) '(10 20))"),
+            Ok(list_of(&[11, 22]))
+        );
+        assert_eq!(
+            interpreter.interpret("(filter big? '(1 2 3))"),
+            Ok(list_of(&[2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(reduce add 0 '(1 2 3))"),
+            Ok(LispVal::Integer(6))
+        );
+        assert_eq!(
+            interpreter.interpret("(fold-left sub 0 '(1 2 3))"),
+            Ok(LispVal::Integer(-6))
+        );
+        assert_eq!(
+            interpreter.interpret("(fold-right sub 0 '(1 2 3))"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(for-each big? '(1 2))"),
+            Ok(LispVal::Unspecified)
+        );
+    }
+
+    #[test]
+    fn test_primitives_are_bound() {
+        for (name, _) in PRIMITIVES {
+            assert!(
+                Interpreter::lookup_primitives(name).is_ok()
+                    || Interpreter::lookup_higher_order_primitives(name).is_some(),
+                "{} has no implementation",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_primitive_values() {
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret("(define plus +)").unwrap();
+        assert_eq!(interpreter.interpret("(plus 1 2)"), Ok(LispVal::Integer(3)));
+        assert_eq!(
+            interpreter.interpret("(map + '(1 2) '(3 4))"),
+            Ok(list_of(&[4, 6]))
+        );
+        assert_eq!(
+            interpreter.interpret("(procedure? car)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(procedure? 'car)"),
+            Ok(LispVal::Bool(false))
+        );
+        assert_eq!(
+            interpreter.interpret("car").unwrap().to_string(),
+            "#<procedure car>"
+        );
+        assert_eq!(
+            interpreter.interpret("(car 1 2)"),
+            Err("car expects 1 arguments, but got 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(apply + 1 2 '(3 4))"),
+            Ok(LispVal::Integer(10))
+        );
+        interpreter.interpret("(define (add a b) (+ a b))").unwrap();
+        assert_eq!(
+            interpreter.interpret("(apply add '(1 2))"),
+            Ok(LispVal::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_lambda_closure() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define (adder n) (lambda (x) (+ x n)))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("((adder 10) 5)"),
+            Ok(LispVal::Integer(15))
+        );
+        assert_eq!(
+            interpreter.interpret("(map (lambda (x) (* x x)) '(1 2 3))"),
+            Ok(list_of(&[1, 4, 9]))
+        );
+        interpreter.interpret("(define (car x) 0)").unwrap();
+        assert_eq!(interpreter.interpret("(car '(1))"), Ok(LispVal::Integer(0)));
+    }
+
+    #[test]
+    fn test_hash_table_with_equivalence() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define t (make-hash-table string=?))")
+            .unwrap();
+        interpreter
+            .interpret("(hash-table-set! t \"a\" 1)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref t (string #\\a))"),
+            Ok(LispVal::Integer(1))
+        );
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define (f a . rest) (cons a rest))")
+            .unwrap();
+        assert_eq!(interpreter.interpret("(f 1 2 3)"), Ok(list_of(&[1, 2, 3])));
+        assert_eq!(interpreter.interpret("(f 1)"), Ok(list_of(&[1])));
+        assert_eq!(
+            interpreter.interpret("(f)"),
+            Err("f expects at least 1 arguments, but got 0".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("((lambda args args) 1 2)"),
+            Ok(list_of(&[1, 2]))
+        );
+        interpreter.interpret("(define (g . args) args)").unwrap();
+        assert_eq!(interpreter.interpret("(g)"), Ok(list_of(&[])));
+        assert_eq!(
+            interpreter.interpret("((lambda (a) a) 1 2)"),
+            Err("procedure expects 1 arguments, but got 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_case_lambda() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define area (case-lambda ((r) (* 3 r r)) ((w h) (* w h)) ((a b . c) (length c))))",
+            )
+            .unwrap();
+        assert_eq!(interpreter.interpret("(area 2)"), Ok(LispVal::Integer(12)));
+        assert_eq!(interpreter.interpret("(area 2 3)"), Ok(LispVal::Integer(6)));
+        assert_eq!(
+            interpreter.interpret("(area 1 2 3 4)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(area)"),
+            Err("area expects 1 or 2 or at least 2 arguments, but got 0".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(procedure? area)"),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_optional_and_named_parameters() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define* (f a (b (+ a 1)) (scale: s 10)) (* s (+ a b)))")
+            .unwrap();
+        assert_eq!(interpreter.interpret("(f 1)"), Ok(LispVal::Integer(30)));
+        assert_eq!(interpreter.interpret("(f 1 5)"), Ok(LispVal::Integer(60)));
+        assert_eq!(
+            interpreter.interpret("(f 1 scale: 2)"),
+            Ok(LispVal::Integer(6))
+        );
+        assert_eq!(
+            interpreter.interpret("(f 1 5 scale: 2)"),
+            Ok(LispVal::Integer(12))
+        );
+        assert_eq!(
+            interpreter.interpret("(f 1 size: 2)"),
+            Err("f: unknown keyword size:".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("((lambda* (a (b 2)) (list a b)) 1)"),
+            Ok(list_of(&[1, 2]))
+        );
+        assert_eq!(
+            interpreter.interpret("(lambda (a (b 2)) a)"),
+            Err("(b 2) is not a valid parameter here".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(quote key:)"),
+            Ok(LispVal::Keyword(Symbol::intern("key")))
+        );
+    }
+
+    #[test]
+    fn test_and_or_not() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.interpret("(and)"), Ok(LispVal::Bool(true)));
+        assert_eq!(interpreter.interpret("(or)"), Ok(LispVal::Bool(false)));
+        assert_eq!(
+            interpreter.interpret("(and 1 2 3)"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(
+            interpreter.interpret("(or #f 2 3)"),
+            Ok(LispVal::Integer(2))
+        );
+        // the unbound atom after the deciding test is never evaluated
+        assert_eq!(
+            interpreter.interpret("(and 1 #f unbound)"),
+            Ok(LispVal::Bool(false))
+        );
+        assert_eq!(
+            interpreter.interpret("(or 1 unbound)"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(interpreter.interpret("(not #f)"), Ok(LispVal::Bool(true)));
+        assert_eq!(interpreter.interpret("(not 0)"), Ok(LispVal::Bool(false)));
+    }
+
+    #[test]
+    fn test_when_unless() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(when (< 1 2) 1 2)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(when #f unbound)"),
+            Ok(LispVal::Unspecified)
+        );
+        assert_eq!(
+            interpreter.interpret("(unless #f 3)"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(
+            interpreter.interpret("(unless 1 unbound)"),
+            Ok(LispVal::Unspecified)
+        );
+    }
+
+    #[test]
+    fn test_case() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define (kind x) (case x ((1 2 3) 'small) ((a b) 'letter) ((#\\x) => string) (else 'other)))",
+            )
+            .unwrap();
+        let symbol = |s| Ok(LispVal::Symbol(Symbol::intern(s)));
+        assert_eq!(interpreter.interpret("(kind 2)"), symbol("small"));
+        assert_eq!(interpreter.interpret("(kind 'b)"), symbol("letter"));
+        assert_eq!(interpreter.interpret("(kind 7)"), symbol("other"));
+        assert_eq!(
+            interpreter.interpret("(kind #\\x)"),
+            Ok(LispVal::String(LispString::immutable("x")))
+        );
+        assert_eq!(
+            interpreter.interpret("(case 5 ((1) 'one))"),
+            Ok(LispVal::Unspecified)
+        );
+        assert_eq!(
+            interpreter.interpret("(case 5 (else => (lambda (x) (* x x))))"),
+            Ok(LispVal::Integer(25))
+        );
+    }
+
+    #[test]
+    fn test_do() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(do ((i 0 (+ i 1)) (acc '() (cons i acc))) ((= i 3) acc))"),
+            Ok(list_of(&[2, 1, 0]))
+        );
+        // every iteration has its own binding of i
+        assert_eq!(
+            interpreter.interpret(
+                "(map (lambda (f) (f)) (do ((i 0 (+ i 1)) (fs '() (cons (lambda () i) fs))) ((= i 2) fs)))"
+            ),
+            Ok(list_of(&[1, 0]))
+        );
+        assert_eq!(
+            interpreter.interpret("(do ((i 0 (+ i 1))) ((= i 2)))"),
+            Ok(LispVal::Unspecified)
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define (count n) (if (= n 0) 'done (and #t (count (- n 1)))))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(count 30000)"),
+            Ok(LispVal::Symbol(Symbol::intern("done")))
+        );
+        // the caller's environment is restored after a tail call
+        interpreter.interpret("(define n 7)").unwrap();
+        interpreter.interpret("(count 3)").unwrap();
+        assert_eq!(interpreter.interpret("n"), Ok(LispVal::Integer(7)));
+    }
+
+    #[test]
+    fn test_cond() {
+        let mut interpreter = Interpreter::new();
+        let symbol = |s| Ok(LispVal::Symbol(Symbol::intern(s)));
+        assert_eq!(interpreter.interpret("(cond (5 'x))"), symbol("x"));
+        interpreter.interpret("(define x 1)").unwrap();
+        assert_eq!(interpreter.interpret("(cond (x 'one))"), symbol("one"));
+        assert_eq!(
+            interpreter.interpret("(cond (#f 1) (else 2 3))"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(
+            interpreter.interpret("(cond ((< 2 1) 1) ((memv 2 '(1 2 3))))"),
+            Ok(list_of(&[2, 3]))
+        );
+        assert_eq!(
+            interpreter.interpret("(cond ((memv 2 '(1 2 3)) => length) (else 0))"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(cond (#f 1))"),
+            Ok(LispVal::Unspecified)
+        );
+        assert_eq!(
+            interpreter.interpret("(cond ((car 1) 1) (else 2))"),
+            Err("Cannot take car of non-list".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(cond (else 1) (#t 2))"),
+            Err("cond: else must be the last clause".to_string())
+        );
+        interpreter
+            .interpret("(define (count n) (cond ((= n 0) 'done) (else (count (- n 1)))))")
+            .unwrap();
+        assert_eq!(interpreter.interpret("(count 30000)"), symbol("done"));
+    }
+
+    #[test]
+    fn test_equivalence_predicates() {
+        let mut interpreter = Interpreter::new();
+        let check = |interpreter: &mut Interpreter, expr: &str, expected: bool| {
+            assert_eq!(
+                interpreter.interpret(expr),
+                Ok(LispVal::Bool(expected)),
+                "{}",
+                expr
+            )
+        };
+        check(&mut interpreter, "(eq? 'a 'a)", true);
+        check(&mut interpreter, "(eq? 'a 'b)", false);
+        check(&mut interpreter, "(eq? '() '())", true);
+        check(&mut interpreter, "(eqv? 2 2)", true);
+        check(&mut interpreter, "(eqv? #\\a #\\a)", true);
+        check(&mut interpreter, "(eqv? \"ab\" \"ab\")", false);
+        check(&mut interpreter, "(equal? \"ab\" \"ab\")", true);
+        check(
+            &mut interpreter,
+            "(equal? '(1 (2 \"c\")) '(1 (2 \"c\")))",
+            true,
+        );
+        check(&mut interpreter, "(eqv? car car)", true);
+        check(
+            &mut interpreter,
+            "(eqv? (lambda (x) x) (lambda (x) x))",
+            false,
+        );
+        interpreter.interpret("(define s \"ab\")").unwrap();
+        check(&mut interpreter, "(eq? s s)", true);
+        check(&mut interpreter, "(eq? 2 \"2\")", false);
+        assert_eq!(
+            interpreter.interpret("(memv 'b '(a b c))"),
+            interpreter.interpret("'(b c)")
+        );
+        assert_eq!(
+            interpreter.interpret("(assoc \"b\" '((\"a\" 1) (\"b\" 2)))"),
+            interpreter.interpret("'(\"b\" 2)")
+        );
+        check(
+            &mut interpreter,
+            "(assv \"b\" '((\"a\" 1) (\"b\" 2)))",
+            false,
+        );
+    }
+
+    #[test]
+    fn test_promises() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define h (make-hash-table))")
+            .unwrap();
+        interpreter
+            .interpret("(define p (delay (begin-count)))")
+            .unwrap();
+        interpreter
+            .interpret("(define (begin-count) (hash-table-update!/default h 'n (lambda (x) (+ x 1)) 0) 'forced)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(promise? p)"),
+            Ok(LispVal::Bool(true))
+        );
+        let forced = Ok(LispVal::Symbol(Symbol::intern("forced")));
+        assert_eq!(interpreter.interpret("(force p)"), forced);
+        assert_eq!(interpreter.interpret("(force p)"), forced);
+        assert_eq!(
+            interpreter.interpret("(hash-table-ref h 'n)"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(force (make-promise 3))"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(interpreter.interpret("(force 4)"), Ok(LispVal::Integer(4)));
+        interpreter
+            .interpret("(define (loop n) (delay-force (if (= n 0) (delay 'done) (loop (- n 1)))))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(force (loop 30000))"),
+            Ok(LispVal::Symbol(Symbol::intern("done")))
+        );
+        assert_eq!(
+            interpreter.interpret("(force (delay-force 1))"),
+            Err("delay-force: 1 is not a promise".to_string())
+        );
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define (ints n) (stream-cons n (ints (+ n 1))))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(stream->list (stream-take 4 (ints 1)))"),
+            Ok(list_of(&[1, 2, 3, 4]))
+        );
+        assert_eq!(
+            interpreter.interpret("(stream->list (ints 1) 2)"),
+            Ok(list_of(&[1, 2]))
+        );
+        assert_eq!(
+            interpreter.interpret("(stream-car (stream-cdr (stream-cdr (ints 5))))"),
+            Ok(LispVal::Integer(7))
+        );
+        assert_eq!(
+            interpreter.interpret("(stream->list (list->stream '(1 2)))"),
+            Ok(list_of(&[1, 2]))
+        );
+        assert_eq!(
+            interpreter.interpret("(stream-null? (stream-cdr (list->stream '(1))))"),
+            Ok(LispVal::Bool(true))
+        );
+        // SICP's cons-stream evaluates the head right away
+        interpreter
+            .interpret("(define (integers-from n) (cons-stream n (integers-from (+ n 1))))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(stream-car (stream-cdr (integers-from 1)))"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(stream-pair? the-empty-stream)"),
+            Ok(LispVal::Bool(false))
+        );
+    }
+
+    /// An output sink an embedder keeps a handle to.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_redirected_output() {
+        let mut interpreter = Interpreter::new();
+        let out = SharedBuffer::default();
+        interpreter.set_output(out.clone());
+        interpreter
+            .interpret("(for-each display '(1 \"a\" #\\b (\"c\" #\\d)))")
+            .unwrap();
+        interpreter.interpret("(newline)").unwrap();
+        interpreter
+            .interpret("(write '(\"c\" #\\d #\\space))")
+            .unwrap();
+        interpreter
+            .interpret("(write-string \"!\" (current-output-port))")
+            .unwrap();
+        assert_eq!(out.contents(), "1ab(c d)\n(\"c\" #\\d #\\space)!");
+    }
+
+    #[test]
+    fn test_redirected_input() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_input(std::io::Cursor::new(
+            "first line\n(a\n b) x\n".as_bytes().to_vec(),
+        ));
+        assert_eq!(
+            interpreter.interpret("(read-line)"),
+            Ok(LispVal::String(LispString::immutable("first line")))
+        );
+        assert_eq!(interpreter.interpret("(peek-char)"), Ok(LispVal::Char('(')));
+        assert_eq!(
+            interpreter.interpret("(read)"),
+            interpreter.interpret("'(a b)")
+        );
+        assert_eq!(interpreter.interpret("(read-char)"), Ok(LispVal::Char(' ')));
+        assert_eq!(
+            interpreter.interpret("(read)"),
+            Ok(LispVal::Symbol(Symbol::intern("x")))
+        );
+        assert_eq!(interpreter.interpret("(read)"), Ok(LispVal::Eof));
+        assert_eq!(
+            interpreter.interpret("(eof-object? (read-line))"),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_string_ports() {
+        let mut interpreter = Interpreter::new();
+        let string = |s: &str| Ok(LispVal::String(LispString::immutable(s)));
+        assert_eq!(
+            interpreter
+                .interpret("(with-output-to-string (lambda () (display \"a\") (write \"b\")))"),
+            string("a\"b\"")
+        );
+        assert_eq!(
+            interpreter.interpret("(call-with-output-string (lambda (port) (display 42 port)))"),
+            string("42")
+        );
+        interpreter
+            .interpret("(define p (open-input-string \"ab\"))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(char-ready? p)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(read-char p)"),
+            Ok(LispVal::Char('a'))
+        );
+        assert_eq!(
+            interpreter.interpret("(read-char p)"),
+            Ok(LispVal::Char('b'))
+        );
+        assert_eq!(interpreter.interpret("(read-char p)"), Ok(LispVal::Eof));
+        assert_eq!(
+            interpreter.interpret("(input-port? p)"),
+            Ok(LispVal::Bool(true))
+        );
+        interpreter.interpret("(close-port p)").unwrap();
+        assert_eq!(
+            interpreter.interpret("(read-char p)"),
+            Err("read-char: the port is closed".to_string())
+        );
+        // the output port is restored when the thunk fails
+        let out = SharedBuffer::default();
+        interpreter.set_output(out.clone());
+        assert!(interpreter
+            .interpret("(with-output-to-string (lambda () (car 1)))")
+            .is_err());
+        interpreter.interpret("(display 1)").unwrap();
+        assert_eq!(out.contents(), "1");
+    }
+
+    #[test]
+    fn test_file_ports() {
+        let path = std::env::temp_dir().join(format!("scheme-port-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(&format!(
+                "(call-with-output-file {:?} (lambda (port) (write '(1 \"two\") port) (newline port)))",
+                path
+            ))
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret(&format!("(call-with-input-file {:?} read)", path)),
+            interpreter.interpret("'(1 \"two\")")
+        );
+        assert_eq!(
+            interpreter.interpret(&format!("(read-line (open-input-file {:?}))", path)),
+            Ok(LispVal::String(LispString::immutable("(1 \"two\")")))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_forms_and_begin() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(define x 1) (+ x 1)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("(begin (define y 2) (+ x y))"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(interpreter.interpret(""), Ok(LispVal::Unspecified));
+        assert_eq!(
+            interpreter.interpret("(+ 1"),
+            Err("unexpected end of input".to_string())
+        );
+    }
+
+    #[test]
+    fn test_import_sets() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(import (prefix (only (scheme base) car cdr) b:))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(b:car (b:cdr '(1 2)))"),
+            Ok(LispVal::Integer(2))
+        );
+        interpreter
+            .interpret("(import (rename (except (scheme write) write) (display show)))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(procedure? show)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(import (only (scheme base) nope))"),
+            Err("only: nope is not exported".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(import (no such library))"),
+            Err("unknown library (no such library)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_define_library() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define-library (counter) (export (rename next successor)) (import (scheme base)) (begin (define (next n) (+ n 1))))",
+            )
+            .unwrap();
+        interpreter.interpret("(import (counter))").unwrap();
+        assert_eq!(
+            interpreter.interpret("(successor 1)"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter.interpret("next"),
+            Err("unknown atom next".to_string())
+        );
+        // a library body only sees what it imports
+        interpreter
+            .interpret("(define-library (bare) (export f) (begin (define (f) (car '(1)))))")
+            .unwrap();
+        interpreter.interpret("(import (bare))").unwrap();
+        assert_eq!(
+            interpreter.interpret("(f)"),
+            Err("unknown atom car".to_string())
+        );
+    }
+
+    #[test]
+    fn test_library_files() {
+        let dir = std::env::temp_dir().join(format!("scheme-library-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("geometry")).unwrap();
+        std::fs::write(
+            dir.join("geometry/shapes.sld"),
+            "(define-library (geometry shapes) (export area (rename perimeter perim)) (import (scheme base)) (include \"impl.scm\"))",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("geometry/impl.scm"),
+            "(define (area w h) (* w h))\n(define (perimeter w h) (* 2 (+ w h)))",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("main.scm"),
+            "(define z 5)\n(define (twice x) (* 2 x))",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.add_library_path(&dir);
+        interpreter.interpret("(import (geometry shapes))").unwrap();
+        assert_eq!(interpreter.interpret("(area 2 3)"), Ok(LispVal::Integer(6)));
+        assert_eq!(
+            interpreter.interpret("(perim 2 3)"),
+            Ok(LispVal::Integer(10))
+        );
+        interpreter
+            .interpret(&format!(
+                "(load {:?})",
+                dir.join("main.scm").to_str().unwrap()
+            ))
+            .unwrap();
+        assert_eq!(interpreter.interpret("(twice z)"), Ok(LispVal::Integer(10)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_profiles() {
+        let dir = std::env::temp_dir().join(format!("scheme-sandbox-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("data.txt"), "inside").unwrap();
+        std::fs::write(root.join("defs.scm"), "(define z 5)").unwrap();
+        std::fs::write(
+            root.join("lib/util.sld"),
+            "(define-library (lib util) (export twice) (import (scheme base)) (begin (define (twice x) (* 2 x))))",
+        )
+        .unwrap();
+        std::fs::write(dir.join("secret.txt"), "outside").unwrap();
+
+        let mut pure = Interpreter::with_profile(Profile::Pure);
+        assert_eq!(pure.interpret("(+ 1 2)"), Ok(LispVal::Integer(3)));
+        pure.interpret("(import (scheme base))").unwrap();
+        for (source, who) in [
+            ("(open-input-file \"data.txt\")", "open-input-file"),
+            ("(open-output-file \"out.txt\")", "open-output-file"),
+            (
+                "(call-with-input-file \"data.txt\" read)",
+                "call-with-input-file",
+            ),
+            ("(load \"defs.scm\")", "load"),
+            ("(include \"defs.scm\")", "include"),
+        ] {
+            assert_eq!(
+                pure.interpret(source),
+                Err(format!("{}: capability not granted", who))
+            );
+        }
+
+        let mut read_only = Interpreter::with_profile(Profile::ReadOnly { root: root.clone() });
+        assert_eq!(
+            read_only.interpret("(read-line (open-input-file \"data.txt\"))"),
+            Ok(LispVal::String(LispString::immutable("inside")))
+        );
+        read_only.interpret("(load \"defs.scm\")").unwrap();
+        read_only.interpret("(import (lib util))").unwrap();
+        assert_eq!(read_only.interpret("(twice z)"), Ok(LispVal::Integer(10)));
+        assert_eq!(
+            read_only.interpret("(open-input-file \"../secret.txt\")"),
+            Err(format!(
+                "open-input-file: capability not granted to read {}",
+                root.join("../secret.txt").display()
+            ))
+        );
+        assert_eq!(
+            read_only.interpret("(open-output-file \"data.txt\")"),
+            Err("open-output-file: capability not granted".to_string())
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("data.txt")).unwrap(),
+            "inside"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.interpret("(values 1)"), Ok(LispVal::Integer(1)));
+        assert_eq!(
+            interpreter.interpret("(values 1 2)"),
+            Ok(LispVal::Values(vec![
+                LispVal::Integer(1),
+                LispVal::Integer(2)
+            ]))
+        );
+        assert_eq!(
+            interpreter.interpret("(call-with-values (lambda () (values 1 2 3)) +)"),
+            Ok(LispVal::Integer(6))
+        );
+        assert_eq!(
+            interpreter.interpret("(call-with-values (lambda () 4) (lambda (x) (* x x)))"),
+            Ok(LispVal::Integer(16))
+        );
+        assert_eq!(
+            interpreter.interpret("(call-with-values values list)"),
+            Ok(LispVal::List(Vec::new()))
+        );
+        assert_eq!(
+            interpreter.interpret(
+                "(let-values (((a b) (values 1 2)) ((c . d) (values 3 4 5)) (e (values))) (list a b c d e))"
+            ),
+            Ok(LispVal::List(vec![
+                LispVal::Integer(1),
+                LispVal::Integer(2),
+                LispVal::Integer(3),
+                list_of(&[4, 5]),
+                LispVal::List(Vec::new()),
+            ]))
+        );
+        assert_eq!(
+            interpreter.interpret("(receive (q . r) (values 7 8 9) (cons q r))"),
+            Ok(list_of(&[7, 8, 9]))
+        );
+        interpreter
+            .interpret("(define-values (x y) (values 10 20))")
+            .unwrap();
+        assert_eq!(interpreter.interpret("(+ x y)"), Ok(LispVal::Integer(30)));
+        assert_eq!(
+            interpreter.interpret("(define-values (x y) (values 1 2 3))"),
+            Err("define-values expects 2 arguments, but got 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.interpret("(eval '(+ 1 2) (scheme-report-environment 5))"),
+            Ok(LispVal::Integer(3))
+        );
+        assert_eq!(
+            interpreter.interpret("(eval (list 'quote 'a))"),
+            Ok(LispVal::Symbol(Symbol::intern("a")))
+        );
+        // definitions made in the interaction environment are seen by the program
+        interpreter
+            .interpret("(eval '(define (square x) (* x x)) (interaction-environment))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(square 4)"),
+            Ok(LispVal::Integer(16))
+        );
+        // and those made in a report environment are not
+        interpreter
+            .interpret("(define env (scheme-report-environment 7)) (eval '(define y 1) env)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(eval 'y env)"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("y"),
+            Err("unknown atom y".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(eval '(car '(1 2)) (environment '(only (scheme base) car)))"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(eval '(cdr '(1 2)) (environment '(only (scheme base) car)))"),
+            Err("unknown atom cdr".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(eval '(if #t 1 2) (null-environment 5))"),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("(eval 1 2)"),
+            Err("eval: 2 is not an environment".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(eq? (interaction-environment) (interaction-environment))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter
+                .interpret("(eval (read (open-input-string \"(string-length \\\"abc\\\")\")))"),
+            Ok(LispVal::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_parameters() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define radix (make-parameter 10)) (define (show) (radix))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(list (show) (parameterize ((radix 2)) (show)) (show))"),
+            Ok(list_of(&[10, 2, 10]))
+        );
+        // converters apply to the initial value and to parameterized ones
+        interpreter
+            .interpret("(define width (make-parameter 1 (lambda (x) (* x 10))))")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(list (width) (parameterize ((width 2)) (width)))"),
+            Ok(list_of(&[10, 20]))
+        );
+        // the values are restored when the body fails
+        assert_eq!(
+            interpreter.interpret("(parameterize ((radix 16) (width 3)) (car 1))"),
+            Err("Cannot take car of non-list".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(list (radix) (width))"),
+            Ok(list_of(&[10, 10]))
+        );
+        assert_eq!(
+            interpreter.interpret("(parameterize ((car 1)) 1)"),
+            Err("parameterize: car is not a parameter".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(procedure? radix)"),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_parameterize_current_output_port() {
+        let mut interpreter = Interpreter::new();
+        let output = SharedBuffer::default();
+        interpreter.set_output(output.clone());
+        interpreter
+            .interpret("(define port (open-output-string))")
+            .unwrap();
+        interpreter
+            .interpret("(parameterize ((current-output-port port)) (display \"inner\")) (display \"outer\")")
+            .unwrap();
+        assert_eq!(output.contents(), "outer");
+        assert_eq!(
+            interpreter.interpret("(get-output-string port)"),
+            Ok(LispVal::String(LispString::immutable("inner")))
+        );
+        assert_eq!(
+            interpreter.interpret("(parameterize ((current-output-port 1)) (display 2))"),
+            Err("current-output-port: 1 is not a port".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pairs() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define l (list 1 2 3)) (define m (cdr l))")
+            .unwrap();
+        interpreter.interpret("(set-car! m 20)").unwrap();
+        assert_eq!(
+            interpreter.interpret("l").map(|v| v.to_string()),
+            Ok("(1 20 3)".to_string()),
+            "the tail is shared, not copied"
+        );
+        assert_eq!(
+            interpreter.interpret("(eq? m (cdr l))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(equal? l '(1 20 3))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(cons 1 2)").map(|v| v.to_string()),
+            Ok("(1 . 2)".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(cdr '(1 . 2))"),
+            Ok(LispVal::Integer(2))
+        );
+        assert_eq!(
+            interpreter
+                .interpret("(append '(1) (list 2) 3)")
+                .map(|v| v.to_string()),
+            Ok("(1 2 . 3)".to_string())
+        );
+        assert_eq!(
+            interpreter
+                .interpret("(assv 2 (list (cons 1 'a) (cons 2 'b)))")
+                .map(|v| v.to_string()),
+            Ok("(2 . b)".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(set-car! '(1 2) 3)"),
+            Err("set-car!: cannot modify the constant (1 2)".to_string())
+        );
+        // quoted lists are pairs, so their cdr is shared rather than copied
+        assert_eq!(
+            interpreter.interpret("(define q '(1 2 3)) (eq? (cdr q) (cdr q))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(set-cdr! (cdr q) '())"),
+            Err("set-cdr!: cannot modify the constant (2 3)".to_string())
+        );
+        interpreter.interpret("(set-cdr! (cdr (cdr l)) l)").unwrap();
+        assert_eq!(
+            interpreter.interpret("l").map(|v| v.to_string()),
+            Ok("#0=(1 20 3 . #0#)".to_string())
+        );
+        assert_eq!(interpreter.interpret("(list? l)"), Ok(LispVal::Bool(false)));
+    }
+
+    #[test]
+    fn test_equal_circular_lists() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret("(define l (list 1)) (set-car! l l)")
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(equal? l l)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret(
+                "(define m (list 1)) (set-car! m m)
+                 (define t (make-equal-hash-table))
+                 (hash-table-set! t l 'l)
+                 (hash-table-ref t m)"
+            ),
+            Ok(LispVal::Symbol(Symbol::intern("l"))),
+            "a pair holding itself is equal to any other"
+        );
+        interpreter
+            .interpret(
+                "(define a (list 1 2)) (set-cdr! (cdr a) a)
+                 (define b (list 1 2 1 2)) (set-cdr! (cdr (cdr (cdr b))) b)
+                 (define c (list 1 2 1 3)) (set-cdr! (cdr (cdr (cdr c))) c)",
+            )
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(equal? a b)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(equal? a c)"),
+            Ok(LispVal::Bool(false))
+        );
+        assert_eq!(
+            interpreter.interpret("(hash-table-set! t a 'a) (hash-table-ref t b)"),
+            Ok(LispVal::Symbol(Symbol::intern("a")))
+        );
+        assert_eq!(
+            interpreter.interpret("(equal? (list a 1) (list b 1))"),
+            Ok(LispVal::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_cycles_are_collected() {
+        let mut interpreter = Interpreter::new();
+        // each call makes a frame holding a closure that holds the frame
+        interpreter
+            .interpret("(define (make) (define (self) self) self)")
+            .unwrap();
+        let baseline = gc::collect();
+        interpreter
+            .interpret("(for-each (lambda (i) (make)) (iota 100))")
+            .unwrap();
+        assert_eq!(gc::collect(), baseline);
+        interpreter
+            .interpret("((lambda (l) (set-cdr! (cdr l) l)) (list 1 2))")
+            .unwrap();
+        assert_eq!(gc::collect(), baseline);
+    }
+
+    #[test]
+    fn test_long_list() {
+        let mut interpreter = Interpreter::new();
+        // freeing the pairs must not recurse down the list
+        assert_eq!(
+            interpreter.interpret("(length (iota 100000))"),
+            Ok(LispVal::Integer(100000))
+        );
+    }
+
+    #[test]
+    fn test_heap_limit_is_per_interpreter() {
+        let mut limited = Interpreter::new();
+        limited.set_limits(Limits {
+            max_heap: Some(1 << 20),
+            ..Limits::default()
+        });
+        let mut unlimited = Interpreter::new();
+        assert_eq!(
+            limited.interpret("(length (iota 100000))"),
+            Err(LimitExceeded::Heap.to_string())
+        );
+        assert_eq!(
+            unlimited.interpret("(length (iota 100000))"),
+            Ok(LispVal::Integer(100000))
+        );
+        assert_eq!(
+            Interpreter::new().interpret("(length (iota 100000))"),
+            Ok(LispVal::Integer(100000))
+        );
+        assert_eq!(
+            limited.interpret("(length (iota 100000))"),
+            Err(LimitExceeded::Heap.to_string())
+        );
+    }
+
+    #[test]
+    fn test_deep_data() {
+        let mut interpreter = Interpreter::new();
+        // comparing, hashing, printing and freeing must not recurse into the cars
+        interpreter
+            .interpret(
+                "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (list acc))))
+                 (define a (nest 100000 '()))
+                 (define b (nest 100000 '()))",
+            )
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(equal? a b)"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret(
+                "(define t (make-equal-hash-table)) (hash-table-set! t a 1) (hash-table-ref t b)"
+            ),
+            Ok(LispVal::Integer(1))
+        );
+        assert_eq!(
+            interpreter.interpret("a").map(|v| v.to_string()),
+            Ok(format!("{}(){}", "(".repeat(100000), ")".repeat(100000)))
+        );
+        drop(interpreter);
+
+        // nor must freeing records held in records, or closures made in closures
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define-record-type box (mk value) box? (value unbox))
+                 (define (wrap f) (lambda () f))
+                 (define r (do ((i 0 (+ i 1)) (r '() (mk r))) ((= i 90000) r)))
+                 (define f (do ((i 0 (+ i 1)) (f car (wrap f))) ((= i 90000) f)))",
+            )
+            .unwrap();
+        assert_eq!(
+            interpreter.interpret("(box? (unbox r))"),
+            Ok(LispVal::Bool(true))
+        );
+        assert_eq!(
+            interpreter.interpret("(procedure? ((f)))"),
+            Ok(LispVal::Bool(true))
+        );
+        drop(interpreter);
+
+        // code may nest as deeply as evaluation may
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(interpret_with_all_backends(&format!("(quote {})", nested(1000))).is_ok());
+        let mut limited = Interpreter::new();
+        limited.set_limits(Limits {
+            max_depth: Some(100),
+            ..Limits::default()
+        });
+        assert_eq!(
+            limited.interpret(&format!("(quote {})", nested(100))),
+            Err("lists are nested more than 100 deep".to_string())
+        );
+        assert_eq!(
+            limited.interpret(&format!("(read (open-input-string \"{}\"))", nested(101))),
+            Err("read: lists are nested more than 100 deep".to_string())
+        );
+        assert!(limited
+            .interpret(&format!("(quote {})", nested(99)))
+            .is_ok());
+    }
+
+    /// Runs `source` with every backend, which must agree.
+    fn interpret_with_all_backends(source: &str) -> Result<LispVal, String> {
+        let expected = Interpreter::new().interpret(source);
+        for backend in [Backend::Analyzer, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            assert_eq!(
+                interpreter.interpret(source),
+                expected,
+                "{:?}: {}",
+                backend,
+                source
+            );
+        }
+        expected
+    }
+
+    #[test]
+    fn test_vm_backend() {
+        let fib = "(define (fib n) (if (= n 0) 0 (if (= n 1) 1 (+ (fib (- n 1)) (fib (- n 2))))))";
+        assert_eq!(
+            interpret_with_all_backends(&format!("{} (fib 15)", fib)),
+            Ok(LispVal::Integer(610))
+        );
+        assert_eq!(
+            interpret_with_all_backends(
+                "(define (count n) (if (= n 0) 'done (count (- n 1)))) (count 100000)"
+            ),
+            Ok(LispVal::Symbol(Symbol::intern("done")))
+        );
+        for (source, expected) in [
+            ("(define (adder n) (lambda (x) (+ x n))) ((adder 2) 3)", "5"),
+            ("(define (f x) (define y (* x 2)) (+ x y)) (f 3)", "9"),
+            (
+                "(list (and 1 #f 2) (or #f 2) (and) (or) (and 1 2))",
+                "(#f 2 #t #f 2)",
+            ),
+            (
+                "(list (cond (#f 1) ((+ 1 1)) (else 3)) (cond (#f 1) (else 2 3)) (unless #f 1 2))",
+                "(2 3 2)",
+            ),
+            ("(map (lambda (x) (* x x)) '(1 2 3))", "(1 4 9)"),
+            ("(case 2 ((1) 'one) ((2) 'two))", "two"),
+            ("(cond (2 => (lambda (x) (* x x))))", "4"),
+            (
+                "(do ((i 0 (+ i 1)) (acc '() (cons i acc))) ((= i 3) acc))",
+                "(2 1 0)",
+            ),
+            (
+                "(define (f x) (case x ((1 2) 'small) ((3) => (lambda (y) (* y y))) (else 'big)))
+                 (list (f 2) (f 3) (f 4) (case 5 ((1) 1)))",
+                "(small 9 big #<unspecified>)",
+            ),
+            (
+                "(define (f n) (define v (do ((i 0 (+ i 1)) (fs '() (cons (lambda () i) fs)))
+                                            ((= i n) (map (lambda (f) (f)) fs))))
+                   (list v n))
+                 (f 3)",
+                "((2 1 0) 3)",
+            ),
+            ("(do ((i 0 (+ i 1))) ((= i 2)))", "#<unspecified>"),
+            ("(cond (#f => car) ((assv 2 '((1 . a) (2 . b))) => cdr))", "b"),
+            (
+                "(define p (delay (+ 1 2))) (define q (delay-force (delay 4))) (list (force p) (force q))",
+                "(3 4)",
+            ),
+            (
+                "(define s (stream-cons 1 (cons-stream 2 stream-nil)))
+                 (list (stream-car s) (stream-car (stream-cdr s)))",
+                "(1 2)",
+            ),
+            (
+                "(define f (case-lambda ((x) x) ((x y) (+ x y)))) (list (f 1) (f 1 2))",
+                "(1 3)",
+            ),
+            ("((lambda* (a (b 2)) (list a b)) 1)", "(1 2)"),
+            ("(eval '((lambda (x) (+ x 1)) 1))", "2"),
+            ("(begin)", "#<unspecified>"),
+        ] {
+            assert_eq!(
+                interpret_with_all_backends(source).map(|v| v.to_string()),
+                Ok(expected.to_string())
+            );
+        }
+        for (source, error) in [
+            ("(if #f 1)", "Unspecified return value"),
+            ("(undefined)", "unknown atom undefined"),
+            (
+                "((lambda (x) x))",
+                "procedure expects 1 arguments, but got 0",
+            ),
+            ("(define (f x) x) (f)", "f expects 1 arguments, but got 0"),
+            ("(car 1)", "Cannot take car of non-list"),
+            ("(lambda (1) 1)", "1 is not a valid parameter here"),
+            ("(case 1 (1 'one))", "case: bad clause (1 (quote one))"),
+            ("(do ((1 2)) (#t))", "do: bad variable spec (1 2)"),
+            ("(quote)", "quote expects exactly one datum"),
+            ("(quote 1 2)", "quote expects exactly one datum"),
+            ("(define)", "unknown define"),
+            ("(define x)", "define expects a variable and a value"),
+            ("(define x 1 2)", "define expects a variable and a value"),
+        ] {
+            assert_eq!(interpret_with_all_backends(source), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_analyzer_backend() {
+        for (source, expected) in [
+            ("(define (f x) (define x 2) x) (f 1)", "2"),
+            ("(define (f x) (define (g) x) (define x 2) (g)) (f 1)", "2"),
+            (
+                "(define (f x . y) (lambda (z) (list x y z))) ((f 1 2) 3)",
+                "(1 (2) 3)",
+            ),
+            (
+                "(define* (f a (b (* a 2)) (c: c 3)) (list a b c)) (f 1 c: 4)",
+                "(1 2 4)",
+            ),
+            (
+                "(define f (case-lambda ((x) x) ((x y) (+ x y)))) (f 1 2)",
+                "3",
+            ),
+            ("(define (f x) (do ((i 0 (+ i 1))) ((= i 2) x))) (f 5)", "5"),
+            (
+                "(define (f x) (case x ((1) => (lambda (y) (+ x y))) (else 'no))) (f 1)",
+                "2",
+            ),
+            (
+                "(define (f x) (define-values (a b) (values x 2)) (list a b)) (f 1)",
+                "(1 2)",
+            ),
+            ("(define x 1) (define (f) x) (define x 2) (f)", "2"),
+        ] {
+            assert_eq!(
+                interpret_with_all_backends(source).map(|v| v.to_string()),
+                Ok(expected.to_string())
+            );
+        }
+        // syntax errors in a body are found when it is defined, not when it runs
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(Backend::Analyzer);
+        assert_eq!(
+            interpreter.interpret("(define (f) (if))"),
+            Err("if must have a condition".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("f"),
+            Err("unknown atom f".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let limited = |backend, limits| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_limits(limits);
+            interpreter
+        };
+        let error = |limit: LimitExceeded| Err(limit.to_string());
+        let recurse = "(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+        for backend in [Backend::TreeWalker, Backend::Analyzer, Backend::Vm] {
+            let mut interpreter = limited(
+                backend,
+                Limits {
+                    fuel: Some(10_000),
+                    ..Limits::default()
+                },
+            );
+            assert_eq!(
+                interpreter.interpret("(define (loop) (loop)) (loop)"),
+                error(LimitExceeded::Fuel)
+            );
+            assert_eq!(interpreter.fuel(), Some(0));
+
+            let mut interpreter = limited(
+                backend,
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+            );
+            assert_eq!(
+                interpreter.interpret(&format!("{} (f 1000)", recurse)),
+                error(LimitExceeded::Depth)
+            );
+            // the depth is back to where it was, and tail calls do not nest
+            assert_eq!(
+                interpreter.interpret("(define (g n) (if (= n 0) 'done (g (- n 1)))) (g 10000)"),
+                Ok(LispVal::Symbol(Symbol::intern("done")))
+            );
+
+            let mut interpreter = limited(
+                backend,
+                Limits {
+                    deadline: Some(std::time::Instant::now()),
+                    ..Limits::default()
+                },
+            );
+            assert_eq!(
+                interpreter.interpret("(define (loop) (loop)) (loop)"),
+                error(LimitExceeded::Deadline)
+            );
+        }
+
+        let mut interpreter = limited(
+            Backend::TreeWalker,
+            Limits {
+                max_heap: Some(1 << 20),
+                ..Limits::default()
+            },
+        );
+        assert_eq!(
+            interpreter.interpret("(length (iota 100000))"),
+            error(LimitExceeded::Heap)
+        );
+        assert_eq!(
+            interpreter.interpret(
+                "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))
+                 (length (build 100000 '()))"
+            ),
+            error(LimitExceeded::Heap)
+        );
+        assert_eq!(
+            interpreter.interpret("(length (build 1000 '()))"),
+            Ok(LispVal::Integer(1000))
+        );
+        // strings and lists are charged by size, before primitives make them
+        for source in [
+            "(make-string 100000000 #\\a)",
+            "(define (double s n) (if (= n 0) s (double (string-append s s) (- n 1))))
+             (double \"ab\" 40)",
+            "(define l (iota 1000)) (append l l l l l l l l l l l l l l l l l l l l)",
+            "(string->list (make-string 200000))",
+        ] {
+            assert_eq!(
+                interpreter.interpret(source),
+                error(LimitExceeded::Heap),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            interpreter.interpret("(length (append l l))"),
+            Ok(LispVal::Integer(2000))
+        );
+        assert_eq!(
+            interpreter.interpret(
+                "(define p (open-output-string))
+                 (define (fill n) (when (> n 0) (write-string \"0123456789\" p) (fill (- n 1))))
+                 (fill 200000)"
+            ),
+            error(LimitExceeded::Heap)
+        );
+        assert_eq!(
+            Interpreter::new().interpret("(make-string 9223372036854775807)"),
+            error(LimitExceeded::Heap)
+        );
+        assert_eq!(
+            Interpreter::new().interpret("(iota 3 9223372036854775806)"),
+            Err("integer overflow".to_string())
+        );
+
+        // primitives running long watch the deadline
+        let mut interpreter = limited(
+            Backend::TreeWalker,
+            Limits {
+                deadline: Some(std::time::Instant::now() + std::time::Duration::from_millis(200)),
+                ..Limits::default()
+            },
+        );
+        assert_eq!(
+            interpreter.interpret("(length (string->list (make-string 5000000)))"),
+            error(LimitExceeded::Deadline)
+        );
+
+        let mut interpreter = Interpreter::new();
+        let handle = interpreter.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        let result = interpreter.interpret("(define (loop) (loop)) (loop)");
+        interrupter.join().unwrap();
+        assert_eq!(result, error(LimitExceeded::Interrupted));
+        assert_eq!(
+            LimitExceeded::from_error(&result.unwrap_err()),
+            Some(LimitExceeded::Interrupted)
+        );
+        assert_eq!(LimitExceeded::from_error("unknown atom x"), None);
+        assert_eq!(interpreter.interpret("(+ 1 2)"), Ok(LispVal::Integer(3)));
+    }
 }
@@ -0,0 +1,568 @@
+//! Conversion between values and JSON, as in SRFI 180. Arrays are lists, since the
+//! language has no vectors, and objects are `equal?` hash tables with symbol keys, so
+//! that every value is written back as it was read. `null` is the symbol `null`.
+//! Numbers must have integral values.
+
+use crate::parser::parser::{LispVal, ParseError};
+
+use super::{
+    hash_table::{Equivalence, HashTable},
+    pair,
+    strings::LispString,
+    symbol::Symbol,
+};
+
+/// How deeply arrays and objects may nest for `from_json` and `to_json`, which have no
+/// interpreter limits to follow. Reading and writing JSON recurse.
+pub const MAX_NESTING: usize = 128;
+
+/// Reads the value `text` holds. Numbers must have integral values, like `2`, `2.0` or
+/// `1e3`, since integers are the only numbers; others are an error.
+pub fn from_json(text: &str) -> Result<LispVal, String> {
+    match read(text, Some(MAX_NESTING)) {
+        (Ok(Some(v)), consumed) if text[consumed..].trim().is_empty() => Ok(v),
+        (Ok(Some(_)), consumed) => Err(format!(
+            "unexpected text after the JSON value: {:?}",
+            text[consumed..].trim()
+        )),
+        (Ok(None), _) => Err("expected a JSON value, but got nothing".to_string()),
+        (Err(e), _) => Err(e.to_string()),
+    }
+}
+
+/// Writes `v` as JSON. Lists are written as arrays and hash tables keyed by symbols or
+/// strings as objects, with their members sorted by key.
+pub fn to_json(v: &LispVal) -> Result<String, String> {
+    write(v, Some(MAX_NESTING))
+}
+
+/// Reads the next value of `text`, or `None` when only whitespace is left, with the
+/// length of the text consumed. Arrays and objects may nest up to `max_nesting` deep.
+pub(super) fn read(
+    text: &str,
+    max_nesting: Option<usize>,
+) -> (Result<Option<LispVal>, ParseError>, usize) {
+    let mut reader = Reader {
+        text,
+        pos: 0,
+        depth: 0,
+        max_nesting,
+    };
+    reader.skip_whitespace();
+    if reader.peek().is_none() {
+        return (Ok(None), text.len());
+    }
+    let result = reader.value().map(Some);
+    (result, reader.pos)
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    /// byte offset of the next character in `text`
+    pos: usize,
+    /// arrays and objects the next value is inside of
+    depth: usize,
+    max_nesting: Option<usize>,
+}
+
+fn too_deep(max: usize) -> String {
+    format!("JSON values are nested more than {} deep", max)
+}
+
+fn invalid(message: String) -> ParseError {
+    ParseError::Invalid(message)
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Result<char, ParseError> {
+        let c = self.peek().ok_or(ParseError::Incomplete)?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// The next character after whitespace, which is not consumed.
+    fn peek_token(&mut self) -> Result<char, ParseError> {
+        self.skip_whitespace();
+        self.peek().ok_or(ParseError::Incomplete)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(invalid(format!("expected {:?}, but got {:?}", expected, c))),
+        }
+    }
+
+    fn value(&mut self) -> Result<LispVal, ParseError> {
+        match self.peek_token()? {
+            '[' | '{' if self.max_nesting.is_some_and(|max| self.depth >= max) => {
+                Err(invalid(too_deep(self.depth)))
+            }
+            '[' => self.nested(Self::array),
+            '{' => self.nested(Self::object),
+            '"' => Ok(LispVal::String(LispString::immutable(&self.string()?))),
+            '-' | '0'..='9' => self.number(),
+            c if c.is_ascii_alphabetic() => self.word(),
+            c => Err(invalid(format!("unexpected {:?}", c))),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        read: fn(&mut Self) -> Result<LispVal, ParseError>,
+    ) -> Result<LispVal, ParseError> {
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// The elements of an array or the members of an object, read with `element`.
+    fn sequence(
+        &mut self,
+        close: char,
+        mut element: impl FnMut(&mut Self) -> Result<LispVal, ParseError>,
+    ) -> Result<Vec<LispVal>, ParseError> {
+        self.next()?;
+        let mut items = Vec::new();
+        if self.peek_token()? == close {
+            self.next()?;
+            return Ok(items);
+        }
+        loop {
+            items.push(element(self)?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => {}
+                c if c == close => return Ok(items),
+                c => {
+                    return Err(invalid(format!(
+                        "expected ',' or {:?}, but got {:?}",
+                        close, c
+                    )))
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<LispVal, ParseError> {
+        self.sequence(']', Self::value).map(LispVal::List)
+    }
+
+    fn object(&mut self) -> Result<LispVal, ParseError> {
+        let table = HashTable::new(Equivalence::Equal);
+        self.sequence('}', |reader| {
+            if reader.peek_token()? != '"' {
+                return Err(invalid("object keys must be strings".to_string()));
+            }
+            let key = LispVal::Symbol(Symbol::intern(&reader.string()?));
+            reader.skip_whitespace();
+            reader.expect(':')?;
+            let value = reader.value()?;
+            table.insert(key, value).map_err(invalid)?;
+            Ok(LispVal::Unspecified)
+        })?;
+        Ok(LispVal::HashTable(table))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.next()?;
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => s.push(self.escape()?),
+                c if c < ' ' => {
+                    return Err(invalid(format!("control character {:?} in string", c)))
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        Ok(match self.next()? {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let unit = self.code_unit()?;
+                // characters outside the basic plane are escaped as surrogate pairs
+                let code = if (0xd800..0xdc00).contains(&unit) {
+                    self.expect('\\')?;
+                    self.expect('u')?;
+                    let low = self.code_unit()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(invalid(format!("unpaired surrogate \\u{:04x}", unit)));
+                    }
+                    0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+                } else {
+                    unit
+                };
+                char::from_u32(code)
+                    .ok_or_else(|| invalid(format!("unpaired surrogate \\u{:04x}", code)))?
+            }
+            c => return Err(invalid(format!("bad escape \\{}", c))),
+        })
+    }
+
+    /// The four hexadecimal digits of a `\u` escape.
+    fn code_unit(&mut self) -> Result<u32, ParseError> {
+        let mut unit = 0;
+        for _ in 0..4 {
+            let c = self.next()?;
+            let digit = c
+                .to_digit(16)
+                .ok_or_else(|| invalid(format!("bad hexadecimal digit {:?}", c)))?;
+            unit = unit * 16 + digit;
+        }
+        Ok(unit)
+    }
+
+    /// A number, which must have an integral value; `2.0` and `1e3` are integers too.
+    fn number(&mut self) -> Result<LispVal, ParseError> {
+        let start = self.pos;
+        while let Some('0'..='9' | '-' | '+' | '.' | 'e' | 'E') = self.peek() {
+            self.pos += 1;
+        }
+        let number = &self.text[start..self.pos];
+        let bad = || invalid(format!("bad number {}", number));
+        let unsigned = number.strip_prefix('-').unwrap_or(number);
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+            None => (unsigned, None),
+        };
+        let (whole, fraction) = match mantissa.split_once('.') {
+            Some((whole, fraction)) if !fraction.is_empty() => (whole, fraction),
+            Some(_) => return Err(bad()),
+            None => (mantissa, ""),
+        };
+        let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || (whole.len() > 1 && whole.starts_with('0')) {
+            return Err(bad());
+        }
+        if !fraction.is_empty() && !is_digits(fraction) {
+            return Err(bad());
+        }
+        // the value is `digits` times ten to the power of `scale`
+        let scale = match exponent {
+            Some(e) => {
+                let (negative, magnitude) = match e.as_bytes().first() {
+                    Some(b'-') => (true, &e[1..]),
+                    Some(b'+') => (false, &e[1..]),
+                    _ => (false, e),
+                };
+                if !is_digits(magnitude) {
+                    return Err(bad());
+                }
+                // exponents too large for `i64` saturate, which has the same effect
+                let magnitude = magnitude.parse::<i64>().unwrap_or(i64::MAX);
+                if negative {
+                    -magnitude
+                } else {
+                    magnitude
+                }
+            }
+            None => 0,
+        }
+        .saturating_sub(fraction.len() as i64);
+        let mut digits = format!("{}{}", whole, fraction)
+            .trim_start_matches('0')
+            .to_string();
+        if digits.is_empty() {
+            return Ok(LispVal::Integer(0));
+        }
+        if scale < 0 {
+            let kept = usize::try_from(scale.unsigned_abs())
+                .ok()
+                .and_then(|dropped| digits.len().checked_sub(dropped))
+                .filter(|&kept| digits[kept..].bytes().all(|b| b == b'0'))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "floating point numbers are not supported: {}",
+                        number
+                    ))
+                })?;
+            digits.truncate(kept);
+        } else if scale > 0 {
+            // no integer has more than 19 digits
+            if scale > 19 {
+                return Err(invalid(format!("{} does not fit in an integer", number)));
+            }
+            digits.extend(std::iter::repeat_n('0', scale as usize));
+        }
+        let sign = if number.starts_with('-') { "-" } else { "" };
+        format!("{}{}", sign, digits)
+            .parse()
+            .map(LispVal::Integer)
+            .map_err(|_| invalid(format!("{} does not fit in an integer", number)))
+    }
+
+    fn word(&mut self) -> Result<LispVal, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        match &self.text[start..self.pos] {
+            "true" => Ok(LispVal::Bool(true)),
+            "false" => Ok(LispVal::Bool(false)),
+            "null" => Ok(LispVal::Symbol(Symbol::intern("null"))),
+            word => Err(invalid(format!("unexpected {}", word))),
+        }
+    }
+}
+
+/// Writes `v` as JSON, with arrays and objects nested up to `max_nesting` deep.
+pub(super) fn write(v: &LispVal, max_nesting: Option<usize>) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(v, &mut out, 0, max_nesting)?;
+    Ok(out)
+}
+
+fn write_value(
+    v: &LispVal,
+    out: &mut String,
+    depth: usize,
+    max_nesting: Option<usize>,
+) -> Result<(), String> {
+    if let Some(max) = max_nesting.filter(|max| depth > *max) {
+        return Err(too_deep(max));
+    }
+    match v {
+        LispVal::Integer(i) => out.push_str(&i.to_string()),
+        LispVal::Bool(true) => out.push_str("true"),
+        LispVal::Bool(false) => out.push_str("false"),
+        LispVal::Symbol(s) if s.name() == "null" => out.push_str("null"),
+        LispVal::String(s) => write_string(&s.to_string(), out),
+        LispVal::HashTable(t) => {
+            let mut members = t
+                .entries()
+                .into_iter()
+                .map(|(key, value)| Some((key_name(&key)?, value)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("{} has keys other than symbols and strings", v))?;
+            members.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push('{');
+            for (i, (key, value)) in members.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(key, out);
+                out.push(':');
+                write_value(value, out, depth + 1, max_nesting)?;
+            }
+            out.push('}');
+        }
+        _ => {
+            let items =
+                pair::items(v).ok_or_else(|| format!("{} has no JSON representation", v))?;
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out, depth + 1, max_nesting)?;
+            }
+            out.push(']');
+        }
+    }
+    Ok(())
+}
+
+fn key_name(key: &LispVal) -> Option<String> {
+    match key {
+        LispVal::Symbol(s) => Some(s.name().to_string()),
+        LispVal::String(s) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Interpreter, Limits};
+    use super::*;
+
+    #[test]
+    fn test_json_conversion() {
+        let value = from_json(
+            r#" {"name": "web", "ports": [80, -443], "tls": true, "proxy": null,
+                 "note": "tab\tquote\" é😀", "empty": {}} "#,
+        )
+        .unwrap();
+        let LispVal::HashTable(table) = &value else {
+            panic!("{} is not a hash table", value);
+        };
+        let get = |key| table.get(&LispVal::Symbol(Symbol::intern(key))).unwrap();
+        assert_eq!(
+            get("ports").map(|v| v.to_string()),
+            Some("(80 -443)".to_string())
+        );
+        assert_eq!(
+            get("proxy").map(|v| v.to_string()),
+            Some("null".to_string())
+        );
+        assert_eq!(get("missing"), None);
+        assert_eq!(
+            to_json(&value),
+            Ok(r#"{"empty":{},"name":"web","note":"tab\tquote\" é😀","ports":[80,-443],"proxy":null,"tls":true}"#.to_string())
+        );
+        for text in [
+            r#"[["a",1]]"#,
+            "{}",
+            "[{}]",
+            "[[null,1]]",
+            "[]",
+            r#"{"a":[{"b":[]}]}"#,
+        ] {
+            assert_eq!(to_json(&from_json(text).unwrap()).as_deref(), Ok(text));
+        }
+
+        for (text, value) in [
+            ("2.0", 2),
+            ("1e3", 1000),
+            ("-1.5E+2", -150),
+            ("120e-1", 12),
+            ("0.0e99999999999999999999", 0),
+            ("-0", 0),
+            ("9.223372036854775807e18", i64::MAX),
+            ("-9223372036854775808", i64::MIN),
+        ] {
+            assert_eq!(from_json(text), Ok(LispVal::Integer(value)), "{}", text);
+        }
+
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(from_json(&nested(MAX_NESTING)).is_ok());
+        assert_eq!(
+            from_json(&nested(MAX_NESTING + 1)),
+            Err(too_deep(MAX_NESTING))
+        );
+        assert_eq!(from_json(&nested(100_000)), Err(too_deep(MAX_NESTING)));
+        let mut deep = LispVal::List(Vec::new());
+        for _ in 0..MAX_NESTING + 1 {
+            deep = LispVal::List(vec![deep]);
+        }
+        assert_eq!(to_json(&deep), Err(too_deep(MAX_NESTING)));
+
+        for (text, error) in [
+            ("[1, 2", "unexpected end of input"),
+            ("[1 2]", "expected ',' or ']', but got '2'"),
+            ("1.5", "floating point numbers are not supported: 1.5"),
+            ("1e-1", "floating point numbers are not supported: 1e-1"),
+            ("1.25e1", "floating point numbers are not supported: 1.25e1"),
+            (
+                "1e-99999999999999999999",
+                "floating point numbers are not supported: 1e-99999999999999999999",
+            ),
+            ("1e20", "1e20 does not fit in an integer"),
+            ("9.3e18", "9.3e18 does not fit in an integer"),
+            ("1.", "bad number 1."),
+            ("1e", "bad number 1e"),
+            ("-", "bad number -"),
+            ("1e+-2", "bad number 1e+-2"),
+            ("01", "bad number 01"),
+            ("{1: 2}", "object keys must be strings"),
+            ("nil", "unexpected nil"),
+            ("[] []", "unexpected text after the JSON value: \"[]\""),
+            ("  ", "expected a JSON value, but got nothing"),
+        ] {
+            assert_eq!(from_json(text), Err(error.to_string()));
+        }
+        assert_eq!(
+            to_json(&LispVal::Char('a')),
+            Err("#\\a has no JSON representation".to_string())
+        );
+    }
+
+    #[test]
+    fn test_json_primitives() {
+        let mut interpreter = Interpreter::new();
+        for (source, expected) in [
+            (
+                "(define p (open-input-string \"{\\\"a\\\": [1, {\\\"b\\\": null}]}\\n[true,\\n false]\"))",
+                "#<port>",
+            ),
+            (
+                "(define o (json-read p)) (hash-table-ref/default (car (cdr (hash-table-ref/default o 'a #f))) 'b #f)",
+                "null",
+            ),
+            ("(json-read p)", "(#t #f)"),
+            ("(eof-object? (json-read p))", "#t"),
+            (
+                "(with-output-to-string (lambda () (json-write (list '(\"id\" 7) '(\"tags\" \"x\")))))",
+                "\"[[\\\"id\\\",7],[\\\"tags\\\",\\\"x\\\"]]\"",
+            ),
+            (
+                "(define t (make-equal-hash-table)) (hash-table-set! t \"k\" 'null) \
+                 (with-output-to-string (lambda () (json-write t)))",
+                "\"{\\\"k\\\":null}\"",
+            ),
+            ("(import (srfi 180)) (json-read (open-input-string \"[]\"))", "()"),
+        ] {
+            assert_eq!(
+                interpreter.interpret(source).map(|v| v.to_string()),
+                Ok(expected.to_string())
+            );
+        }
+        assert_eq!(
+            interpreter.interpret("(json-write car)"),
+            Err("json-write: #<procedure car> has no JSON representation".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(json-write (list (cons 'id 7)))"),
+            Err("json-write: (id . 7) has no JSON representation".to_string())
+        );
+        assert_eq!(
+            interpreter.interpret("(json-read (open-input-string \"[1,\"))"),
+            Err("json-read: unexpected end of input".to_string())
+        );
+
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        let read = |depth| format!("(json-read (open-input-string \"{}\"))", nested(depth));
+        assert!(interpreter.interpret(&read(500)).is_ok());
+        interpreter.set_limits(Limits {
+            max_depth: Some(10),
+            ..Limits::default()
+        });
+        assert!(interpreter.interpret(&read(10)).is_ok());
+        assert_eq!(
+            interpreter.interpret(&read(11)),
+            Err("json-read: JSON values are nested more than 10 deep".to_string())
+        );
+        assert_eq!(
+            interpreter
+                .interpret("(json-write (do ((i 0 (+ i 1)) (l '() (list l))) ((= i 11) l)))"),
+            Err("json-write: JSON values are nested more than 10 deep".to_string())
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use std::{collections::HashMap, path::PathBuf, rc::Rc};
+
+use crate::parser::{parser::LispVal, Parser};
+
+use super::{symbol::Symbol, Interpreter};
+
+/// The bindings a library exports, under their exported names.
+type Exports = Rc<Vec<(Symbol, LispVal)>>;
+
+/// The standard libraries, exporting primitives under their usual names. Syntax such
+/// as `define` or `delay` is available everywhere, so some of them export nothing.
+const BUILTIN_LIBRARIES: &[(&str, &[&str])] = &[
+    (
+        "(scheme base)",
+        &[
+            "+",
+            "*",
+            "-",
+            "=",
+            "<",
+            ">",
+            "car",
+            "cdr",
+            "cons",
+            "set-car!",
+            "set-cdr!",
+            "eq?",
+            "eqv?",
+            "equal?",
+            "not",
+            "null?",
+            "pair?",
+            "list?",
+            "list",
+            "length",
+            "append",
+            "reverse",
+            "list-tail",
+            "list-ref",
+            "list-copy",
+            "memq",
+            "memv",
+            "member",
+            "assq",
+            "assv",
+            "assoc",
+            "map",
+            "for-each",
+            "apply",
+            "procedure?",
+            "string?",
+            "make-string",
+            "string",
+            "string-length",
+            "string-ref",
+            "string-set!",
+            "substring",
+            "string-append",
+            "string-copy",
+            "string->list",
+            "list->string",
+            "string->symbol",
+            "symbol->string",
+            "symbol?",
+            "symbol=?",
+            "string=?",
+            "string<?",
+            "string>?",
+            "string<=?",
+            "string>=?",
+            "string-map",
+            "string-for-each",
+            "write-string",
+            "write-char",
+            "newline",
+            "flush-output-port",
+            "read-line",
+            "read-char",
+            "peek-char",
+            "char-ready?",
+            "current-input-port",
+            "current-output-port",
+            "current-error-port",
+            "open-input-string",
+            "open-output-string",
+            "get-output-string",
+            "close-port",
+            "close-input-port",
+            "close-output-port",
+            "eof-object",
+            "eof-object?",
+            "port?",
+            "input-port?",
+            "output-port?",
+            "call-with-port",
+            "values",
+            "call-with-values",
+            "make-parameter",
+        ],
+    ),
+    (
+        "(scheme char)",
+        &[
+            "string-upcase",
+            "string-downcase",
+            "string-ci=?",
+            "string-ci<?",
+            "string-ci>?",
+            "string-ci<=?",
+            "string-ci>=?",
+        ],
+    ),
+    ("(scheme write)", &["display", "write"]),
+    (
+        "(scheme eval)",
+        &[
+            "eval",
+            "environment",
+            "scheme-report-environment",
+            "null-environment",
+        ],
+    ),
+    ("(scheme repl)", &["interaction-environment"]),
+    ("(scheme read)", &["read"]),
+    (
+        "(scheme file)",
+        &[
+            "open-input-file",
+            "open-output-file",
+            "call-with-input-file",
+            "call-with-output-file",
+        ],
+    ),
+    ("(scheme lazy)", &["force", "make-promise", "promise?"]),
+    ("(scheme case-lambda)", &[]),
+    ("(scheme load)", &["load"]),
+    (
+        "(srfi 1)",
+        &[
+            "iota",
+            "last-pair",
+            "filter",
+            "reduce",
+            "fold",
+            "fold-right",
+            "delete",
+        ],
+    ),
+    (
+        "(srfi 41)",
+        &[
+            "stream-null",
+            "stream-null?",
+            "stream-pair?",
+            "stream?",
+            "stream-car",
+            "stream-cdr",
+            "stream-take",
+            "stream->list",
+            "list->stream",
+        ],
+    ),
+    (
+        "(srfi 69)",
+        &[
+            "make-hash-table",
+            "hash-table?",
+            "hash-table-ref",
+            "hash-table-ref/default",
+            "hash-table-set!",
+            "hash-table-delete!",
+            "hash-table-exists?",
+            "hash-table-count",
+            "hash-table-size",
+            "hash-table-keys",
+            "hash-table-values",
+            "hash-table->alist",
+            "hash-table-update!",
+            "hash-table-update!/default",
+            "hash-table-walk",
+        ],
+    ),
+    ("(srfi 180)", &["json-read", "json-write"]),
+];
+
+/// The libraries known to an interpreter and where to look for more.
+pub(super) struct Libraries {
+    loaded: HashMap<String, Exports>,
+    /// libraries whose definition is being loaded, to report import cycles
+    loading: Vec<String>,
+    /// directories searched for `.sld` files, after the directory of the current file
+    pub(super) search_path: Vec<PathBuf>,
+    /// directories of the files being loaded, innermost last
+    pub(super) source_dirs: Vec<PathBuf>,
+}
+
+impl Default for Libraries {
+    fn default() -> Self {
+        Libraries {
+            loaded: HashMap::new(),
+            loading: Vec::new(),
+            search_path: vec![PathBuf::from(".")],
+            source_dirs: Vec::new(),
+        }
+    }
+}
+
+impl Libraries {
+    /// Where `file` is looked up from the file currently being loaded.
+    fn resolve(&self, file: &str) -> PathBuf {
+        match self.source_dirs.last() {
+            Some(dir) => dir.join(file),
+            None => PathBuf::from(file),
+        }
+    }
+
+    fn candidates(&self, name: &[LispVal]) -> Vec<PathBuf> {
+        let mut relative = name
+            .iter()
+            .map(|part| part.to_string())
+            .collect::<PathBuf>();
+        relative.set_extension("sld");
+        self.source_dirs
+            .last()
+            .into_iter()
+            .chain(&self.search_path)
+            .map(|dir| dir.join(&relative))
+            .collect()
+    }
+}
+
+/// `(foo bar)`, made of identifiers and non-negative integers.
+fn library_name(v: &LispVal) -> Result<(String, Vec<LispVal>), String> {
+    match v {
+        LispVal::List(parts)
+            if !parts.is_empty()
+                && parts
+                    .iter()
+                    .all(|p| matches!(p, LispVal::Atom(_) | LispVal::Integer(0..))) =>
+        {
+            Ok((v.to_string(), parts.clone()))
+        }
+        _ => Err(format!("{} is not a library name", v)),
+    }
+}
+
+fn identifier(v: &LispVal, who: &str) -> Result<Symbol, String> {
+    match v {
+        LispVal::Atom(s) => Ok(*s),
+        _ => Err(format!("{}: {} is not an identifier", who, v)),
+    }
+}
+
+fn find_library(interpreter: &mut Interpreter, name: &LispVal) -> Result<Exports, String> {
+    let (key, parts) = library_name(name)?;
+    if let Some(exports) = interpreter.libraries.loaded.get(&key) {
+        return Ok(exports.clone());
+    }
+    if let Some((_, names)) = BUILTIN_LIBRARIES.iter().find(|(n, _)| *n == key) {
+        let exports = names
+            .iter()
+            .map(|name| {
+                interpreter
+                    .builtins
+                    .lookup(*name)
+                    .map(|v| (Symbol::intern(name), v))
+                    .ok_or(format!("{} exports unknown primitive {}", key, name))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let exports = Rc::new(exports);
+        interpreter.libraries.loaded.insert(key, exports.clone());
+        return Ok(exports);
+    }
+    if interpreter.libraries.loading.contains(&key) {
+        return Err(format!("library {} imports itself", key));
+    }
+    let path = interpreter
+        .libraries
+        .candidates(&parts)
+        .into_iter()
+        .map(|p| interpreter.profile.locate(&p))
+        .find(|p| p.is_file())
+        .ok_or(format!("unknown library {}", key))?;
+    let path = interpreter.profile.readable("import", &path)?;
+    interpreter.libraries.loading.push(key.clone());
+    let result = interpreter.load_file(&path);
+    interpreter.libraries.loading.pop();
+    result?;
+    interpreter
+        .libraries
+        .loaded
+        .get(&key)
+        .cloned()
+        .ok_or(format!(
+            "{} does not define library {}",
+            path.display(),
+            key
+        ))
+}
+
+/// The bindings denoted by an import set: a library name, or one of `only`, `except`,
+/// `prefix` and `rename` applied to an import set.
+fn import_set(
+    interpreter: &mut Interpreter,
+    set: &LispVal,
+) -> Result<Vec<(Symbol, LispVal)>, String> {
+    let LispVal::List(v) = set else {
+        return Err(format!("import: {} is not an import set", set));
+    };
+    let (modifier, inner, args) = match v.as_slice() {
+        [LispVal::Atom(m), inner, args @ ..]
+            if ["only", "except", "prefix", "rename"].contains(&m.name()) =>
+        {
+            (m.name(), inner, args)
+        }
+        _ => return Ok(find_library(interpreter, set)?.to_vec()),
+    };
+    let mut bindings = import_set(interpreter, inner)?;
+    match modifier {
+        "only" | "except" => {
+            let names = args
+                .iter()
+                .map(|a| identifier(a, modifier))
+                .collect::<Result<Vec<Symbol>, String>>()?;
+            if let Some(missing) = names
+                .iter()
+                .find(|n| !bindings.iter().any(|(b, _)| b == *n))
+            {
+                return Err(format!("{}: {} is not exported", modifier, missing));
+            }
+            bindings.retain(|(name, _)| names.contains(name) == (modifier == "only"));
+        }
+        "prefix" => {
+            let prefix = match args {
+                [LispVal::Atom(p)] => p.name().to_string(),
+                // `b:` reads as a keyword
+                [LispVal::Keyword(k)] => format!("{}:", k),
+                _ => return Err("prefix expects an import set and an identifier".to_string()),
+            };
+            for (name, _) in bindings.iter_mut() {
+                *name = Symbol::intern(&format!("{}{}", prefix, name));
+            }
+        }
+        _ => {
+            for rename in args {
+                let LispVal::List(pair) = rename else {
+                    return Err(format!("rename: {} is not a pair of identifiers", rename));
+                };
+                let [from, to] = pair.as_slice() else {
+                    return Err(format!("rename: {} is not a pair of identifiers", rename));
+                };
+                let (from, to) = (identifier(from, "rename")?, identifier(to, "rename")?);
+                let binding = bindings
+                    .iter_mut()
+                    .find(|(name, _)| *name == from)
+                    .ok_or(format!("rename: {} is not exported", from))?;
+                binding.0 = to;
+            }
+        }
+    }
+    Ok(bindings)
+}
+
+/// `(import import-set ...)` binds the imported names in the current environment.
+pub(super) fn import(interpreter: &mut Interpreter, sets: &[LispVal]) -> Result<LispVal, String> {
+    for set in sets {
+        for (name, value) in import_set(interpreter, set)? {
+            interpreter.env.new_binding(name, value);
+        }
+    }
+    Ok(LispVal::Unspecified)
+}
+
+/// `(environment import-set ...)` is a fresh environment holding the imported bindings.
+pub(super) fn environment(
+    interpreter: &mut Interpreter,
+    sets: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let sets = sets.iter().map(LispVal::to_code).collect::<Vec<_>>();
+    let outer = std::mem::take(&mut interpreter.env);
+    let result = import(interpreter, &sets);
+    let env = std::mem::replace(&mut interpreter.env, outer);
+    result.map(|_| LispVal::Environment(env))
+}
+
+/// `(include "file" ...)` evaluates the forms of the files as if they were written
+/// in place of the `include`.
+pub(super) fn include(interpreter: &mut Interpreter, files: &[LispVal]) -> Result<LispVal, String> {
+    let mut value = LispVal::Unspecified;
+    for file in files {
+        let LispVal::String(file) = file else {
+            return Err(format!("include: {} is not a file name", file));
+        };
+        let path = interpreter.libraries.resolve(&file.to_string());
+        let path = interpreter.profile.readable("include", &path)?;
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| format!("include: {}: {}", path.display(), e))?;
+        value = interpreter.eval_source(&source)?;
+    }
+    Ok(value)
+}
+
+/// `(define-library name declaration ...)`, where the declarations are `export`,
+/// `import`, `begin`, `include` and `include-library-declarations`. The body sees
+/// only what it imports.
+pub(super) fn define_library(
+    interpreter: &mut Interpreter,
+    v: &[LispVal],
+) -> Result<LispVal, String> {
+    let (name, declarations) = v.split_first().ok_or("define-library expects a name")?;
+    let (key, _) = library_name(name)?;
+    let outer = std::mem::take(&mut interpreter.env);
+    let mut exports = Vec::new();
+    let result = library_declarations(interpreter, declarations, &mut exports);
+    let env = std::mem::replace(&mut interpreter.env, outer);
+    result?;
+    let exports = exports
+        .into_iter()
+        .map(|(internal, external)| {
+            env.lookup(internal)
+                .map(|value| (external, value))
+                .ok_or(format!("{} exports unbound {}", key, internal))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    interpreter.libraries.loaded.insert(key, Rc::new(exports));
+    Ok(LispVal::Unspecified)
+}
+
+/// Evaluates library declarations in the library's environment, collecting the
+/// exports as `(internal, external)` names.
+fn library_declarations(
+    interpreter: &mut Interpreter,
+    declarations: &[LispVal],
+    exports: &mut Vec<(Symbol, Symbol)>,
+) -> Result<(), String> {
+    for declaration in declarations {
+        let Some((LispVal::Atom(kind), args)) = (match declaration {
+            LispVal::List(d) => d.split_first(),
+            _ => None,
+        }) else {
+            return Err(format!("define-library: bad declaration {}", declaration));
+        };
+        match kind.name() {
+            "export" => {
+                for spec in args {
+                    exports.push(match spec {
+                        LispVal::Atom(s) => (*s, *s),
+                        LispVal::List(r) => match r.as_slice() {
+                            [LispVal::Atom(k), from, to] if k == "rename" => {
+                                (identifier(from, "export")?, identifier(to, "export")?)
+                            }
+                            _ => return Err(format!("export: bad export spec {}", spec)),
+                        },
+                        _ => return Err(format!("export: bad export spec {}", spec)),
+                    });
+                }
+            }
+            "import" => {
+                import(interpreter, args)?;
+            }
+            "begin" => {
+                for form in args {
+                    interpreter.eval(form)?;
+                }
+            }
+            "include" => {
+                include(interpreter, args)?;
+            }
+            "include-library-declarations" => {
+                for file in args {
+                    let LispVal::String(file) = file else {
+                        return Err(format!("include-library-declarations: bad file {}", file));
+                    };
+                    let path = interpreter.libraries.resolve(&file.to_string());
+                    let path = interpreter
+                        .profile
+                        .readable("include-library-declarations", &path)?;
+                    let source = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+                    let mut parser =
+                        Parser::with_max_nesting(&source, interpreter.meter.max_depth());
+                    let mut included = Vec::new();
+                    while let Some(form) = parser.read().map_err(|e| e.to_string())? {
+                        included.push(form);
+                    }
+                    library_declarations(interpreter, &included, exports)?;
+                }
+            }
+            _ => return Err(format!("define-library: unknown declaration {}", kind)),
+        }
+    }
+    Ok(())
+}
+
+/// `(load "file")` evaluates the forms of a file in the top-level environment.
+pub(super) fn load(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [LispVal::String(file)] = v.as_slice() else {
+        return Err("load expects a file name".to_string());
+    };
+    let path = interpreter.libraries.resolve(&file.to_string());
+    let path = interpreter.profile.readable("load", &path)?;
+    interpreter.load_file(&path)?;
+    Ok(LispVal::Unspecified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_libraries_are_bound() {
+        let mut interpreter = Interpreter::new();
+        for (name, _) in BUILTIN_LIBRARIES {
+            let name = Parser::new(name).parse();
+            assert!(find_library(&mut interpreter, &name).is_ok(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use super::gc;
+
+/// Bounds on the resources evaluation may use, for running untrusted programs. Each
+/// is unlimited when `None`.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// how many evaluation steps may be taken; the virtual machine counts procedure
+    /// calls instead
+    pub fuel: Option<u64>,
+    /// how deeply evaluations may nest, which bounds the Rust stack they use. A level
+    /// takes up to about 2.5 KiB of stack in release builds and 10 KiB in debug builds,
+    /// so in release builds 3000 is safe on an 8 MiB main thread and 700 on the 2 MiB
+    /// of a spawned thread. It also bounds how deeply lists may nest in the code and
+    /// JSON read and in the JSON written, as analyzing code and converting JSON recurse
+    pub max_depth: Option<usize>,
+    /// how many bytes the heap of the thread may hold while the interpreter runs,
+    /// counting those of other interpreters on the thread. Objects are charged their
+    /// size, and strings and lists the memory of their elements
+    pub max_heap: Option<usize>,
+    /// when evaluation must have finished
+    pub deadline: Option<Instant>,
+}
+
+/// Why evaluation was stopped. The error it fails with is the message of one of
+/// these, which `from_error` tells apart from the errors of the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Fuel,
+    Depth,
+    Heap,
+    Deadline,
+    Interrupted,
+}
+
+impl LimitExceeded {
+    const ALL: [LimitExceeded; 5] = [
+        LimitExceeded::Fuel,
+        LimitExceeded::Depth,
+        LimitExceeded::Heap,
+        LimitExceeded::Deadline,
+        LimitExceeded::Interrupted,
+    ];
+
+    pub fn from_error(error: &str) -> Option<LimitExceeded> {
+        Self::ALL
+            .into_iter()
+            .find(|limit| limit.to_string() == error)
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LimitExceeded::Fuel => "out of fuel",
+            LimitExceeded::Depth => "maximum recursion depth exceeded",
+            LimitExceeded::Heap => "heap limit exceeded",
+            LimitExceeded::Deadline => "deadline exceeded",
+            LimitExceeded::Interrupted => "interrupted",
+        })
+    }
+}
+
+/// Stops the evaluation of an interpreter from any thread.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Interrupts the evaluation running, or the next one if none is.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The deadline, the heap and interrupts are checked once every this many steps, and
+/// the deadline and interrupts once every this many calls to `poll`.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// The deadline and interrupt flag of the interpreter running on this thread.
+#[derive(Clone)]
+pub(super) struct Watch {
+    deadline: Option<Instant>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Watch {
+    fn check(&self) -> Result<(), String> {
+        if self.interrupted.swap(false, Ordering::Relaxed) {
+            return Err(LimitExceeded::Interrupted.to_string());
+        }
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(LimitExceeded::Deadline.to_string());
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static WATCH: RefCell<Option<Watch>> = const { RefCell::new(None) };
+    static POLLS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Makes `poll` check `watch`, returning the watch it replaces.
+pub(super) fn set_watch(watch: Option<Watch>) -> Option<Watch> {
+    WATCH.with(|w| w.replace(watch))
+}
+
+/// Stops primitives that loop over long lists or strings when the deadline of the
+/// interpreter running has passed or it was interrupted. Cheap enough to call once per
+/// element, as it only looks once every `CHECK_INTERVAL` calls.
+pub(super) fn poll() -> Result<(), String> {
+    let polls = POLLS.with(|p| {
+        p.set(p.get().wrapping_add(1));
+        p.get()
+    });
+    if !polls.is_multiple_of(CHECK_INTERVAL) {
+        return Ok(());
+    }
+    WATCH.with(|w| match &*w.borrow() {
+        Some(watch) => watch.check(),
+        None => Ok(()),
+    })
+}
+
+/// Keeps track of what evaluation uses of its limits.
+#[derive(Default)]
+pub(super) struct Meter {
+    limits: Limits,
+    /// the fuel left
+    fuel: Option<u64>,
+    depth: usize,
+    steps: u64,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Meter {
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.fuel = limits.fuel;
+        self.limits = limits;
+    }
+
+    pub fn max_heap(&self) -> Option<usize> {
+        self.limits.max_heap
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.limits.max_depth
+    }
+
+    /// What `poll` checks while this meter's evaluation runs.
+    pub fn watch(&self) -> Watch {
+        Watch {
+            deadline: self.limits.deadline,
+            interrupted: self.interrupted.clone(),
+        }
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupted.clone())
+    }
+
+    /// Charges an evaluation step.
+    pub fn tick(&mut self) -> Result<(), String> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(LimitExceeded::Fuel.to_string());
+            }
+            *fuel -= 1;
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.watch().check()?;
+        gc::reserve(0)
+    }
+
+    /// Enters a nested evaluation, returning the depth to `leave` it for.
+    pub fn enter(&mut self) -> Result<usize, String> {
+        if self.limits.max_depth.is_some_and(|max| self.depth >= max) {
+            return Err(LimitExceeded::Depth.to_string());
+        }
+        self.depth += 1;
+        Ok(self.depth - 1)
+    }
+
+    pub fn leave(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+}
@@ -0,0 +1,356 @@
+use crate::parser::parser::LispVal;
+
+use super::{
+    equivalence::{equal, eqv},
+    gc, pair, Interpreter,
+};
+
+fn list_arg(v: &[LispVal], i: usize, who: &str) -> Result<Vec<LispVal>, String> {
+    v.get(i)
+        .and_then(pair::items)
+        .ok_or(format!("{} expects a list as argument {}", who, i + 1))
+}
+
+fn index_arg(v: &[LispVal], i: usize, who: &str) -> Result<usize, String> {
+    v.get(i)
+        .and_then(LispVal::to_integer)
+        .and_then(|k| usize::try_from(k).ok())
+        .ok_or(format!(
+            "{} expects a non-negative integer as argument {}",
+            who,
+            i + 1
+        ))
+}
+
+pub(super) fn is_null(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("null? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(matches!(x, LispVal::List(l) if l.is_empty())))
+}
+
+pub(super) fn is_pair(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("pair? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(pair::is_pair(x)))
+}
+
+pub(super) fn is_list(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("list? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(pair::items(x).is_some()))
+}
+
+pub(super) fn list(v: Vec<LispVal>) -> Result<LispVal, String> {
+    pair::try_list(v)
+}
+
+pub(super) fn length(v: Vec<LispVal>) -> Result<LispVal, String> {
+    Ok(LispVal::Integer(list_arg(&v, 0, "length")?.len() as i64))
+}
+
+/// The result shares the last argument, which need not be a list.
+pub(super) fn append(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let Some((last, lists)) = v.split_last() else {
+        return Ok(LispVal::List(Vec::new()));
+    };
+    let mut result = Vec::new();
+    for i in 0..lists.len() {
+        result.extend(list_arg(lists, i, "append")?);
+    }
+    pair::try_list_with_tail(result, last.clone())
+}
+
+pub(super) fn reverse(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let l = list_arg(&v, 0, "reverse")?;
+    pair::try_list(l.into_iter().rev().collect())
+}
+
+/// The tail is shared with the list, not copied.
+pub(super) fn list_tail(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let k = index_arg(&v, 1, "list-tail")?;
+    pair::tail(&v[0], k).ok_or(format!("list-tail: index {} is out of range", k))
+}
+
+pub(super) fn list_ref(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let l = list_arg(&v, 0, "list-ref")?;
+    let k = index_arg(&v, 1, "list-ref")?;
+    l.get(k)
+        .cloned()
+        .ok_or(format!("list-ref: index {} is out of range", k))
+}
+
+pub(super) fn list_copy(v: Vec<LispVal>) -> Result<LispVal, String> {
+    pair::try_list(list_arg(&v, 0, "list-copy")?)
+}
+
+pub(super) fn last_pair(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let mut last = match v.first() {
+        Some(LispVal::List(l)) if !l.is_empty() => {
+            return Ok(LispVal::List(vec![l[l.len() - 1].clone()]))
+        }
+        Some(LispVal::Pair(p)) => p.clone(),
+        _ => return Err("last-pair expects a non-empty list".to_string()),
+    };
+    loop {
+        match last.cdr() {
+            LispVal::Pair(p) => last = p,
+            LispVal::List(l) if !l.is_empty() => {
+                return Ok(LispVal::List(vec![l[l.len() - 1].clone()]))
+            }
+            _ => return Ok(LispVal::Pair(last)),
+        }
+    }
+}
+
+/// Builds `set-car!`/`set-cdr!`. Quoted lists are constants, only the pairs made by
+/// running the program can be modified.
+pub(super) fn set_pair_field(
+    who: &'static str,
+    set: fn(&pair::Pair, LispVal),
+) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| match v.as_slice() {
+        [LispVal::Pair(p), _] if p.is_constant() => {
+            Err(format!("{}: cannot modify the constant {}", who, v[0]))
+        }
+        [LispVal::Pair(p), value] => {
+            set(p, value.clone());
+            Ok(LispVal::Unspecified)
+        }
+        [LispVal::List(l), _] if !l.is_empty() => {
+            Err(format!("{}: cannot modify the constant {}", who, v[0]))
+        }
+        [x, _] => Err(format!("{}: {} is not a pair", who, x)),
+        _ => Err(format!("{} expects 2 arguments", who)),
+    }
+}
+
+/// `(iota count [start [step]])`
+pub(super) fn iota(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let count = index_arg(&v, 0, "iota")?;
+    let integer = |i: usize, default: i64| match v.get(i) {
+        Some(x) => x
+            .to_integer()
+            .ok_or(format!("iota expects an integer as argument {}", i + 1)),
+        None => Ok(default),
+    };
+    let start = integer(1, 0)?;
+    let step = integer(2, 1)?;
+    pair::reserve(count)?;
+    let mut items = gc::vec_with_capacity(count)?;
+    let mut next = Some(start);
+    for _ in 0..count {
+        let i = next.ok_or("integer overflow")?;
+        items.push(LispVal::Integer(i));
+        next = i.checked_add(step);
+    }
+    pair::try_list(items)
+}
+
+/// The first tail of `l` whose car satisfies `matches`, or `#f`.
+fn find_tail(
+    l: &LispVal,
+    who: &str,
+    mut matches: impl FnMut(&LispVal) -> Result<bool, String>,
+) -> Result<LispVal, String> {
+    let mut rest = l.clone();
+    loop {
+        rest = match rest {
+            LispVal::Pair(p) if matches(&p.car())? => return Ok(LispVal::Pair(p)),
+            LispVal::Pair(p) => p.cdr(),
+            LispVal::List(items) => {
+                for (i, x) in items.iter().enumerate() {
+                    if matches(x)? {
+                        return Ok(LispVal::List(items[i..].to_vec()));
+                    }
+                }
+                return Ok(LispVal::Bool(false));
+            }
+            _ => return Err(format!("{} expects a list, but got {}", who, l)),
+        }
+    }
+}
+
+/// The car of an association list entry.
+fn entry_key(entry: &LispVal, who: &str, alist: &LispVal) -> Result<LispVal, String> {
+    match entry {
+        LispVal::Pair(p) => Ok(p.car()),
+        LispVal::List(l) if !l.is_empty() => Ok(l[0].clone()),
+        _ => Err(format!("{}: {} is not an association list", who, alist)),
+    }
+}
+
+/// Builds `memq`/`memv`, which return the sublist starting at the first match or `#f`.
+pub(super) fn mem(who: &'static str) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        let [x, l] = v.as_slice() else {
+            return Err(format!("{} expects 2 arguments", who));
+        };
+        find_tail(l, who, |y| Ok(eqv(x, y)))
+    }
+}
+
+/// Builds `assq`/`assv`, which return the first entry whose first element matches or `#f`.
+pub(super) fn ass(who: &'static str) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        let alist = list_arg(&v, 1, who)?;
+        for entry in alist {
+            if eqv(&v[0], &entry_key(&entry, who, &v[1])?) {
+                return Ok(entry);
+            }
+        }
+        Ok(LispVal::Bool(false))
+    }
+}
+
+/// Calls the optional comparison procedure, falling back to `equal?`.
+fn compare(
+    interpreter: &mut Interpreter,
+    who: &str,
+    procedure: Option<&LispVal>,
+    a: &LispVal,
+    b: &LispVal,
+) -> Result<bool, String> {
+    match procedure {
+        Some(p) => Ok(
+            interpreter.apply_procedure(who, p, &[a.clone(), b.clone()])? != LispVal::Bool(false),
+        ),
+        None => Ok(equal(a, b)),
+    }
+}
+
+/// `(member x list [compare])`
+pub(super) fn member(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (x, l) = (&v[0], &v[1]);
+    find_tail(l, "member", |y| {
+        compare(interpreter, "member", v.get(2), x, y)
+    })
+}
+
+/// `(assoc key alist [compare])`
+pub(super) fn assoc(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let alist = list_arg(&v, 1, "assoc")?;
+    for entry in alist {
+        let key = entry_key(&entry, "assoc", &v[1])?;
+        if compare(interpreter, "assoc", v.get(2), &v[0], &key)? {
+            return Ok(entry);
+        }
+    }
+    Ok(LispVal::Bool(false))
+}
+
+/// `(delete x list [compare])` removes every element equal to `x`.
+pub(super) fn delete(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let l = list_arg(&v, 1, "delete")?;
+    let mut result = Vec::new();
+    for x in l {
+        if !compare(interpreter, "delete", v.get(2), &v[0], &x)? {
+            result.push(x);
+        }
+    }
+    pair::try_list(result)
+}
+
+/// Transposes the list arguments of `map`-like procedures into the arguments of each call,
+/// stopping at the end of the shortest list.
+fn columns(lists: &[LispVal], who: &str) -> Result<Vec<Vec<LispVal>>, String> {
+    if lists.is_empty() {
+        return Err(format!("{} expects at least one list", who));
+    }
+    let lists = (0..lists.len())
+        .map(|i| list_arg(lists, i, who))
+        .collect::<Result<Vec<Vec<LispVal>>, String>>()?;
+    let len = lists.iter().map(|l| l.len()).min().unwrap_or(0);
+    Ok((0..len)
+        .map(|k| lists.iter().map(|l| l[k].clone()).collect())
+        .collect())
+}
+
+pub(super) fn map(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (f, lists) = v.split_first().ok_or("map expects a procedure")?;
+    columns(lists, "map")?
+        .iter()
+        .map(|args| interpreter.apply_procedure("map", f, args))
+        .collect::<Result<Vec<LispVal>, String>>()
+        .and_then(pair::try_list)
+}
+
+pub(super) fn for_each(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (f, lists) = v.split_first().ok_or("for-each expects a procedure")?;
+    for args in columns(lists, "for-each")? {
+        interpreter.apply_procedure("for-each", f, &args)?;
+    }
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn filter(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (Some(l), [f, _]) = (v.get(1).and_then(pair::items), v.as_slice()) else {
+        return Err("filter expects a procedure and a list".to_string());
+    };
+    let mut result = Vec::new();
+    for x in l {
+        if interpreter.apply_procedure("filter", f, std::slice::from_ref(&x))?
+            != LispVal::Bool(false)
+        {
+            result.push(x);
+        }
+    }
+    pair::try_list(result)
+}
+
+/// `(reduce f ridentity list)` is `(f e3 (f e2 e1))`, or `ridentity` for the empty list.
+pub(super) fn reduce(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (Some(l), [f, ridentity, _]) = (v.get(2).and_then(pair::items), v.as_slice()) else {
+        return Err("reduce expects a procedure, a default value and a list".to_string());
+    };
+    let Some((first, rest)) = l.split_first() else {
+        return Ok(ridentity.clone());
+    };
+    rest.iter().try_fold(first.clone(), |acc, x| {
+        interpreter.apply_procedure("reduce", f, &[x.clone(), acc])
+    })
+}
+
+/// `(fold kons knil list ...)` calls `(kons e ... acc)` from left to right.
+pub(super) fn fold(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [f, init, lists @ ..] = v.as_slice() else {
+        return Err("fold expects a procedure and an initial value".to_string());
+    };
+    columns(lists, "fold")?
+        .into_iter()
+        .try_fold(init.clone(), |acc, mut args| {
+            args.push(acc);
+            interpreter.apply_procedure("fold", f, &args)
+        })
+}
+
+/// `(fold-left f init list ...)` calls `(f acc e ...)` from left to right.
+pub(super) fn fold_left(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [f, init, lists @ ..] = v.as_slice() else {
+        return Err("fold-left expects a procedure and an initial value".to_string());
+    };
+    columns(lists, "fold-left")?
+        .into_iter()
+        .try_fold(init.clone(), |acc, args| {
+            interpreter.apply_procedure("fold-left", f, &[&[acc], args.as_slice()].concat())
+        })
+}
+
+/// `(fold-right f init list ...)` calls `(f e ... acc)` from right to left.
+pub(super) fn fold_right(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let [f, init, lists @ ..] = v.as_slice() else {
+        return Err("fold-right expects a procedure and an initial value".to_string());
+    };
+    columns(lists, "fold-right")?
+        .into_iter()
+        .rev()
+        .try_fold(init.clone(), |acc, mut args| {
+            args.push(acc);
+            interpreter.apply_procedure("fold-right", f, &args)
+        })
+}
@@ -1,4 +1,31 @@
-mod environment;
+pub(crate) mod analyzer;
+pub(crate) mod bytecode;
+pub(crate) mod environment;
+pub(crate) mod equivalence;
+pub(crate) mod gc;
+pub(crate) mod hash_table;
+pub(crate) mod json;
+mod library;
+pub(crate) mod limits;
+mod lists;
+pub(crate) mod native;
+pub(crate) mod pair;
+pub(crate) mod parameter;
+pub(crate) mod port;
+pub(crate) mod procedure;
+pub(crate) mod promise;
+pub(crate) mod record;
+pub(crate) mod sandbox;
+pub(crate) mod stream;
+pub(crate) mod strings;
+pub(crate) mod symbol;
+pub(crate) mod values;
+mod vm;
 
 pub mod interpreter;
-pub use interpreter::Interpreter;
+pub use crate::parser::parser::LispVal;
+pub use interpreter::{Backend, Interpreter};
+pub use json::{from_json, to_json};
+pub use limits::{InterruptHandle, LimitExceeded, Limits};
+pub use native::{FromLisp, IntoLisp, IntoLispArgs, NativeFn, NativeResult};
+pub use sandbox::Profile;
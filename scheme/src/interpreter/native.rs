@@ -0,0 +1,529 @@
+use std::{collections::HashMap, hash::Hash};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    hash_table::{Equivalence, HashTable},
+    pair,
+    procedure::Arity,
+    strings::LispString,
+};
+
+/// Rust types the arguments of native functions are converted to.
+pub trait FromLisp: Sized {
+    /// The type as it appears in error messages.
+    fn type_name() -> String;
+
+    /// `None` when `v` is not of this type.
+    fn from_lisp(v: &LispVal) -> Option<Self>;
+}
+
+/// Rust types native functions may return.
+pub trait IntoLisp {
+    fn into_lisp(self) -> LispVal;
+}
+
+impl FromLisp for LispVal {
+    fn type_name() -> String {
+        "any".to_string()
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        Some(v.clone())
+    }
+}
+
+impl IntoLisp for LispVal {
+    fn into_lisp(self) -> LispVal {
+        self
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty: $name:literal),*) => {$(
+        impl FromLisp for $t {
+            fn type_name() -> String {
+                $name.to_string()
+            }
+
+            fn from_lisp(v: &LispVal) -> Option<Self> {
+                v.to_integer().and_then(|i| <$t>::try_from(i).ok())
+            }
+        }
+    )*};
+}
+
+integer_conversions!(
+    i64: "integer",
+    i32: "integer",
+    u32: "non-negative integer",
+    usize: "non-negative integer"
+);
+
+impl IntoLisp for i64 {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Integer(self)
+    }
+}
+
+impl IntoLisp for i32 {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Integer(self.into())
+    }
+}
+
+impl IntoLisp for u32 {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Integer(self.into())
+    }
+}
+
+/// Sizes beyond `i64::MAX` saturate, no list or string gets that long.
+impl IntoLisp for usize {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Integer(i64::try_from(self).unwrap_or(i64::MAX))
+    }
+}
+
+/// Integers are the only numbers, so any integer can be taken as a float.
+impl FromLisp for f64 {
+    fn type_name() -> String {
+        "number".to_string()
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        v.to_integer().map(|i| i as f64)
+    }
+}
+
+impl FromLisp for bool {
+    fn type_name() -> String {
+        "boolean".to_string()
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        match v {
+            LispVal::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl IntoLisp for bool {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Bool(self)
+    }
+}
+
+impl FromLisp for char {
+    fn type_name() -> String {
+        "char".to_string()
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        v.as_char()
+    }
+}
+
+impl IntoLisp for char {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Char(self)
+    }
+}
+
+impl FromLisp for String {
+    fn type_name() -> String {
+        "string".to_string()
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        v.as_string().map(LispString::to_string)
+    }
+}
+
+impl IntoLisp for String {
+    fn into_lisp(self) -> LispVal {
+        LispVal::String(LispString::mutable(self.chars().collect()))
+    }
+}
+
+impl IntoLisp for &str {
+    fn into_lisp(self) -> LispVal {
+        self.to_string().into_lisp()
+    }
+}
+
+impl IntoLisp for () {
+    fn into_lisp(self) -> LispVal {
+        LispVal::Unspecified
+    }
+}
+
+impl<T: FromLisp> FromLisp for Vec<T> {
+    fn type_name() -> String {
+        format!("list of {}", T::type_name())
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        pair::items(v)?.iter().map(T::from_lisp).collect()
+    }
+}
+
+impl<T: IntoLisp> IntoLisp for Vec<T> {
+    fn into_lisp(self) -> LispVal {
+        pair::list(self.into_iter().map(IntoLisp::into_lisp).collect())
+    }
+}
+
+/// `None` is `#f`.
+impl<T: FromLisp> FromLisp for Option<T> {
+    fn type_name() -> String {
+        format!("{} or #f", T::type_name())
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        match v {
+            LispVal::Bool(false) => Some(None),
+            v => T::from_lisp(v).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLisp> IntoLisp for Option<T> {
+    fn into_lisp(self) -> LispVal {
+        self.map_or(LispVal::Bool(false), IntoLisp::into_lisp)
+    }
+}
+
+impl<K: FromLisp + Eq + Hash, V: FromLisp> FromLisp for HashMap<K, V> {
+    fn type_name() -> String {
+        format!("hash table of {} to {}", K::type_name(), V::type_name())
+    }
+
+    fn from_lisp(v: &LispVal) -> Option<Self> {
+        let LispVal::HashTable(table) = v else {
+            return None;
+        };
+        table
+            .entries()
+            .iter()
+            .map(|(k, v)| Some((K::from_lisp(k)?, V::from_lisp(v)?)))
+            .collect()
+    }
+}
+
+/// An `equal?` hash table.
+impl<K: IntoLisp, V: IntoLisp> IntoLisp for HashMap<K, V> {
+    fn into_lisp(self) -> LispVal {
+        let table = HashTable::new(Equivalence::Equal);
+        for (k, v) in self {
+            table
+                .insert(k.into_lisp(), v.into_lisp())
+                .expect("equal? tables take any key");
+        }
+        LispVal::HashTable(table)
+    }
+}
+
+/// Tuples are lists of as many elements.
+macro_rules! tuple_conversions {
+    ($($t:ident $v:ident $i:tt),*) => {
+        impl<$($t: FromLisp),*> FromLisp for ($($t,)*) {
+            fn type_name() -> String {
+                format!("list ({})", [$($t::type_name()),*].join(" "))
+            }
+
+            fn from_lisp(v: &LispVal) -> Option<Self> {
+                match pair::items(v)?.as_slice() {
+                    [$($v),*] => Some(($($t::from_lisp($v)?,)*)),
+                    _ => None,
+                }
+            }
+        }
+
+        impl<$($t: IntoLisp),*> IntoLisp for ($($t,)*) {
+            fn into_lisp(self) -> LispVal {
+                pair::list(vec![$(self.$i.into_lisp()),*])
+            }
+        }
+    };
+}
+
+tuple_conversions!(A a 0, B b 1);
+tuple_conversions!(A a 0, B b 1, C c 2);
+tuple_conversions!(A a 0, B b 1, C c 2, D d 3);
+
+/// What native functions may return: a value, or a `Result` whose error becomes the
+/// error of the call.
+pub trait NativeResult {
+    fn into_result(self) -> Result<LispVal, String>;
+}
+
+impl<T: IntoLisp> NativeResult for T {
+    fn into_result(self) -> Result<LispVal, String> {
+        Ok(self.into_lisp())
+    }
+}
+
+impl<T: IntoLisp> NativeResult for Result<T, String> {
+    fn into_result(self) -> Result<LispVal, String> {
+        self.map(IntoLisp::into_lisp)
+    }
+}
+
+/// Floats are returned as integers, the only numbers there are, and are an error
+/// unless they are integral and within the range of `i64`.
+impl NativeResult for f64 {
+    fn into_result(self) -> Result<LispVal, String> {
+        // -2^63 is exact as a float and 2^63 is the first float past `i64::MAX`
+        if self.fract() == 0.0 && (i64::MIN as f64..-(i64::MIN as f64)).contains(&self) {
+            Ok(LispVal::Integer(self as i64))
+        } else {
+            Err(format!("{} is not an integer", self))
+        }
+    }
+}
+
+impl NativeResult for Result<f64, String> {
+    fn into_result(self) -> Result<LispVal, String> {
+        self.and_then(NativeResult::into_result)
+    }
+}
+
+/// The arguments Rust code calls Scheme procedures with: tuples of up to six values
+/// that implement `IntoLisp`, or a `Vec` of values.
+pub trait IntoLispArgs {
+    fn into_lisp_args(self) -> Vec<LispVal>;
+}
+
+impl IntoLispArgs for Vec<LispVal> {
+    fn into_lisp_args(self) -> Vec<LispVal> {
+        self
+    }
+}
+
+macro_rules! lisp_args {
+    ($(($($t:ident $i:tt),*)),*) => {$(
+        impl<$($t: IntoLisp),*> IntoLispArgs for ($($t,)*) {
+            fn into_lisp_args(self) -> Vec<LispVal> {
+                vec![$(self.$i.into_lisp()),*]
+            }
+        }
+    )*};
+}
+
+lisp_args!(
+    (),
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+);
+
+/// Rust closures callable from Scheme, taking up to six arguments that implement
+/// `FromLisp`. `Args` is the tuple of the argument types.
+pub trait NativeFn<Args>: 'static {
+    fn arity(&self) -> Arity;
+
+    /// Converts the arguments and calls the function; `name` is what it is bound to.
+    fn call(&self, name: &str, args: &[LispVal]) -> Result<LispVal, String>;
+}
+
+fn argument<T: FromLisp>(name: &str, args: &[LispVal], i: usize) -> Result<T, String> {
+    T::from_lisp(&args[i]).ok_or_else(|| {
+        format!(
+            "{} expects argument {} of type {}, but got {}",
+            name,
+            i + 1,
+            T::type_name(),
+            args[i]
+        )
+    })
+}
+
+macro_rules! native_fns {
+    ($(($($t:ident $i:tt),*)),*) => {$(
+        impl<Func, R, $($t: FromLisp),*> NativeFn<($($t,)*)> for Func
+        where
+            Func: Fn($($t),*) -> R + 'static,
+            R: NativeResult,
+        {
+            fn arity(&self) -> Arity {
+                Arity::exactly(0 $(+ { let _ = $i; 1 })*)
+            }
+
+            fn call(&self, name: &str, args: &[LispVal]) -> Result<LispVal, String> {
+                self.arity().check(name, args.len())?;
+                self($(argument::<$t>(name, args, $i)?),*).into_result()
+            }
+        }
+    )*};
+}
+
+native_fns!(
+    (),
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Backend, Interpreter};
+    use super::*;
+
+    #[test]
+    fn test_define_native() {
+        let mut interpreter = Interpreter::new();
+        interpreter.define_native("http-status", |code: i64, msg: String| -> bool {
+            code == 200 && msg == "OK"
+        });
+        interpreter.define_native("sum", |v: Vec<i64>| v.iter().sum::<i64>());
+        interpreter.define_native("lookup", |table: HashMap<String, i64>, key: String| {
+            table.get(&key).copied()
+        });
+        interpreter.define_native("swap", |(a, b): (i64, String)| (b, a));
+        interpreter.define_native("checked-div", |a: i64, b: i64| match b {
+            0 => Err("checked-div: division by zero".to_string()),
+            b => Ok(a / b),
+        });
+        interpreter.define_native("answer", || 42);
+        for (source, expected) in [
+            ("(http-status 200 \"OK\")", "#t"),
+            ("(sum (list 1 2 3))", "6"),
+            ("(sum '())", "0"),
+            (
+                "(define t (make-equal-hash-table)) (hash-table-set! t \"a\" 1) (list (lookup t \"a\") (lookup t \"b\"))",
+                "(1 #f)",
+            ),
+            ("(swap '(1 \"one\"))", "(\"one\" 1)"),
+            ("(checked-div 7 2)", "3"),
+            ("(map answer '())", "()"),
+            ("(answer)", "42"),
+        ] {
+            assert_eq!(
+                interpreter.interpret(source).map(|v| v.to_string()),
+                Ok(expected.to_string())
+            );
+        }
+        for (source, error) in [
+            (
+                "(http-status \"200\" \"OK\")",
+                "http-status expects argument 1 of type integer, but got \"200\"",
+            ),
+            (
+                "(http-status 200)",
+                "http-status expects 2 arguments, but got 1",
+            ),
+            (
+                "(sum '(1 a))",
+                "sum expects argument 1 of type list of integer, but got (1 a)",
+            ),
+            (
+                "(swap '(1 2))",
+                "swap expects argument 1 of type list (integer string), but got (1 2)",
+            ),
+            ("(checked-div 1 0)", "checked-div: division by zero"),
+        ] {
+            assert_eq!(interpreter.interpret(source), Err(error.to_string()));
+        }
+        interpreter.set_backend(Backend::Vm);
+        assert_eq!(
+            interpreter.interpret("(apply sum (list (list 1 2)))"),
+            Ok(LispVal::Integer(3))
+        );
+        interpreter.define_native("half", |x: f64| x / 2.0);
+        assert_eq!(interpreter.interpret("(half 6)"), Ok(LispVal::Integer(3)));
+        assert_eq!(
+            interpreter.interpret("(half 5)"),
+            Err("2.5 is not an integer".to_string())
+        );
+        // errors name the native procedure, not the one calling it
+        for backend in [Backend::TreeWalker, Backend::Analyzer, Backend::Vm] {
+            interpreter.set_backend(backend);
+            for (source, error) in [
+                ("(map answer '(1))", "answer expects 0 arguments, but got 1"),
+                (
+                    "(for-each half '(1) '(2))",
+                    "half expects 1 arguments, but got 2",
+                ),
+                ("(apply half '())", "half expects 1 arguments, but got 0"),
+                (
+                    "(map sum '(1))",
+                    "sum expects argument 1 of type list of integer, but got 1",
+                ),
+            ] {
+                assert_eq!(interpreter.interpret(source), Err(error.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_conversions() {
+        let table = HashMap::from([("a".to_string(), vec![1, 2])]).into_lisp();
+        assert_eq!(
+            HashMap::<String, Vec<i64>>::from_lisp(&table),
+            Some(HashMap::from([("a".to_string(), vec![1, 2])]))
+        );
+        assert_eq!(Option::<i64>::from_lisp(&LispVal::Bool(false)), Some(None));
+        assert_eq!(u32::from_lisp(&LispVal::Integer(-1)), None);
+        assert_eq!(f64::from_lisp(&LispVal::Integer(2)), Some(2.0));
+        assert_eq!((-4.0).into_result(), Ok(LispVal::Integer(-4)));
+        assert_eq!(
+            (i64::MIN as f64).into_result(),
+            Ok(LispVal::Integer(i64::MIN))
+        );
+        for x in [0.5, f64::NAN, f64::INFINITY, -(i64::MIN as f64)] {
+            assert_eq!(x.into_result(), Err(format!("{} is not an integer", x)));
+        }
+        assert_eq!(
+            Err::<f64, _>("failed".to_string()).into_result(),
+            Err("failed".to_string())
+        );
+        assert_eq!(Some('x').into_lisp(), LispVal::Char('x'));
+        assert_eq!(().into_lisp(), LispVal::Unspecified);
+    }
+
+    #[test]
+    fn test_call_and_get() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+                 (define (discount total tier) (if (and (> total 100) (string=? tier \"gold\")) 10 0))
+                 (define primes '(2 3 5))",
+            )
+            .unwrap();
+        assert_eq!(interpreter.call::<i64>("fib", (10,)), Ok(55));
+        assert_eq!(interpreter.call::<i64>("discount", (150, "gold")), Ok(10));
+        assert_eq!(
+            interpreter.call::<i64>(
+                "discount",
+                vec![LispVal::Integer(150), "silver".into_lisp()]
+            ),
+            Ok(0)
+        );
+        assert_eq!(interpreter.get::<Vec<i64>>("primes"), Ok(vec![2, 3, 5]));
+        assert_eq!(
+            interpreter.call::<i64>("fib", ()),
+            Err("fib expects 1 arguments, but got 0".to_string())
+        );
+        assert_eq!(
+            interpreter.call::<String>("fib", (10,)),
+            Err("fib returned 55, which is not of type string".to_string())
+        );
+        assert_eq!(
+            interpreter.get::<i64>("primes"),
+            Err("primes is (2 3 5), which is not of type integer".to_string())
+        );
+        assert_eq!(
+            interpreter.call::<i64>("missing", ()),
+            Err("unknown atom missing".to_string())
+        );
+    }
+}
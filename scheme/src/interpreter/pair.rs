@@ -0,0 +1,448 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Formatter},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use crate::parser::parser::{Displayed, LispVal};
+
+use super::{
+    gc::{self, Gc, Trace, Tracer},
+    limits,
+};
+
+/// A mutable pair made by `cons`, the procedures building lists and quoting. Lists in
+/// source code are vectors instead, which `car` and `cdr` also accept.
+pub struct Pair {
+    car: RefCell<LispVal>,
+    cdr: RefCell<LispVal>,
+    /// whether the pair is quoted data, which may not be modified
+    constant: bool,
+}
+
+impl Pair {
+    pub fn is_constant(&self) -> bool {
+        self.constant
+    }
+
+    pub fn car(&self) -> LispVal {
+        self.car.borrow().clone()
+    }
+
+    pub fn cdr(&self) -> LispVal {
+        self.cdr.borrow().clone()
+    }
+
+    pub fn set_car(&self, v: LispVal) {
+        *self.car.borrow_mut() = v;
+    }
+
+    pub fn set_cdr(&self, v: LispVal) {
+        *self.cdr.borrow_mut() = v;
+    }
+}
+
+impl Trace for Pair {
+    fn trace(&self, tracer: &mut Tracer) {
+        match (self.car.try_borrow(), self.cdr.try_borrow()) {
+            (Ok(car), Ok(cdr)) => {
+                car.trace(tracer);
+                cdr.trace(tracer);
+            }
+            _ => tracer.fail(),
+        }
+    }
+
+    fn clear(&self) {
+        self.set_car(LispVal::Unspecified);
+        self.set_cdr(LispVal::Unspecified);
+    }
+}
+
+/// Frees the values this pair holds in a loop rather than recursively, so that neither
+/// long nor deeply nested lists overflow the stack.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        gc::release([
+            std::mem::replace(self.car.get_mut(), LispVal::Unspecified),
+            std::mem::replace(self.cdr.get_mut(), LispVal::Unspecified),
+        ]);
+    }
+}
+
+/// Printed like `write` does, which terminates even for circular lists.
+impl Debug for Pair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} . {}", self.car(), self.cdr())
+    }
+}
+
+pub fn cons(car: LispVal, cdr: LispVal) -> LispVal {
+    make(car, cdr, false)
+}
+
+fn make(car: LispVal, cdr: LispVal, constant: bool) -> LispVal {
+    LispVal::Pair(Gc::new(Pair {
+        car: RefCell::new(car),
+        cdr: RefCell::new(cdr),
+        constant,
+    }))
+}
+
+/// Whether `v` is a pair, which includes the non-empty lists written in the source.
+pub fn is_pair(v: &LispVal) -> bool {
+    match v {
+        LispVal::Pair(_) => true,
+        LispVal::List(l) => !l.is_empty(),
+        _ => false,
+    }
+}
+
+/// A list of fresh pairs.
+pub fn list(items: Vec<LispVal>) -> LispVal {
+    list_with_tail(items, LispVal::List(Vec::new()))
+}
+
+/// `(item ... . tail)`
+pub fn list_with_tail(items: Vec<LispVal>, tail: LispVal) -> LispVal {
+    items.into_iter().rev().fold(tail, |rest, x| cons(x, rest))
+}
+
+/// `list_with_tail` for quoted data, whose pairs cannot be modified.
+pub fn constant_list_with_tail(items: Vec<LispVal>, tail: LispVal) -> LispVal {
+    items
+        .into_iter()
+        .rev()
+        .fold(tail, |rest, x| make(x, rest, true))
+}
+
+/// Makes sure `n` more pairs fit on the heap.
+pub fn reserve(n: usize) -> Result<(), String> {
+    gc::reserve(n.saturating_mul(gc::object_size::<Pair>()))
+}
+
+/// `list_with_tail` for primitives making lists as long as the program asks for: the
+/// pairs are reserved on the heap first, and the deadline is watched while making them.
+pub fn try_list_with_tail(items: Vec<LispVal>, tail: LispVal) -> Result<LispVal, String> {
+    reserve(items.len())?;
+    items.into_iter().rev().try_fold(tail, |rest, x| {
+        limits::poll()?;
+        Ok(cons(x, rest))
+    })
+}
+
+/// `list` for primitives, like `try_list_with_tail`.
+pub fn try_list(items: Vec<LispVal>) -> Result<LispVal, String> {
+    try_list_with_tail(items, LispVal::List(Vec::new()))
+}
+
+/// The elements of a list made of pairs, source lists or both, and what follows the
+/// last pair: the empty list for proper lists. Circular lists stop at the pair where
+/// the cycle was noticed.
+pub fn elements(v: &LispVal) -> (Vec<LispVal>, LispVal) {
+    let mut items = Vec::new();
+    let mut v = v.clone();
+    // Brent's cycle detection: compare with a pair saved at every power of two steps
+    let mut lap = None;
+    let mut steps = 0usize;
+    loop {
+        match v {
+            LispVal::Pair(p) => {
+                if lap.as_ref().is_some_and(|lap: &Gc<Pair>| lap.ptr_eq(&p)) {
+                    return (items, LispVal::Pair(p));
+                }
+                steps += 1;
+                if steps.is_power_of_two() {
+                    lap = Some(p.clone());
+                }
+                items.push(p.car());
+                v = p.cdr();
+            }
+            LispVal::List(l) => {
+                items.extend(l);
+                return (items, LispVal::List(Vec::new()));
+            }
+            tail => return (items, tail),
+        }
+    }
+}
+
+/// The elements of a proper list, or `None` for anything else.
+pub fn items(v: &LispVal) -> Option<Vec<LispVal>> {
+    match v {
+        LispVal::List(l) => Some(l.clone()),
+        LispVal::Pair(_) => match elements(v) {
+            (items, LispVal::List(tail)) if tail.is_empty() => Some(items),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What `cdr` applied `k` times to `v` returns, if those are all pairs.
+pub fn tail(v: &LispVal, k: usize) -> Option<LispVal> {
+    let mut v = v.clone();
+    for _ in 0..k {
+        v = match v {
+            LispVal::Pair(p) => p.cdr(),
+            LispVal::List(l) if !l.is_empty() => LispVal::List(l[1..].to_vec()),
+            _ => return None,
+        };
+    }
+    Some(v)
+}
+
+/// A position in a list: a value, or the elements of a source list from an index on,
+/// which spares copying the rest of the list at every step.
+enum Cursor {
+    Value(LispVal),
+    Rest(Rc<[LispVal]>, usize),
+}
+
+/// What `equal` and `hash_structure` see of a value.
+enum View {
+    /// the car and the cdr, with the pair itself for pairs made at runtime
+    Pair(LispVal, Cursor, Option<Gc<Pair>>),
+    Empty,
+    Values(Vec<LispVal>),
+    /// anything else, which contains no lists
+    Leaf(LispVal),
+}
+
+impl Cursor {
+    fn view(self) -> View {
+        match self {
+            Cursor::Value(LispVal::Pair(p)) => View::Pair(p.car(), Cursor::Value(p.cdr()), Some(p)),
+            Cursor::Value(LispVal::List(l)) if l.is_empty() => View::Empty,
+            Cursor::Value(LispVal::List(l)) => Cursor::Rest(l.into(), 0).view(),
+            Cursor::Value(LispVal::Values(v)) => View::Values(v),
+            Cursor::Value(v) => View::Leaf(v),
+            Cursor::Rest(items, i) => match items.get(i) {
+                Some(x) => View::Pair(x.clone(), Cursor::Rest(items, i + 1), None),
+                None => View::Empty,
+            },
+        }
+    }
+}
+
+/// The pairs found equal so far, as classes of a union-find structure.
+#[derive(Default)]
+struct Classes(HashMap<usize, usize>);
+
+impl Classes {
+    fn find(&mut self, id: usize) -> usize {
+        let mut root = id;
+        while let Some(&parent) = self.0.get(&root) {
+            root = parent;
+        }
+        // point the pairs on the way straight at the root
+        let mut id = id;
+        while let Some(parent) = self.0.insert(id, root) {
+            if parent == root {
+                break;
+            }
+            id = parent;
+        }
+        self.0.remove(&root);
+        root
+    }
+
+    /// Merges the classes of `a` and `b`, `false` if they were already the same.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.0.insert(a, b);
+        }
+        a != b
+    }
+}
+
+/// Whether `a` and `b` are lists, or multiple values, with equal elements. Pairs met
+/// again are assumed equal to the pairs they were compared with, so circular lists
+/// that unfold into the same infinite list are equal, through their cdrs or their
+/// cars. The structures are walked with a stack rather than recursively, so that
+/// deep nesting cannot overflow the Rust stack.
+pub(crate) fn equal(a: &LispVal, b: &LispVal) -> bool {
+    let mut classes = Classes::default();
+    let mut pending = vec![(Cursor::Value(a.clone()), Cursor::Value(b.clone()))];
+    while let Some((a, b)) = pending.pop() {
+        match (a.view(), b.view()) {
+            (View::Pair(a_car, a_cdr, a_pair), View::Pair(b_car, b_cdr, b_pair)) => {
+                if let (Some(a), Some(b)) = (a_pair, b_pair) {
+                    if !classes.union(a.identity(), b.identity()) {
+                        continue;
+                    }
+                }
+                pending.push((a_cdr, b_cdr));
+                pending.push((Cursor::Value(a_car), Cursor::Value(b_car)));
+            }
+            (View::Empty, View::Empty) => {}
+            (View::Values(a), View::Values(b)) if a.len() == b.len() => pending.extend(
+                a.into_iter()
+                    .zip(b)
+                    .map(|(a, b)| (Cursor::Value(a), Cursor::Value(b))),
+            ),
+            (View::Leaf(a), View::Leaf(b)) if a == b => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// How many nodes of a list `hash_structure` looks at.
+const HASHED_NODES: usize = 64;
+
+/// A hash consistent with `equal`. Lists that are equal unfold into the same tree, of
+/// which only the first nodes are hashed, so that circular lists are hashed too.
+pub(crate) fn hash_structure<H: Hasher>(v: &LispVal, state: &mut H) {
+    let mut pending = vec![Cursor::Value(v.clone())];
+    for _ in 0..HASHED_NODES {
+        let Some(next) = pending.pop() else {
+            break;
+        };
+        match next.view() {
+            View::Pair(car, cdr, _) => {
+                0u8.hash(state);
+                pending.push(cdr);
+                pending.push(Cursor::Value(car));
+            }
+            View::Empty => 1u8.hash(state),
+            View::Values(v) => {
+                2u8.hash(state);
+                v.len().hash(state);
+                pending.extend(v.into_iter().rev().map(Cursor::Value));
+            }
+            View::Leaf(v) => {
+                3u8.hash(state);
+                v.hash(state);
+            }
+        }
+    }
+}
+
+/// Prints lists of pairs, labelling the pairs that are part of a cycle as in
+/// `#0=(1 . #0#)`, so that printing always terminates. What is left to print is kept
+/// on a stack rather than recursing, so that deep nesting cannot overflow the Rust
+/// stack.
+pub(crate) struct Printer {
+    /// the pairs that need a label
+    cyclic: HashSet<usize>,
+    labels: HashMap<usize, usize>,
+    /// whether to print like `display` rather than like `write`
+    display: bool,
+}
+
+/// What is left to print.
+enum Task {
+    Value(LispVal),
+    /// the cdr of a pair whose car has been printed
+    Rest(LispVal),
+    Text(&'static str),
+}
+
+impl Printer {
+    pub(crate) fn print(v: &LispVal, f: &mut Formatter<'_>, display: bool) -> std::fmt::Result {
+        Printer {
+            cyclic: find_cycles(v),
+            labels: HashMap::new(),
+            display,
+        }
+        .value(v, f)
+    }
+
+    fn value(&mut self, v: &LispVal, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut tasks = vec![Task::Value(v.clone())];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Value(LispVal::Pair(p)) => {
+                    let id = p.identity();
+                    if self.cyclic.contains(&id) {
+                        if let Some(n) = self.labels.get(&id) {
+                            write!(f, "#{}#", n)?;
+                            continue;
+                        }
+                        let n = self.labels.len();
+                        self.labels.insert(id, n);
+                        write!(f, "#{}=", n)?;
+                    }
+                    write!(f, "(")?;
+                    tasks.push(Task::Text(")"));
+                    tasks.push(Task::Rest(p.cdr()));
+                    tasks.push(Task::Value(p.car()));
+                }
+                Task::Value(LispVal::List(l)) => {
+                    write!(f, "(")?;
+                    tasks.push(Task::Text(")"));
+                    if let Some((first, rest)) = l.split_first() {
+                        Self::elements(rest, &mut tasks);
+                        tasks.push(Task::Value(first.clone()));
+                    }
+                }
+                Task::Value(v) if self.display => write!(f, "{}", Displayed(&v))?,
+                Task::Value(v) => write!(f, "{}", v)?,
+                Task::Rest(LispVal::Pair(p)) if !self.cyclic.contains(&p.identity()) => {
+                    write!(f, " ")?;
+                    tasks.push(Task::Rest(p.cdr()));
+                    tasks.push(Task::Value(p.car()));
+                }
+                Task::Rest(LispVal::List(l)) => Self::elements(&l, &mut tasks),
+                Task::Rest(rest) => {
+                    write!(f, " . ")?;
+                    tasks.push(Task::Value(rest));
+                }
+                Task::Text(text) => f.write_str(text)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedules `l`, each element preceded by a space.
+    fn elements(l: &[LispVal], tasks: &mut Vec<Task>) {
+        for x in l.iter().rev() {
+            tasks.push(Task::Value(x.clone()));
+            tasks.push(Task::Text(" "));
+        }
+    }
+}
+
+/// The pairs reachable from `v` that lead back to themselves.
+fn find_cycles(v: &LispVal) -> HashSet<usize> {
+    enum Visit {
+        Enter(LispVal),
+        /// the car and cdr of the pair have been explored
+        Leave(usize),
+    }
+
+    let mut cyclic = HashSet::new();
+    let mut on_path = HashSet::new();
+    let mut done = HashSet::new();
+    let mut visits = vec![Visit::Enter(v.clone())];
+    while let Some(visit) = visits.pop() {
+        match visit {
+            Visit::Enter(LispVal::Pair(p)) => {
+                let id = p.identity();
+                if on_path.contains(&id) {
+                    cyclic.insert(id);
+                    continue;
+                }
+                if !done.insert(id) {
+                    continue;
+                }
+                on_path.insert(id);
+                visits.push(Visit::Leave(id));
+                visits.push(Visit::Enter(p.cdr()));
+                visits.push(Visit::Enter(p.car()));
+            }
+            Visit::Enter(LispVal::List(l)) => {
+                visits.extend(l.into_iter().rev().map(Visit::Enter));
+            }
+            Visit::Enter(_) => {}
+            Visit::Leave(id) => {
+                on_path.remove(&id);
+            }
+        }
+    }
+    cyclic
+}
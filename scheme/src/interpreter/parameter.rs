@@ -0,0 +1,167 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    gc::{Gc, Trace, Tracer},
+    Interpreter,
+};
+
+struct ParameterState {
+    value: RefCell<LispVal>,
+    /// applied to the initial value and to the values given by `parameterize`
+    converter: Option<LispVal>,
+}
+
+impl Trace for ParameterState {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.value.try_borrow() {
+            Ok(value) => value.trace(tracer),
+            Err(_) => tracer.fail(),
+        }
+        if let Some(converter) = &self.converter {
+            converter.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        self.value.replace(LispVal::Unspecified);
+    }
+}
+
+/// A parameter object made by `make-parameter`. Calling it returns its current value,
+/// which `parameterize` changes for the extent of its body.
+#[derive(Clone)]
+pub struct Parameter(Gc<ParameterState>);
+
+impl Parameter {
+    /// A parameter holding `value` as it is; converters are applied by the caller.
+    pub fn new(value: LispVal, converter: Option<LispVal>) -> Parameter {
+        Parameter(Gc::new(ParameterState {
+            value: RefCell::new(value),
+            converter,
+        }))
+    }
+
+    pub fn value(&self) -> LispVal {
+        self.0.value.borrow().clone()
+    }
+
+    /// Sets the value, returning the previous one.
+    pub fn replace(&self, value: LispVal) -> LispVal {
+        self.0.value.replace(value)
+    }
+
+    fn convert(&self, interpreter: &mut Interpreter, value: LispVal) -> Result<LispVal, String> {
+        match &self.0.converter {
+            Some(converter) => {
+                interpreter.apply_procedure("parameter converter", converter, &[value])
+            }
+            None => Ok(value),
+        }
+    }
+
+    pub fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(&self.0)
+    }
+
+    fn identity(&self) -> usize {
+        self.0.identity()
+    }
+}
+
+/// Parameters are only equal to themselves.
+impl PartialEq for Parameter {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Parameter {}
+
+impl PartialOrd for Parameter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Parameter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for Parameter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl Debug for Parameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Parameter").field(&self.value()).finish()
+    }
+}
+
+/// `(make-parameter value [converter])`
+pub(super) fn make_parameter(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let (value, converter) = match v.as_slice() {
+        [value] => (value.clone(), None),
+        [value, converter] => (value.clone(), Some(converter.clone())),
+        _ => return Err("make-parameter expects a value and an optional converter".to_string()),
+    };
+    let parameter = Parameter::new(LispVal::Unspecified, converter);
+    let value = parameter.convert(interpreter, value)?;
+    parameter.replace(value);
+    Ok(LispVal::Parameter(parameter))
+}
+
+/// `(parameterize ((param value) ...) body ...)`. The parameters and values are all
+/// evaluated before any parameter changes, and the previous values are restored when
+/// the body returns, normally or with an error.
+pub(super) fn parameterize(
+    interpreter: &mut Interpreter,
+    v: &[LispVal],
+) -> Result<LispVal, String> {
+    let [LispVal::List(bindings), body @ ..] = v else {
+        return Err("parameterize expects bindings and a body".to_string());
+    };
+    let mut parameters = Vec::new();
+    for binding in bindings {
+        let LispVal::List(binding) = binding else {
+            return Err(format!("parameterize: bad binding {}", binding));
+        };
+        let [parameter, value] = binding.as_slice() else {
+            return Err(format!(
+                "parameterize: bad binding {}",
+                LispVal::List(binding.clone())
+            ));
+        };
+        let LispVal::Parameter(parameter) = interpreter.eval(parameter)? else {
+            return Err(format!("parameterize: {} is not a parameter", parameter));
+        };
+        let value = interpreter.eval(value)?;
+        let value = parameter.convert(interpreter, value)?;
+        parameters.push((parameter, value));
+    }
+    let saved = parameters
+        .into_iter()
+        .map(|(parameter, value)| {
+            let previous = parameter.replace(value);
+            (parameter, previous)
+        })
+        .collect::<Vec<_>>();
+    let result = interpreter.eval_body(body);
+    for (parameter, previous) in saved.into_iter().rev() {
+        parameter.replace(previous);
+    }
+    result
+}
@@ -0,0 +1,619 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::parser::{
+    parser::{Displayed, LispVal, ParseError},
+    Parser,
+};
+
+use super::{
+    gc::{self, Tracer},
+    json,
+    limits::LimitExceeded,
+    parameter::Parameter,
+    strings::LispString,
+    Interpreter,
+};
+
+/// Reads a value from the start of some text, returning it with the length of the text
+/// consumed.
+type ValueReader<'a> = &'a dyn Fn(&str) -> (Result<Option<LispVal>, ParseError>, usize);
+
+/// Text read from a source but not consumed yet. String and file ports hold all of
+/// their text from the start, ports over a reader fetch it a line at a time.
+struct InputPort {
+    text: String,
+    /// byte offset of the next character in `text`
+    pos: usize,
+    source: Option<Box<dyn BufRead>>,
+}
+
+impl InputPort {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    /// Reads another line from the source, `false` once it is exhausted.
+    fn fill(&mut self) -> Result<bool, String> {
+        let Some(source) = &mut self.source else {
+            return Ok(false);
+        };
+        self.text.drain(..self.pos);
+        self.pos = 0;
+        match source
+            .read_line(&mut self.text)
+            .map_err(|e| e.to_string())?
+        {
+            0 => {
+                self.source = None;
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    fn peek_char(&mut self) -> Result<Option<char>, String> {
+        while self.rest().is_empty() {
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.rest().chars().next())
+    }
+
+    fn read_char(&mut self) -> Result<Option<char>, String> {
+        let c = self.peek_char()?;
+        if let Some(c) = c {
+            self.pos += c.len_utf8();
+        }
+        Ok(c)
+    }
+
+    /// A line without its terminating newline.
+    fn read_line(&mut self) -> Result<Option<String>, String> {
+        loop {
+            if let Some(i) = self.rest().find('\n') {
+                let line = self.rest()[..i].to_string();
+                self.pos += i + 1;
+                return Ok(Some(line));
+            }
+            if !self.fill()? {
+                let line = self.rest().to_string();
+                self.pos = self.text.len();
+                return Ok((!line.is_empty()).then_some(line));
+            }
+        }
+    }
+
+    /// Reads the next datum, which lists may nest in up to `max_nesting` deep.
+    fn read_datum(&mut self, max_nesting: Option<usize>) -> Result<Option<LispVal>, String> {
+        self.read_value("read", &|text| {
+            let mut parser = Parser::with_max_nesting(text, max_nesting);
+            let result = parser.read();
+            (result, text.len() - parser.rest().len())
+        })
+    }
+
+    fn read_json(&mut self, max_nesting: Option<usize>) -> Result<Option<LispVal>, String> {
+        self.read_value("json-read", &|text| json::read(text, max_nesting))
+    }
+
+    /// Reads the next value with `reader`, fetching more text while it is incomplete.
+    fn read_value(&mut self, who: &str, reader: ValueReader) -> Result<Option<LispVal>, String> {
+        loop {
+            let (result, consumed) = reader(self.rest());
+            match result {
+                Ok(Some(v)) => {
+                    self.pos += consumed;
+                    return Ok(Some(v));
+                }
+                Ok(None) | Err(ParseError::Incomplete) if self.fill()? => continue,
+                Ok(None) => {
+                    self.pos = self.text.len();
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.pos += consumed;
+                    return Err(format!("{}: {}", who, e));
+                }
+            }
+        }
+    }
+
+    /// Whether a character can be read without waiting for the source.
+    fn char_ready(&self) -> bool {
+        !self.rest().is_empty() || self.source.is_none()
+    }
+}
+
+enum OutputPort {
+    Writer(Box<dyn Write>),
+    String(String),
+}
+
+enum PortState {
+    Input(InputPort),
+    Output(OutputPort),
+    ClosedInput,
+    ClosedOutput,
+}
+
+/// A textual port. Clones refer to the same port.
+#[derive(Clone)]
+pub struct Port(Rc<RefCell<PortState>>);
+
+impl Port {
+    fn new(state: PortState) -> Port {
+        Port(Rc::new(RefCell::new(state)))
+    }
+
+    pub fn from_reader(reader: impl Read + 'static) -> Port {
+        Port::new(PortState::Input(InputPort {
+            text: String::new(),
+            pos: 0,
+            source: Some(Box::new(BufReader::new(reader))),
+        }))
+    }
+
+    pub fn input_string(text: &str) -> Port {
+        Port::new(PortState::Input(InputPort {
+            text: text.to_string(),
+            pos: 0,
+            source: None,
+        }))
+    }
+
+    pub fn from_writer(writer: impl Write + 'static) -> Port {
+        Port::new(PortState::Output(OutputPort::Writer(Box::new(writer))))
+    }
+
+    pub fn output_string() -> Port {
+        Port::new(PortState::Output(OutputPort::String(String::new())))
+    }
+
+    pub fn is_input(&self) -> bool {
+        matches!(
+            *self.0.borrow(),
+            PortState::Input(_) | PortState::ClosedInput
+        )
+    }
+
+    pub fn is_output(&self) -> bool {
+        !self.is_input()
+    }
+
+    fn input<T>(
+        &self,
+        who: &str,
+        f: impl FnOnce(&mut InputPort) -> Result<T, String>,
+    ) -> Result<T, String> {
+        match &mut *self.0.borrow_mut() {
+            PortState::Input(port) => f(port),
+            PortState::ClosedInput => Err(format!("{}: the port is closed", who)),
+            _ => Err(format!("{} expects an input port", who)),
+        }
+    }
+
+    pub fn write_str(&self, who: &str, s: &str) -> Result<(), String> {
+        // before borrowing the port, which collecting traces
+        if matches!(*self.0.borrow(), PortState::Output(OutputPort::String(_))) {
+            gc::reserve(s.len())?;
+        }
+        match &mut *self.0.borrow_mut() {
+            PortState::Output(OutputPort::Writer(w)) => w
+                .write_all(s.as_bytes())
+                .map_err(|e| format!("{}: {}", who, e)),
+            PortState::Output(OutputPort::String(buffer)) => {
+                buffer
+                    .try_reserve(s.len())
+                    .map_err(|_| LimitExceeded::Heap.to_string())?;
+                buffer.push_str(s);
+                gc::charge(s.len());
+                Ok(())
+            }
+            PortState::ClosedOutput => Err(format!("{}: the port is closed", who)),
+            _ => Err(format!("{} expects an output port", who)),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), String> {
+        match &mut *self.0.borrow_mut() {
+            PortState::Output(OutputPort::Writer(w)) => w.flush().map_err(|e| e.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The text written so far to a port created by `open-output-string`.
+    pub fn output(&self) -> Option<String> {
+        match &*self.0.borrow() {
+            PortState::Output(OutputPort::String(buffer)) => Some(buffer.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn close(&self) -> Result<(), String> {
+        self.flush()?;
+        let closed = match self.is_input() {
+            true => PortState::ClosedInput,
+            false => PortState::ClosedOutput,
+        };
+        *self.0.borrow_mut() = closed;
+        Ok(())
+    }
+
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    /// Reports the text the port holds, which its clones share.
+    pub fn trace(&self, tracer: &mut Tracer) {
+        let bytes = match self.0.try_borrow().as_deref() {
+            Ok(PortState::Input(input)) => input.text.capacity(),
+            Ok(PortState::Output(OutputPort::String(buffer))) => buffer.capacity(),
+            Ok(_) => 0,
+            Err(_) => return tracer.fail(),
+        };
+        tracer.shares(self.identity(), bytes);
+    }
+}
+
+/// Ports are only equal to themselves.
+impl PartialEq for Port {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Port {}
+
+impl PartialOrd for Port {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Port {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for Port {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl Debug for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.is_input() {
+            true => write!(f, "Port(input)"),
+            false => write!(f, "Port(output)"),
+        }
+    }
+}
+
+/// The parameters `current-input-port`, `current-output-port` and `current-error-port`,
+/// which default to the process' standard streams.
+pub(super) struct CurrentPorts {
+    pub input: Parameter,
+    pub output: Parameter,
+    pub error: Parameter,
+}
+
+impl Default for CurrentPorts {
+    fn default() -> Self {
+        let parameter = |port| Parameter::new(LispVal::Port(port), None);
+        CurrentPorts {
+            input: parameter(Port::from_reader(std::io::stdin())),
+            output: parameter(Port::from_writer(std::io::stdout())),
+            error: parameter(Port::from_writer(std::io::stderr())),
+        }
+    }
+}
+
+impl CurrentPorts {
+    pub fn input(&self) -> Result<Port, String> {
+        Self::port(&self.input, "current-input-port")
+    }
+
+    pub fn output(&self) -> Result<Port, String> {
+        Self::port(&self.output, "current-output-port")
+    }
+
+    /// The parameters may have been given anything by `parameterize`.
+    fn port(parameter: &Parameter, who: &str) -> Result<Port, String> {
+        match parameter.value() {
+            LispVal::Port(port) => Ok(port),
+            v => Err(format!("{}: {} is not a port", who, v)),
+        }
+    }
+}
+
+/// The port passed as argument `i`, or `current` when it is left out.
+fn port_arg(v: &[LispVal], i: usize, who: &str, current: &Port) -> Result<Port, String> {
+    match v.get(i) {
+        None => Ok(current.clone()),
+        Some(LispVal::Port(p)) => Ok(p.clone()),
+        Some(_) => Err(format!("{} expects a port as argument {}", who, i + 1)),
+    }
+}
+
+fn path_arg(v: &[LispVal], who: &str) -> Result<String, String> {
+    match v.first() {
+        Some(LispVal::String(s)) => Ok(s.to_string()),
+        _ => Err(format!("{} expects a file name as argument 1", who)),
+    }
+}
+
+fn or_eof<T>(v: Option<T>, f: impl FnOnce(T) -> LispVal) -> LispVal {
+    v.map(f).unwrap_or(LispVal::Eof)
+}
+
+pub(super) fn display(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 1, "display", &interpreter.ports.output()?)?;
+    port.write_str("display", &Displayed(&v[0]).to_string())?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn write(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 1, "write", &interpreter.ports.output()?)?;
+    port.write_str("write", &v[0].to_string())?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn write_string(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let Some(LispVal::String(s)) = v.first() else {
+        return Err("write-string expects a string as argument 1".to_string());
+    };
+    let port = port_arg(&v, 1, "write-string", &interpreter.ports.output()?)?;
+    port.write_str("write-string", &s.to_string())?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn write_char(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let Some(LispVal::Char(c)) = v.first() else {
+        return Err("write-char expects a character as argument 1".to_string());
+    };
+    let port = port_arg(&v, 1, "write-char", &interpreter.ports.output()?)?;
+    port.write_str("write-char", &c.to_string())?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn newline(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "newline", &interpreter.ports.output()?)?;
+    port.write_str("newline", "\n")?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn flush_output_port(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    port_arg(&v, 0, "flush-output-port", &interpreter.ports.output()?)?.flush()?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn read_line(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "read-line", &interpreter.ports.input()?)?;
+    let line = port.input("read-line", InputPort::read_line)?;
+    Ok(or_eof(line, |s| LispVal::String(LispString::immutable(&s))))
+}
+
+pub(super) fn read_char(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "read-char", &interpreter.ports.input()?)?;
+    Ok(or_eof(
+        port.input("read-char", InputPort::read_char)?,
+        LispVal::Char,
+    ))
+}
+
+pub(super) fn peek_char(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "peek-char", &interpreter.ports.input()?)?;
+    Ok(or_eof(
+        port.input("peek-char", InputPort::peek_char)?,
+        LispVal::Char,
+    ))
+}
+
+pub(super) fn char_ready(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "char-ready?", &interpreter.ports.input()?)?;
+    port.input("char-ready?", |p| Ok(LispVal::Bool(p.char_ready())))
+}
+
+pub(super) fn read(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "read", &interpreter.ports.input()?)?;
+    let max_nesting = interpreter.meter.max_depth();
+    Ok(or_eof(
+        port.input("read", |p| p.read_datum(max_nesting))?,
+        |v| v.to_datum(),
+    ))
+}
+
+/// Reads the next JSON value of the port, or the end of file object. Numbers must have
+/// integral values, like `2`, `2.0` or `1e3`, since integers are the only numbers.
+pub(super) fn json_read(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    let port = port_arg(&v, 0, "json-read", &interpreter.ports.input()?)?;
+    let max_nesting = interpreter.meter.max_depth();
+    Ok(or_eof(
+        port.input("json-read", |p| p.read_json(max_nesting))?,
+        |v| v,
+    ))
+}
+
+pub(super) fn json_write(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let port = port_arg(&v, 1, "json-write", &interpreter.ports.output()?)?;
+    let text = json::write(&v[0], interpreter.meter.max_depth())
+        .map_err(|e| format!("json-write: {}", e))?;
+    port.write_str("json-write", &text)?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn open_input_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [LispVal::String(s)] = v.as_slice() else {
+        return Err("open-input-string expects a string".to_string());
+    };
+    Ok(LispVal::Port(Port::input_string(&s.to_string())))
+}
+
+pub(super) fn open_output_string(_: Vec<LispVal>) -> Result<LispVal, String> {
+    Ok(LispVal::Port(Port::output_string()))
+}
+
+pub(super) fn get_output_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    match v.as_slice() {
+        [LispVal::Port(p)] => p
+            .output()
+            .map(|s| LispVal::String(LispString::immutable(&s)))
+            .ok_or("get-output-string expects a port made by open-output-string".to_string()),
+        _ => Err("get-output-string expects a port".to_string()),
+    }
+}
+
+/// Opens `path` for reading, if the profile of the interpreter allows it.
+fn open_input_file_port(interpreter: &Interpreter, who: &str, path: &str) -> Result<Port, String> {
+    let path = interpreter.profile.readable(who, Path::new(path))?;
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Port::input_string(&text))
+}
+
+fn open_output_file_port(path: &str) -> Result<Port, String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Port::from_writer(BufWriter::new(file)))
+}
+
+pub(super) fn open_input_file(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let path = path_arg(&v, "open-input-file")?;
+    open_input_file_port(interpreter, "open-input-file", &path).map(LispVal::Port)
+}
+
+pub(super) fn open_output_file(v: Vec<LispVal>) -> Result<LispVal, String> {
+    open_output_file_port(&path_arg(&v, "open-output-file")?).map(LispVal::Port)
+}
+
+/// `close-port`, `close-input-port` and `close-output-port` alike.
+pub(super) fn close_port(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [LispVal::Port(p)] = v.as_slice() else {
+        return Err("close-port expects a port".to_string());
+    };
+    p.close()?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn eof_object(_: Vec<LispVal>) -> Result<LispVal, String> {
+    Ok(LispVal::Eof)
+}
+
+pub(super) fn is_eof_object(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("eof-object? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(*x == LispVal::Eof))
+}
+
+/// Builds `port?`, `input-port?` and `output-port?`.
+pub(super) fn is_port(
+    who: &'static str,
+    accept: fn(&Port) -> bool,
+) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        let [x] = v.as_slice() else {
+            return Err(format!("{} expects 1 argument", who));
+        };
+        Ok(LispVal::Bool(matches!(x, LispVal::Port(p) if accept(p))))
+    }
+}
+
+/// `(call-with-port port proc)` closes the port once `proc` returns.
+pub(super) fn call_with_port(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let [LispVal::Port(port), proc] = v.as_slice() else {
+        return Err("call-with-port expects a port and a procedure".to_string());
+    };
+    let result = interpreter.apply_procedure("call-with-port", proc, &v[..1])?;
+    port.close()?;
+    Ok(result)
+}
+
+pub(super) fn call_with_output_file(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let path = path_arg(&v, "call-with-output-file")?;
+    let port = open_output_file_port(&path)?;
+    let proc = v
+        .get(1)
+        .ok_or("call-with-output-file expects a procedure")?;
+    call_with_port(interpreter, vec![LispVal::Port(port), proc.clone()])
+}
+
+pub(super) fn call_with_input_file(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let path = path_arg(&v, "call-with-input-file")?;
+    let port = open_input_file_port(interpreter, "call-with-input-file", &path)?;
+    let proc = v.get(1).ok_or("call-with-input-file expects a procedure")?;
+    call_with_port(interpreter, vec![LispVal::Port(port), proc.clone()])
+}
+
+/// `(call-with-output-string proc)` returns what `proc` wrote to the port it was given.
+pub(super) fn call_with_output_string(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let [proc] = v.as_slice() else {
+        return Err("call-with-output-string expects a procedure".to_string());
+    };
+    let port = Port::output_string();
+    interpreter.apply_procedure(
+        "call-with-output-string",
+        proc,
+        &[LispVal::Port(port.clone())],
+    )?;
+    get_output_string(vec![LispVal::Port(port)])
+}
+
+/// `(with-output-to-string thunk)` returns what `thunk` wrote to the current output port.
+pub(super) fn with_output_to_string(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let [thunk] = v.as_slice() else {
+        return Err("with-output-to-string expects a procedure".to_string());
+    };
+    let port = Port::output_string();
+    let caller_output = interpreter
+        .ports
+        .output
+        .replace(LispVal::Port(port.clone()));
+    let result = interpreter.apply_procedure("with-output-to-string", thunk, &[]);
+    interpreter.ports.output.replace(caller_output);
+    result?;
+    get_output_string(vec![LispVal::Port(port)])
+}
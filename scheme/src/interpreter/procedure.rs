@@ -0,0 +1,225 @@
+use std::{
+    cell::OnceCell,
+    cmp::Ordering,
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    analyzer::{self, Node},
+    bytecode::{self, Chunk},
+    environment::Environment,
+    gc::{Trace, Tracer},
+    symbol::Symbol,
+};
+
+/// How many arguments a procedure accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Arity {
+    pub min: usize,
+    /// `None` when any number of extra arguments is accepted.
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub const fn exactly(n: usize) -> Arity {
+        Arity {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    pub const fn at_least(n: usize) -> Arity {
+        Arity { min: n, max: None }
+    }
+
+    pub const fn between(min: usize, max: usize) -> Arity {
+        Arity {
+            min,
+            max: Some(max),
+        }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+
+    pub fn check(&self, operator: &str, n: usize) -> Result<(), String> {
+        match self.accepts(n) {
+            true => Ok(()),
+            false => Err(format!(
+                "{} expects {} arguments, but got {}",
+                operator, self, n
+            )),
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// The formal parameters of a closure, in the order arguments are matched against them:
+/// `(a b (c default) (key: d default) . rest)`.
+#[derive(Debug, Default, Clone)]
+pub struct Params {
+    pub required: Vec<Symbol>,
+    /// positional parameters with the expressions computing their defaults
+    pub optional: Vec<(Symbol, LispVal)>,
+    /// named parameters as `(keyword variable default)`
+    pub named: Vec<(Symbol, Symbol, LispVal)>,
+    pub rest: Option<Symbol>,
+}
+
+impl Params {
+    /// Parses `args`, `(a b . rest)` and, when `extended` (`lambda*` and `define*`),
+    /// the optional `(c default)` and named `(key: d default)` parameters of SRFI 89.
+    pub fn parse(formals: &[LispVal], extended: bool) -> Result<Params, String> {
+        let variable = |v: &LispVal| match v {
+            LispVal::Atom(s) if s != "." => Ok(*s),
+            _ => Err(format!("{} is not a valid parameter", v)),
+        };
+        let mut params = Params::default();
+        let mut formals = formals.iter();
+        while let Some(formal) = formals.next() {
+            match formal {
+                LispVal::Atom(s) if s == "." => {
+                    let (Some(rest), None) = (formals.next(), formals.next()) else {
+                        return Err("exactly one rest parameter must follow `.`".to_string());
+                    };
+                    params.rest = Some(variable(rest)?);
+                }
+                LispVal::Atom(_) if params.optional.is_empty() && params.named.is_empty() => {
+                    params.required.push(variable(formal)?)
+                }
+                LispVal::List(spec) if extended => match spec.as_slice() {
+                    [var, default] if params.named.is_empty() => {
+                        params.optional.push((variable(var)?, default.clone()))
+                    }
+                    [LispVal::Keyword(key), var, default] => {
+                        params.named.push((*key, variable(var)?, default.clone()))
+                    }
+                    _ => return Err(format!("bad optional parameter {}", formal)),
+                },
+                _ => return Err(format!("{} is not a valid parameter here", formal)),
+            }
+        }
+        Ok(params)
+    }
+
+    /// Parses the formals of a lambda: a parameter list, or one identifier that takes
+    /// all the arguments.
+    pub fn from_formals(formals: &LispVal, extended: bool) -> Result<Params, String> {
+        match formals {
+            LispVal::Atom(args) => Ok(Params {
+                rest: Some(*args),
+                ..Params::default()
+            }),
+            LispVal::List(formals) => Params::parse(formals, extended),
+            _ => Err("lambda must have a parameter list".to_string()),
+        }
+    }
+
+    pub fn arity(&self) -> Arity {
+        let min = self.required.len();
+        match self.rest {
+            Some(_) => Arity::at_least(min),
+            None => Arity::between(min, min + self.optional.len() + 2 * self.named.len()),
+        }
+    }
+}
+
+/// A user procedure created by `lambda` or `define`, together with the
+/// environment it was created in.
+pub struct Closure {
+    pub params: Params,
+    pub body: Rc<[LispVal]>,
+    pub env: Environment,
+    /// the body compiled for the virtual machine, the first time it runs there
+    pub code: OnceCell<Rc<Chunk>>,
+    /// the body analyzed, the first time it runs after analysis
+    pub analyzed: OnceCell<Rc<Node>>,
+}
+
+impl Closure {
+    pub fn arity(&self) -> Arity {
+        self.params.arity()
+    }
+
+    pub fn code(&self) -> Rc<Chunk> {
+        self.code
+            .get_or_init(|| Rc::new(bytecode::compile_body(&self.body)))
+            .clone()
+    }
+
+    pub fn analyzed(&self) -> Rc<Node> {
+        self.analyzed
+            .get_or_init(|| Rc::new(analyzer::analyze_body(&self.params, &self.body)))
+            .clone()
+    }
+
+    fn identity(&self) -> usize {
+        self as *const Closure as usize
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.params
+            .optional
+            .iter()
+            .for_each(|(_, default)| default.trace(tracer));
+        self.params
+            .named
+            .iter()
+            .for_each(|(_, _, default)| default.trace(tracer));
+        self.body.trace(tracer);
+        self.env.trace(tracer);
+    }
+}
+
+/// Closures are only equal to themselves.
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Closure {}
+
+impl PartialOrd for Closure {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Closure {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for Closure {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+/// The captured environment is left out, it usually contains the closure itself.
+impl Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Closure")
+            .field("params", &self.params)
+            .field("body", &self.body)
+            .finish()
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    environment::Environment,
+    gc::{self, Gc, Trace, Tracer},
+    Interpreter,
+};
+
+#[derive(Clone)]
+enum PromiseState {
+    Value(LispVal),
+    /// `(delay expr)`
+    Delay {
+        expr: LispVal,
+        env: Environment,
+    },
+    /// `(delay-force expr)`, where `expr` evaluates to another promise
+    DelayForce {
+        expr: LispVal,
+        env: Environment,
+    },
+}
+
+/// The box shared by promises forced through `delay-force`.
+struct PromiseBox {
+    state: RefCell<PromiseState>,
+}
+
+impl Trace for PromiseBox {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.state.try_borrow() {
+            Ok(state) => match &*state {
+                PromiseState::Value(v) => v.trace(tracer),
+                PromiseState::Delay { expr, env } | PromiseState::DelayForce { expr, env } => {
+                    expr.trace(tracer);
+                    env.trace(tracer);
+                }
+            },
+            Err(_) => tracer.fail(),
+        }
+    }
+
+    fn clear(&self) {
+        self.state
+            .replace(PromiseState::Value(LispVal::Unspecified));
+    }
+}
+
+/// Frees the value of the promise in a loop rather than recursively, as in the promises
+/// making up a long stream.
+impl Drop for PromiseBox {
+    fn drop(&mut self) {
+        match std::mem::replace(
+            self.state.get_mut(),
+            PromiseState::Value(LispVal::Unspecified),
+        ) {
+            PromiseState::Value(v) => gc::release([v]),
+            PromiseState::Delay { expr, env } | PromiseState::DelayForce { expr, env } => {
+                gc::release([expr, LispVal::Environment(env)])
+            }
+        }
+    }
+}
+
+struct PromiseCell {
+    current: RefCell<Gc<PromiseBox>>,
+}
+
+impl Trace for PromiseCell {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.current.try_borrow() {
+            Ok(current) => tracer.visit(&current),
+            Err(_) => tracer.fail(),
+        }
+    }
+}
+
+/// A memoized delayed evaluation. As in the R7RS reference implementation, a promise
+/// points to a box holding its state, and forcing a `delay-force` makes the inner
+/// promise share the box of the outer one, so chains of them are forced in a loop.
+#[derive(Clone)]
+pub struct Promise(Gc<PromiseCell>);
+
+impl Promise {
+    fn new(state: PromiseState) -> Promise {
+        Promise(Gc::new(PromiseCell {
+            current: RefCell::new(Gc::new(PromiseBox {
+                state: RefCell::new(state),
+            })),
+        }))
+    }
+
+    pub fn done(value: LispVal) -> Promise {
+        Promise::new(PromiseState::Value(value))
+    }
+
+    pub fn delay(expr: LispVal, env: Environment) -> Promise {
+        Promise::new(PromiseState::Delay { expr, env })
+    }
+
+    pub fn delay_force(expr: LispVal, env: Environment) -> Promise {
+        Promise::new(PromiseState::DelayForce { expr, env })
+    }
+
+    fn state(&self) -> Gc<PromiseBox> {
+        self.0.current.borrow().clone()
+    }
+
+    fn value(&self) -> Option<LispVal> {
+        match &*self.state().state.borrow() {
+            PromiseState::Value(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(&self.0)
+    }
+
+    fn identity(&self) -> usize {
+        self.0.identity()
+    }
+}
+
+/// Promises are only equal to themselves.
+impl PartialEq for Promise {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Promise {}
+
+impl PartialOrd for Promise {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Promise {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for Promise {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl Debug for Promise {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value() {
+            Some(v) => write!(f, "Promise({:?})", v),
+            None => write!(f, "Promise(..)"),
+        }
+    }
+}
+
+pub(super) fn force_promise(
+    interpreter: &mut Interpreter,
+    promise: &Promise,
+) -> Result<LispVal, String> {
+    loop {
+        let (expr, env, forces_promise) = match &*promise.state().state.borrow() {
+            PromiseState::Value(v) => return Ok(v.clone()),
+            PromiseState::Delay { expr, env } => (expr.clone(), env.clone(), false),
+            PromiseState::DelayForce { expr, env } => (expr.clone(), env.clone(), true),
+        };
+        let value = interpreter.eval_in(&expr, env)?;
+        // forcing the promise again from its own body may already have set its value
+        if promise.value().is_some() {
+            continue;
+        }
+        if !forces_promise {
+            *promise.state().state.borrow_mut() = PromiseState::Value(value);
+            continue;
+        }
+        let LispVal::Promise(next) = value else {
+            return Err(format!("delay-force: {} is not a promise", value));
+        };
+        let next_state = next.state().state.borrow().clone();
+        *promise.state().state.borrow_mut() = next_state;
+        *next.0.current.borrow_mut() = promise.state();
+    }
+}
+
+/// `(force obj)` returns `obj` itself when it is not a promise.
+pub(super) fn force(interpreter: &mut Interpreter, v: Vec<LispVal>) -> Result<LispVal, String> {
+    match v.as_slice() {
+        [LispVal::Promise(p)] => force_promise(interpreter, p),
+        [x] => Ok(x.clone()),
+        _ => Err("force expects 1 argument".to_string()),
+    }
+}
+
+pub(super) fn make_promise(v: Vec<LispVal>) -> Result<LispVal, String> {
+    match v.as_slice() {
+        [p @ LispVal::Promise(_)] => Ok(p.clone()),
+        [x] => Ok(LispVal::Promise(Promise::done(x.clone()))),
+        _ => Err("make-promise expects 1 argument".to_string()),
+    }
+}
+
+pub(super) fn is_promise(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("promise? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(matches!(x, LispVal::Promise(_))))
+}
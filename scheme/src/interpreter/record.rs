@@ -0,0 +1,251 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    rc::Rc,
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    gc::{self, Gc, Trace, Tracer},
+    symbol::Symbol,
+};
+
+/// The type created by one `define-record-type` form.
+#[derive(Debug)]
+pub struct RecordType {
+    name: Symbol,
+    fields: Vec<Symbol>,
+}
+
+impl RecordType {
+    pub fn new(name: Symbol, fields: Vec<Symbol>) -> Rc<RecordType> {
+        Rc::new(RecordType { name, fields })
+    }
+
+    /// `<point>` is printed as `point`.
+    pub fn display_name(&self) -> &'static str {
+        let name = self.name.name();
+        name.strip_prefix('<')
+            .and_then(|n| n.strip_suffix('>'))
+            .unwrap_or(name)
+    }
+
+    pub fn field_index(&self, field: Symbol) -> Option<usize> {
+        self.fields.iter().position(|f| *f == field)
+    }
+}
+
+/// An instance of a record type. Clones refer to the same instance.
+#[derive(Clone)]
+pub struct Record {
+    rtd: Rc<RecordType>,
+    fields: Gc<Fields>,
+}
+
+/// The values of the fields of a record.
+struct Fields(RefCell<Vec<LispVal>>);
+
+impl Deref for Fields {
+    type Target = RefCell<Vec<LispVal>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Frees records nested in the fields in a loop rather than recursively.
+impl Drop for Fields {
+    fn drop(&mut self) {
+        gc::release(std::mem::take(self.0.get_mut()));
+    }
+}
+
+impl Record {
+    pub fn rtd(&self) -> &Rc<RecordType> {
+        &self.rtd
+    }
+
+    /// The fields with their names, in the order of the `define-record-type` form.
+    pub fn fields(&self) -> Vec<(Symbol, LispVal)> {
+        self.rtd
+            .fields
+            .iter()
+            .copied()
+            .zip(self.fields.borrow().iter().cloned())
+            .collect()
+    }
+
+    pub fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(&self.fields)
+    }
+
+    fn identity(&self) -> usize {
+        self.fields.identity()
+    }
+}
+
+impl Trace for Fields {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.try_borrow() {
+            Ok(values) => values.trace(tracer),
+            Err(_) => tracer.fail(),
+        }
+    }
+
+    fn clear(&self) {
+        self.borrow_mut().clear();
+    }
+}
+
+/// Records are only equal to themselves.
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Record {}
+
+impl PartialOrd for Record {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Record {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.identity().cmp(&other.identity())
+    }
+}
+
+impl Hash for Record {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+impl Debug for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple(self.rtd.display_name())
+            .field(&self.fields.borrow())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RecordProcedureKind {
+    /// the indices of the fields initialized by the constructor arguments
+    Constructor(Vec<usize>),
+    Predicate,
+    Accessor(usize),
+    Modifier(usize),
+}
+
+/// A procedure defined by `define-record-type`.
+#[derive(Clone)]
+pub struct RecordProcedure {
+    pub name: Symbol,
+    pub rtd: Rc<RecordType>,
+    pub kind: RecordProcedureKind,
+}
+
+impl RecordProcedure {
+    pub fn apply(&self, operands: &[LispVal]) -> Result<LispVal, String> {
+        match &self.kind {
+            RecordProcedureKind::Constructor(indices) => {
+                if indices.len() != operands.len() {
+                    return Err(format!(
+                        "{} expects {} arguments, but got {}",
+                        self.name,
+                        indices.len(),
+                        operands.len()
+                    ));
+                }
+                let mut fields = vec![LispVal::Unspecified; self.rtd.fields.len()];
+                for (i, v) in indices.iter().zip(operands) {
+                    fields[*i] = v.clone();
+                }
+                Ok(LispVal::Record(Record {
+                    rtd: self.rtd.clone(),
+                    fields: Gc::new(Fields(RefCell::new(fields))),
+                }))
+            }
+            RecordProcedureKind::Predicate => {
+                let [x] = operands else {
+                    return Err(format!("{} expects 1 argument", self.name));
+                };
+                Ok(LispVal::Bool(matches!(
+                    x,
+                    LispVal::Record(r) if Rc::ptr_eq(&r.rtd, &self.rtd)
+                )))
+            }
+            RecordProcedureKind::Accessor(i) => {
+                let [record] = operands else {
+                    return Err(format!("{} expects 1 argument", self.name));
+                };
+                Ok(self.instance(record)?.fields.borrow()[*i].clone())
+            }
+            RecordProcedureKind::Modifier(i) => {
+                let [record, value] = operands else {
+                    return Err(format!("{} expects 2 arguments", self.name));
+                };
+                self.instance(record)?.fields.borrow_mut()[*i] = value.clone();
+                Ok(LispVal::Unspecified)
+            }
+        }
+    }
+
+    fn instance<'a>(&self, v: &'a LispVal) -> Result<&'a Record, String> {
+        match v {
+            LispVal::Record(r) if Rc::ptr_eq(&r.rtd, &self.rtd) => Ok(r),
+            _ => Err(format!(
+                "{}: {} is not a record of type {}",
+                self.name,
+                v,
+                self.rtd.display_name()
+            )),
+        }
+    }
+}
+
+impl PartialEq for RecordProcedure {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Rc::ptr_eq(&self.rtd, &other.rtd) && self.kind == other.kind
+    }
+}
+
+impl Eq for RecordProcedure {}
+
+impl PartialOrd for RecordProcedure {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RecordProcedure {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.name, Rc::as_ptr(&self.rtd), &self.kind).cmp(&(
+            other.name,
+            Rc::as_ptr(&other.rtd),
+            &other.kind,
+        ))
+    }
+}
+
+impl Hash for RecordProcedure {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        Rc::as_ptr(&self.rtd).hash(state);
+        self.kind.hash(state);
+    }
+}
+
+impl Debug for RecordProcedure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RecordProcedure({:?})", self.name)
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+
+/// What the programs of an interpreter may do beyond computing, chosen when it is
+/// created. The primitives a profile denies are still bound, but fail when called.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// no access to files
+    Pure,
+    /// reading the files under `root` with input file ports, `load`, `include` and
+    /// `import`; relative paths are taken from `root`
+    ReadOnly { root: PathBuf },
+    /// everything the primitives can do
+    #[default]
+    Full,
+}
+
+/// What a primitive needs beyond computing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Capability {
+    ReadFiles,
+    WriteFiles,
+}
+
+/// The primitives that need a capability.
+pub(super) const CAPABILITIES: &[(&str, Capability)] = &[
+    ("open-input-file", Capability::ReadFiles),
+    ("call-with-input-file", Capability::ReadFiles),
+    ("load", Capability::ReadFiles),
+    ("open-output-file", Capability::WriteFiles),
+    ("call-with-output-file", Capability::WriteFiles),
+];
+
+impl Profile {
+    pub(super) fn grants(&self, capability: Capability) -> bool {
+        match self {
+            Profile::Pure => false,
+            Profile::ReadOnly { .. } => capability == Capability::ReadFiles,
+            Profile::Full => true,
+        }
+    }
+
+    /// Where the program finds `path`.
+    pub(super) fn locate(&self, path: &Path) -> PathBuf {
+        match self {
+            Profile::ReadOnly { root } => std::path::absolute(root)
+                .unwrap_or_else(|_| root.clone())
+                .join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Checks that the program may read `path`, returning where to read it from.
+    pub(super) fn readable(&self, who: &str, path: &Path) -> Result<PathBuf, String> {
+        match self {
+            Profile::Full => Ok(path.to_path_buf()),
+            Profile::Pure => Err(denied(who)),
+            Profile::ReadOnly { root } => {
+                let path = self.locate(path);
+                let canonical = path
+                    .canonicalize()
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                match root.canonicalize() {
+                    Ok(root) if canonical.starts_with(&root) => Ok(canonical),
+                    _ => Err(format!("{} to read {}", denied(who), path.display())),
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn denied(who: &str) -> String {
+    format!("{}: capability not granted", who)
+}
@@ -0,0 +1,142 @@
+use crate::parser::parser::LispVal;
+
+use super::{
+    environment::Environment,
+    pair,
+    procedure::Arity,
+    promise::{force_promise, Promise},
+    symbol::Symbol,
+    Interpreter,
+};
+
+/// A pair of a SRFI 41 stream. Both the element and the rest of the stream are
+/// promises; the empty stream is the empty list.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamPair {
+    pub car: Promise,
+    pub cdr: Promise,
+}
+
+fn stream_pair_arg<'a>(v: &'a [LispVal], i: usize, who: &str) -> Result<&'a StreamPair, String> {
+    match v.get(i) {
+        Some(LispVal::StreamPair(p)) => Ok(p),
+        _ => Err(format!(
+            "{} expects a stream pair as argument {}",
+            who,
+            i + 1
+        )),
+    }
+}
+
+fn is_empty_stream(v: &LispVal) -> bool {
+    matches!(v, LispVal::List(l) if l.is_empty())
+}
+
+pub(super) fn is_stream_null(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("stream-null? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(is_empty_stream(x)))
+}
+
+pub(super) fn is_stream_pair(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("stream-pair? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(matches!(x, LispVal::StreamPair(_))))
+}
+
+pub(super) fn is_stream(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("stream? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(
+        matches!(x, LispVal::StreamPair(_)) || is_empty_stream(x),
+    ))
+}
+
+pub(super) fn list_to_stream(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (Some(l), [_]) = (v.first().and_then(pair::items), v.as_slice()) else {
+        return Err("list->stream expects a list".to_string());
+    };
+    Ok(l.iter().rev().fold(LispVal::List(Vec::new()), |rest, x| {
+        LispVal::StreamPair(StreamPair {
+            car: Promise::done(x.clone()),
+            cdr: Promise::done(rest),
+        })
+    }))
+}
+
+pub(super) fn stream_car(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    force_promise(interpreter, &stream_pair_arg(&v, 0, "stream-car")?.car)
+}
+
+pub(super) fn stream_cdr(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    force_promise(interpreter, &stream_pair_arg(&v, 0, "stream-cdr")?.cdr)
+}
+
+/// `(stream-take n stream)` lazily returns the first `n` elements of `stream`.
+pub(super) fn stream_take(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [n, stream] = v.as_slice() else {
+        return Err("stream-take expects a count and a stream".to_string());
+    };
+    let n = n
+        .to_integer()
+        .filter(|n| *n >= 0)
+        .ok_or("stream-take expects a non-negative integer as argument 1")?;
+    if n == 0 || is_empty_stream(stream) {
+        return Ok(LispVal::List(Vec::new()));
+    }
+    let pair = stream_pair_arg(&v, 1, "stream-take")?;
+    // the rest is `(stream-take (- n 1) (stream-cdr stream))`, built from the
+    // primitives themselves so it does not depend on any binding
+    let primitive = |name: &str, arity| LispVal::Primitive {
+        name: Symbol::intern(name),
+        arity,
+    };
+    let quote = |v: LispVal| LispVal::List(vec![LispVal::Atom(Symbol::intern("quote")), v]);
+    let rest = LispVal::List(vec![
+        primitive("stream-take", Arity::exactly(2)),
+        quote(LispVal::Integer(n - 1)),
+        LispVal::List(vec![
+            primitive("stream-cdr", Arity::exactly(1)),
+            quote(stream.clone()),
+        ]),
+    ]);
+    Ok(LispVal::StreamPair(StreamPair {
+        car: pair.car.clone(),
+        cdr: Promise::delay(rest, Environment::new()),
+    }))
+}
+
+/// `(stream->list stream [n])` forces at most `n` elements into a list.
+pub(super) fn stream_to_list(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let (mut stream, limit) = match v.as_slice() {
+        [stream] => (stream.clone(), None),
+        [stream, n] => (
+            stream.clone(),
+            Some(
+                n.to_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .ok_or("stream->list expects a non-negative integer as argument 2")?,
+            ),
+        ),
+        _ => return Err("stream->list expects a stream and an optional count".to_string()),
+    };
+    let mut result = Vec::new();
+    while limit.is_none_or(|n| result.len() < n) && !is_empty_stream(&stream) {
+        let pair = stream_pair_arg(std::slice::from_ref(&stream), 0, "stream->list")?.clone();
+        result.push(force_promise(interpreter, &pair.car)?);
+        stream = force_promise(interpreter, &pair.cdr)?;
+    }
+    pair::try_list(result)
+}
@@ -0,0 +1,339 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use crate::parser::parser::LispVal;
+
+use super::{gc, limits, pair, symbol::Symbol};
+
+/// A Scheme string. Literals are immutable, strings created at runtime
+/// (`make-string`, `string-copy`, ...) can be modified with `string-set!`.
+/// Clones share the same characters, so mutation is visible through every reference.
+/// Comparisons only look at the characters.
+#[derive(Clone)]
+pub struct LispString {
+    chars: Rc<RefCell<Vec<char>>>,
+    mutable: bool,
+}
+
+impl LispString {
+    pub fn immutable(s: &str) -> LispString {
+        LispString {
+            chars: Rc::new(RefCell::new(s.chars().collect())),
+            mutable: false,
+        }
+    }
+
+    pub fn mutable(chars: Vec<char>) -> LispString {
+        LispString {
+            chars: Rc::new(RefCell::new(chars)),
+            mutable: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.borrow().is_empty()
+    }
+
+    pub fn chars(&self) -> Vec<char> {
+        self.chars.borrow().clone()
+    }
+
+    pub fn get(&self, k: usize) -> Option<char> {
+        self.chars.borrow().get(k).copied()
+    }
+
+    /// Identifies the underlying storage, shared by all clones of this string.
+    pub fn identity(&self) -> usize {
+        Rc::as_ptr(&self.chars) as usize
+    }
+
+    /// Writes the string as a literal that reads back as the same string: quoted, with
+    /// R7RS escapes for quotes, backslashes and control characters.
+    pub fn write_literal(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"")?;
+        for c in self.chars.borrow().iter() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                '\r' => write!(f, "\\r")?,
+                '\x07' => write!(f, "\\a")?,
+                c if c.is_control() => write!(f, "\\x{:x};", *c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+
+    pub fn set(&self, k: usize, c: char) -> Result<(), String> {
+        if !self.mutable {
+            return Err("Cannot modify an immutable string".to_string());
+        }
+        let mut chars = self.chars.borrow_mut();
+        let len = chars.len();
+        let slot = chars.get_mut(k).ok_or(format!(
+            "Index {} out of range for string of length {}",
+            k, len
+        ))?;
+        *slot = c;
+        Ok(())
+    }
+}
+
+impl PartialEq for LispString {
+    fn eq(&self, other: &Self) -> bool {
+        self.chars == other.chars
+    }
+}
+
+impl Eq for LispString {}
+
+impl PartialOrd for LispString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LispString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.chars.cmp(&other.chars)
+    }
+}
+
+impl Hash for LispString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chars.borrow().hash(state)
+    }
+}
+
+impl std::fmt::Display for LispString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.chars.borrow().iter().collect::<String>())
+    }
+}
+
+impl Debug for LispString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+fn string_arg<'a>(v: &'a [LispVal], i: usize, who: &str) -> Result<&'a LispString, String> {
+    v.get(i).and_then(LispVal::as_string).ok_or(format!(
+        "{} expects a string as argument {}",
+        who,
+        i + 1
+    ))
+}
+
+fn char_arg(v: &[LispVal], i: usize, who: &str) -> Result<char, String> {
+    v.get(i).and_then(LispVal::as_char).ok_or(format!(
+        "{} expects a character as argument {}",
+        who,
+        i + 1
+    ))
+}
+
+fn index_arg(v: &[LispVal], i: usize, who: &str) -> Result<usize, String> {
+    v.get(i)
+        .and_then(LispVal::to_integer)
+        .and_then(|k| usize::try_from(k).ok())
+        .ok_or(format!(
+            "{} expects a non-negative integer as argument {}",
+            who,
+            i + 1
+        ))
+}
+
+/// Reads the optional `start` and `end` arguments found at `from` and `from + 1`.
+fn range_args(v: &[LispVal], from: usize, len: usize, who: &str) -> Result<(usize, usize), String> {
+    let start = match v.get(from) {
+        Some(_) => index_arg(v, from, who)?,
+        None => 0,
+    };
+    let end = match v.get(from + 1) {
+        Some(_) => index_arg(v, from + 1, who)?,
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(format!(
+            "{}: range [{}, {}) is invalid for string of length {}",
+            who, start, end, len
+        ));
+    }
+    Ok((start, end))
+}
+
+/// A mutable string of `chars`, which are charged to the heap. Primitives making long
+/// strings get the vector from `gc::vec_with_capacity`, so that it fits.
+fn new_string(chars: Vec<char>) -> LispVal {
+    gc::charge(chars.len() * std::mem::size_of::<char>());
+    LispVal::String(LispString::mutable(chars))
+}
+
+pub(super) fn is_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let [x] = v.as_slice() else {
+        return Err("string? expects 1 argument".to_string());
+    };
+    Ok(LispVal::Bool(x.as_string().is_some()))
+}
+
+pub(super) fn make_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let k = index_arg(&v, 0, "make-string")?;
+    let fill = match v.len() {
+        1 => ' ',
+        2 => char_arg(&v, 1, "make-string")?,
+        _ => return Err("make-string expects 1 or 2 arguments".to_string()),
+    };
+    let mut chars = gc::vec_with_capacity(k)?;
+    chars.resize(k, fill);
+    Ok(new_string(chars))
+}
+
+pub(super) fn string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let mut chars = gc::vec_with_capacity(v.len())?;
+    for i in 0..v.len() {
+        limits::poll()?;
+        chars.push(char_arg(&v, i, "string")?);
+    }
+    Ok(new_string(chars))
+}
+
+pub(super) fn string_length(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string-length")?;
+    Ok(LispVal::Integer(s.len() as i64))
+}
+
+pub(super) fn string_ref(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string-ref")?;
+    let k = index_arg(&v, 1, "string-ref")?;
+    s.get(k).map(LispVal::Char).ok_or(format!(
+        "string-ref: index {} out of range for string of length {}",
+        k,
+        s.len()
+    ))
+}
+
+pub(super) fn string_set(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string-set!")?;
+    let k = index_arg(&v, 1, "string-set!")?;
+    let c = char_arg(&v, 2, "string-set!")?;
+    s.set(k, c).map_err(|e| format!("string-set!: {}", e))?;
+    Ok(LispVal::Unspecified)
+}
+
+pub(super) fn substring(v: Vec<LispVal>) -> Result<LispVal, String> {
+    if v.len() != 3 {
+        return Err("substring expects 3 arguments".to_string());
+    }
+    copy(&v, "substring")
+}
+
+pub(super) fn string_copy(v: Vec<LispVal>) -> Result<LispVal, String> {
+    copy(&v, "string-copy")
+}
+
+/// The part of the string `v` starts with that its optional range selects.
+fn copy(v: &[LispVal], who: &str) -> Result<LispVal, String> {
+    let s = string_arg(v, 0, who)?;
+    let chars = s.chars();
+    let (start, end) = range_args(v, 1, chars.len(), who)?;
+    let mut copy = gc::vec_with_capacity(end - start)?;
+    copy.extend_from_slice(&chars[start..end]);
+    Ok(new_string(copy))
+}
+
+pub(super) fn string_append(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let mut len = 0usize;
+    for i in 0..v.len() {
+        len = len.saturating_add(string_arg(&v, i, "string-append")?.len());
+    }
+    let mut chars = gc::vec_with_capacity(len)?;
+    for s in &v {
+        limits::poll()?;
+        chars.extend(s.as_string().map(LispString::chars).unwrap_or_default());
+    }
+    Ok(new_string(chars))
+}
+
+pub(super) fn string_to_list(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string->list")?;
+    let chars = s.chars();
+    let (start, end) = range_args(&v, 1, chars.len(), "string->list")?;
+    pair::reserve(end - start)?;
+    let mut items = gc::vec_with_capacity(end - start)?;
+    items.extend(chars[start..end].iter().copied().map(LispVal::Char));
+    pair::try_list(items)
+}
+
+pub(super) fn list_to_string(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let (Some(l), [_]) = (v.first().and_then(pair::items), v.as_slice()) else {
+        return Err("list->string expects a list".to_string());
+    };
+    string(l)
+}
+
+pub(super) fn string_to_symbol(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string->symbol")?;
+    Ok(LispVal::Symbol(Symbol::intern(&s.to_string())))
+}
+
+pub(super) fn string_upcase(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string-upcase")?;
+    gc::reserve(s.len() * std::mem::size_of::<char>())?;
+    Ok(new_string(s.to_string().to_uppercase().chars().collect()))
+}
+
+pub(super) fn string_downcase(v: Vec<LispVal>) -> Result<LispVal, String> {
+    let s = string_arg(&v, 0, "string-downcase")?;
+    gc::reserve(s.len() * std::mem::size_of::<char>())?;
+    Ok(new_string(s.to_string().to_lowercase().chars().collect()))
+}
+
+/// Builds one member of the `string=?` / `string<?` families. Every adjacent pair
+/// of arguments must satisfy `accept` on their ordering.
+pub(super) fn string_compare(
+    who: &'static str,
+    fold_case: bool,
+    accept: fn(Ordering) -> bool,
+) -> impl Fn(Vec<LispVal>) -> Result<LispVal, String> {
+    move |v| {
+        if v.len() < 2 {
+            return Err(format!("{} expects at least 2 arguments", who));
+        }
+        let strings = (0..v.len())
+            .map(|i| {
+                let s = string_arg(&v, i, who)?.to_string();
+                Ok(if fold_case { s.to_lowercase() } else { s })
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(LispVal::Bool(
+            strings.windows(2).all(|w| accept(w[0].cmp(&w[1]))),
+        ))
+    }
+}
+
+/// Collects the characters of the string arguments of `string-map` and `string-for-each`,
+/// truncated to the shortest string.
+pub(super) fn string_columns(strings: &[LispVal], who: &str) -> Result<Vec<Vec<LispVal>>, String> {
+    if strings.is_empty() {
+        return Err(format!("{} expects at least one string", who));
+    }
+    let strings = (0..strings.len())
+        .map(|i| string_arg(strings, i, who).map(LispString::chars))
+        .collect::<Result<Vec<Vec<char>>, String>>()?;
+    let len = strings.iter().map(Vec::len).min().unwrap_or(0);
+    Ok((0..len)
+        .map(|k| strings.iter().map(|s| LispVal::Char(s[k])).collect())
+        .collect())
+}
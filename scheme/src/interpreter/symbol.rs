@@ -0,0 +1,124 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Debug,
+    hash::{BuildHasherDefault, Hasher},
+    sync::{Mutex, OnceLock},
+};
+
+/// An interned name. Two symbols are the same symbol iff they have the same id,
+/// so comparing them never looks at the characters.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+/// Interned names are never freed, so they can be handed out as `&'static str`.
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+thread_local! {
+    /// The names interned so far, copied from the interner on demand so that looking up
+    /// a name does not take its lock.
+    static NAMES: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut interner = interner().lock().unwrap();
+        if let Some(symbol) = interner.ids.get(name) {
+            return *symbol;
+        }
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let symbol = Symbol(interner.names.len() as u32);
+        interner.names.push(name);
+        interner.ids.insert(name, symbol);
+        symbol
+    }
+
+    pub fn name(&self) -> &'static str {
+        NAMES.with(|names| {
+            let mut names = names.borrow_mut();
+            if let Some(name) = names.get(self.0 as usize) {
+                return *name;
+            }
+            let interner = interner().lock().unwrap();
+            let known = names.len();
+            names.extend_from_slice(&interner.names[known..]);
+            names[self.0 as usize]
+        })
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.name() == other
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.name())
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A map keyed by symbols, for the lookups evaluation makes all the time.
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+/// Hashes a symbol by spreading the bits of its id, which are unique already, rather
+/// than running SipHash over them.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u32(*byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, id: u32) {
+        self.0 = (self.0.rotate_left(5) ^ id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::intern("interned");
+        assert_eq!(a, Symbol::intern("interned"));
+        assert_ne!(a, Symbol::intern("other"));
+        assert_eq!(a.name(), "interned");
+    }
+}
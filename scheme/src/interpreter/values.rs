@@ -0,0 +1,52 @@
+use crate::parser::parser::LispVal;
+
+use super::Interpreter;
+
+/// Wraps the results of `values`. A single value stands for itself, so only zero or
+/// several values are ever packed into a `LispVal::Values`.
+pub(crate) fn from_vec(mut v: Vec<LispVal>) -> LispVal {
+    match v.len() {
+        1 => v.pop().unwrap(),
+        _ => LispVal::Values(v),
+    }
+}
+
+/// The values an expression returned, as arguments for a consumer.
+pub(crate) fn into_vec(v: LispVal) -> Vec<LispVal> {
+    match v {
+        LispVal::Values(v) => v,
+        v => vec![v],
+    }
+}
+
+pub(super) fn values(v: Vec<LispVal>) -> Result<LispVal, String> {
+    Ok(from_vec(v))
+}
+
+/// `(call-with-values producer consumer)`
+pub(super) fn call_with_values(
+    interpreter: &mut Interpreter,
+    v: Vec<LispVal>,
+) -> Result<LispVal, String> {
+    let [producer, consumer] = v.as_slice() else {
+        return Err("call-with-values expects 2 arguments".to_string());
+    };
+    let produced = interpreter.apply_procedure("call-with-values", producer, &[])?;
+    interpreter.apply_procedure("call-with-values", consumer, &into_vec(produced))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_value_is_not_wrapped() {
+        assert_eq!(from_vec(vec![LispVal::Integer(1)]), LispVal::Integer(1));
+        assert_eq!(from_vec(Vec::new()), LispVal::Values(Vec::new()));
+        assert_eq!(into_vec(LispVal::Integer(1)), vec![LispVal::Integer(1)]);
+        assert_eq!(
+            into_vec(from_vec(vec![LispVal::Integer(1), LispVal::Integer(2)])),
+            vec![LispVal::Integer(1), LispVal::Integer(2)]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::rc::Rc;
+
+use crate::parser::parser::LispVal;
+
+use super::{
+    bytecode::{self, Chunk, Op},
+    environment::Environment,
+    equivalence,
+    gc::{self, Gc},
+    promise::Promise,
+    stream::StreamPair,
+    Interpreter,
+};
+
+/// A call waiting for the procedure it made to return.
+struct Frame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: Environment,
+    /// the depth of evaluation to return to
+    depth: usize,
+}
+
+impl Interpreter {
+    /// Compiles `form` and runs it in the current environment, which is restored
+    /// afterwards.
+    pub(super) fn execute(&mut self, form: &LispVal) -> Result<LispVal, String> {
+        let caller_env = self.env.clone();
+        let result = self.run(Rc::new(bytecode::compile(form)));
+        self.env = caller_env;
+        result
+    }
+
+    /// Runs `chunk` in the current environment. Calls to closures push a frame instead of
+    /// recursing, and tail calls replace the frame of the caller, so neither grows the
+    /// Rust stack; the current environment is left as the last frame set it.
+    pub(super) fn run(&mut self, chunk: Rc<Chunk>) -> Result<LispVal, String> {
+        let depth = self.meter.enter()?;
+        let result = self.run_frames(chunk);
+        self.meter.leave(depth);
+        result
+    }
+
+    /// The loop of `run`. Frames count as nested evaluations for the depth limit, though
+    /// they do not use the Rust stack.
+    fn run_frames(&mut self, chunk: Rc<Chunk>) -> Result<LispVal, String> {
+        let mut stack: Vec<LispVal> = Vec::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut chunk = chunk;
+        let mut pc = 0;
+        loop {
+            let op = chunk.code[pc];
+            pc += 1;
+            match op {
+                Op::Const(i) => stack.push(chunk.constants[i].clone()),
+                Op::Lookup(name) => stack.push(
+                    self.env
+                        .lookup(name)
+                        .ok_or_else(|| format!("unknown atom {}", name.name()))?,
+                ),
+                Op::Define(name) => self.env.new_binding(name, top(&stack).clone()),
+                Op::Pop => {
+                    stack.pop();
+                }
+                Op::Dup => stack.push(top(&stack).clone()),
+                Op::Swap => {
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 2);
+                }
+                Op::Jump(target) => pc = target,
+                Op::JumpIfFalse(target) => {
+                    if pop(&mut stack) == LispVal::Bool(false) {
+                        pc = target;
+                    }
+                }
+                Op::JumpIfFalseOrPop(target) => match top(&stack) {
+                    LispVal::Bool(false) => pc = target,
+                    _ => {
+                        stack.pop();
+                    }
+                },
+                Op::JumpIfTrueOrPop(target) => match top(&stack) {
+                    LispVal::Bool(false) => {
+                        stack.pop();
+                    }
+                    _ => pc = target,
+                },
+                Op::Memv(i) => {
+                    let LispVal::List(data) = &chunk.constants[i] else {
+                        unreachable!("the compiler makes a list of the data")
+                    };
+                    let key = top(&stack);
+                    let found = data.iter().any(|d| equivalence::eqv(d, key));
+                    stack.push(LispVal::Bool(found));
+                }
+                Op::Closure(i) => stack.push(LispVal::Function(Gc::new(
+                    chunk.lambdas[i].instantiate(self.env.clone()),
+                ))),
+                Op::CaseLambda { start, count } => {
+                    let clauses = chunk.lambdas[start..start + count]
+                        .iter()
+                        .map(|lambda| lambda.instantiate(self.env.clone()))
+                        .collect();
+                    stack.push(LispVal::CaseLambda(Gc::new(clauses)));
+                }
+                Op::Delay { expr, force } => {
+                    let expr = chunk.constants[expr].clone();
+                    let env = self.env.clone();
+                    stack.push(LispVal::Promise(match force {
+                        true => Promise::delay_force(expr, env),
+                        false => Promise::delay(expr, env),
+                    }));
+                }
+                Op::StreamCons { eager } => {
+                    let LispVal::Promise(cdr) = pop(&mut stack) else {
+                        unreachable!("the compiler delays the cdr")
+                    };
+                    let car = match pop(&mut stack) {
+                        car if eager => Promise::done(car),
+                        LispVal::Promise(car) => car,
+                        _ => unreachable!("the compiler delays the car"),
+                    };
+                    stack.push(LispVal::StreamPair(StreamPair { car, cdr }));
+                }
+                Op::Bind { variables, again } => {
+                    if again {
+                        gc::maybe_collect();
+                        self.meter.tick()?;
+                        self.env.pop_frame();
+                    }
+                    let variables = &chunk.variables[variables];
+                    let values = stack.split_off(stack.len() - variables.len());
+                    self.env.new_frame();
+                    for (var, value) in variables.iter().zip(values) {
+                        self.env.new_binding(*var, value);
+                    }
+                }
+                Op::Leave => {
+                    self.env.pop_frame();
+                }
+                Op::Call { argc, name } | Op::TailCall { argc, name } => {
+                    gc::maybe_collect();
+                    self.meter.tick()?;
+                    let base = stack.len() - argc;
+                    let f = match &stack[base - 1] {
+                        LispVal::Function(f) => f,
+                        LispVal::CaseLambda(clauses) => {
+                            Self::select_clause(name.name(), clauses, argc)?
+                        }
+                        _ => {
+                            let operands = stack.split_off(base);
+                            let procedure = pop(&mut stack);
+                            let value = match &procedure {
+                                // the operands are handed over rather than copied
+                                LispVal::Primitive { name, arity } if arity.accepts(argc) => {
+                                    self.apply_primitive(*name, operands)?
+                                }
+                                _ => self.apply_procedure(name.name(), &procedure, &operands)?,
+                            };
+                            stack.push(value);
+                            continue;
+                        }
+                    };
+                    if !f.arity().accepts(argc) {
+                        f.arity().check(name.name(), argc)?;
+                    }
+                    let env = std::mem::replace(&mut self.env, f.env.extend());
+                    let code = std::mem::replace(&mut chunk, f.code());
+                    if let Op::Call { .. } = op {
+                        frames.push(Frame {
+                            chunk: code,
+                            pc,
+                            env,
+                            depth: self.meter.enter()?,
+                        });
+                    }
+                    pc = 0;
+                    // the arguments are bound straight from the stack
+                    self.bind_params(name.name(), &f.params, &stack[base..])?;
+                    stack.truncate(base - 1);
+                }
+                Op::Return => {
+                    let Some(frame) = frames.pop() else {
+                        return Ok(pop(&mut stack));
+                    };
+                    chunk = frame.chunk;
+                    pc = frame.pc;
+                    self.env = frame.env;
+                    self.meter.leave(frame.depth);
+                }
+                Op::Eval(i) => {
+                    let form = chunk.constants[i].clone();
+                    stack.push(self.eval(&form)?);
+                }
+                Op::Fail(message) => return Err(message.to_string()),
+            }
+        }
+    }
+}
+
+fn top(stack: &[LispVal]) -> &LispVal {
+    stack.last().expect("the compiler balances the stack")
+}
+
+fn pop(stack: &mut Vec<LispVal>) -> LispVal {
+    stack.pop().expect("the compiler balances the stack")
+}
@@ -16,6 +16,8 @@ pub enum Tokens {
     Boolean(bool),
     /// #\c
     Char(char),
+    /// "string"
+    Str(String),
     /// wait for next stage...
     Unknown,
     EOF,
@@ -32,6 +34,11 @@ impl Cursor<'_> {
         Cursor { text: text.chars() }
     }
 
+    /// The text not consumed yet.
+    pub fn rest(&self) -> &str {
+        self.text.as_str()
+    }
+
     fn peek(&self) -> char {
         self.text.clone().next().unwrap_or(EOF_SYMBOL)
     }
@@ -85,6 +92,7 @@ impl Cursor<'_> {
                 self.consume();
                 Tokens::QUOTE
             }
+            '"' => self.get_string(),
             c if c.is_ascii_digit() => self.get_number(),
             '#' => self.get_hashtag_literals(),
             EOF_SYMBOL => Tokens::EOF,
@@ -118,24 +126,71 @@ impl Cursor<'_> {
 
     fn get_hashtag_literals(&mut self) -> Tokens {
         self.consume();
-        match self.consume().unwrap() {
-            't' => Tokens::Boolean(true),
-            'f' => Tokens::Boolean(false),
-            '\\' => self.get_char(),
+        match self.consume() {
+            Some('t') => Tokens::Boolean(true),
+            Some('f') => Tokens::Boolean(false),
+            Some('\\') => self.get_char(),
             _ => Tokens::Unknown,
         }
     }
 
     fn get_char(&mut self) -> Tokens {
-        let c = self.consume().unwrap();
-        if self.is_delimiter() {
-            Tokens::Char(c)
-        } else {
-            Tokens::Unknown
+        let Some(c) = self.consume() else {
+            return Tokens::Unknown;
+        };
+        if self.is_delimiter() || self.peek() == ')' {
+            return Tokens::Char(c);
+        }
+        let mut name = c.to_string();
+        name.push_str(&self.consume_while_clone(|c: char| !c.is_whitespace() && c != ')'));
+        match name.as_str() {
+            "space" => Tokens::Char(' '),
+            "newline" => Tokens::Char('\n'),
+            "tab" => Tokens::Char('\t'),
+            "null" => Tokens::Char('\0'),
+            name => match name.strip_prefix('x').and_then(hex_char) {
+                Some(c) => Tokens::Char(c),
+                None => Tokens::Unknown,
+            },
+        }
+    }
+
+    fn get_string(&mut self) -> Tokens {
+        self.consume();
+        let mut s = String::new();
+        loop {
+            match self.consume() {
+                Some('"') => return Tokens::Str(s),
+                Some('\\') => match self.consume() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('a') => s.push('\x07'),
+                    Some(c @ ('\\' | '"')) => s.push(c),
+                    // `\x41;` is the character with that hexadecimal code
+                    Some('x') => match hex_char(&self.consume_while_clone(|c| c != ';')) {
+                        Some(c) if self.consume() == Some(';') => s.push(c),
+                        _ => return Tokens::Unknown,
+                    },
+                    _ => return Tokens::Unknown,
+                },
+                Some(c) => s.push(c),
+                None => return Tokens::Unknown,
+            }
         }
     }
 }
 
+/// The character whose code is the hexadecimal number `digits`.
+fn hex_char(digits: &str) -> Option<char> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,6 +277,48 @@ mod tests {
         assert!(UNREACHABLE)
     }
 
+    #[test]
+    fn parse_named_char_test() {
+        let mut lexer = Cursor::new(r"#\space #\a)");
+        let Tokens::Char(' ') = lexer.get_next_token() else {
+            unreachable!();
+        };
+        let Tokens::Char('a') = lexer.get_next_token() else {
+            unreachable!();
+        };
+    }
+
+    #[test]
+    fn parse_string_test() {
+        let mut lexer = Cursor::new(r#""a \"quoted\"\nline" 1"#);
+        if let Tokens::Str(s) = lexer.get_next_token() {
+            assert_eq!(s, "a \"quoted\"\nline");
+        } else {
+            unreachable!();
+        }
+        let Tokens::Int(1) = lexer.get_next_token() else {
+            unreachable!();
+        };
+    }
+
+    #[test]
+    fn parse_hex_escape_test() {
+        let mut lexer = Cursor::new(r#""\x41;\x3bb;\x1;" #\x7f #\x "\x41""#);
+        let Tokens::Str(s) = lexer.get_next_token() else {
+            unreachable!();
+        };
+        assert_eq!(s, "A\u{3bb}\u{1}");
+        let Tokens::Char('\u{7f}') = lexer.get_next_token() else {
+            unreachable!();
+        };
+        let Tokens::Char('x') = lexer.get_next_token() else {
+            unreachable!();
+        };
+        let Tokens::Unknown = lexer.get_next_token() else {
+            unreachable!();
+        };
+    }
+
     #[test]
     fn parse_two_tokens() {
         let tokens_test = r"123.456 #t";
@@ -15,6 +15,9 @@ mod parser;
 
 pub mod interpreter;
 
+#[cfg(feature = "serde")]
+pub mod sexp;
+
 mod code_generator;
 
 mod value;
@@ -1,14 +1,64 @@
-use crate::lexer::{self, Cursor, Tokens};
+use std::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+use crate::{
+    interpreter::{
+        environment::Environment,
+        gc::Gc,
+        hash_table::HashTable,
+        pair::{self, Pair, Printer},
+        parameter::Parameter,
+        port::Port,
+        procedure::{Arity, Closure},
+        promise::Promise,
+        record::{Record, RecordProcedure},
+        stream::StreamPair,
+        strings::LispString,
+        symbol::Symbol,
+    },
+    lexer::{self, Cursor, Tokens},
+};
+
+#[derive(Debug, Clone)]
 pub enum LispVal {
-    Atom(String),
+    /// an identifier in source code
+    Atom(Symbol),
+    /// a symbol datum, e.g. the value of `'a`
+    Symbol(Symbol),
+    /// a self-evaluating keyword such as `key:`, naming an argument of a `lambda*`
+    Keyword(Symbol),
+    /// a list as written in the source; also the empty list
     List(Vec<LispVal>),
+    /// a mutable pair, the building block of the lists made at runtime
+    Pair(Gc<Pair>),
     Integer(i64),
     Bool(bool),
-    Function {
-        params: Vec<String>,
-        body: Vec<LispVal>,
+    Char(char),
+    String(LispString),
+    HashTable(HashTable),
+    Record(Record),
+    RecordProcedure(RecordProcedure),
+    Promise(Promise),
+    StreamPair(StreamPair),
+    Port(Port),
+    /// the end-of-file object returned by reading from an exhausted port
+    Eof,
+    /// the value of expressions that return nothing useful, such as `string-set!`
+    Unspecified,
+    /// zero or several values returned by `values`; a single value is returned as itself
+    Values(Vec<LispVal>),
+    /// a parameter object made by `make-parameter`
+    Parameter(Parameter),
+    /// an environment specifier, as returned by `interaction-environment`
+    Environment(Environment),
+    Function(Gc<Closure>),
+    /// the clauses of a `case-lambda`, tried in order until one accepts the arguments
+    CaseLambda(Gc<Vec<Closure>>),
+    Primitive {
+        name: Symbol,
+        arity: Arity,
     },
 }
 
@@ -19,50 +69,341 @@ impl LispVal {
             _ => None,
         }
     }
+
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            LispVal::Char(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&LispString> {
+        match self {
+            LispVal::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_symbol(&self) -> Option<Symbol> {
+        match self {
+            LispVal::Symbol(s) => Some(*s),
+            _ => None,
+        }
+    }
+
+    /// Turns source code into the datum denoted by quoting it: identifiers become symbols
+    /// and lists become pairs, so that taking their `cdr` does not copy them.
+    pub fn to_datum(&self) -> LispVal {
+        match self {
+            LispVal::Atom(s) => LispVal::Symbol(*s),
+            LispVal::List(v) => match v.as_slice() {
+                [] => LispVal::List(Vec::new()),
+                [items @ .., LispVal::Atom(dot), tail] if dot == "." && !items.is_empty() => {
+                    pair::constant_list_with_tail(
+                        items.iter().map(LispVal::to_datum).collect(),
+                        tail.to_datum(),
+                    )
+                }
+                _ => pair::constant_list_with_tail(
+                    v.iter().map(LispVal::to_datum).collect(),
+                    LispVal::List(Vec::new()),
+                ),
+            },
+            other => other.clone(),
+        }
+    }
+
+    /// The inverse of `to_datum`, so that data built at runtime can be evaluated.
+    pub fn to_code(&self) -> LispVal {
+        match self {
+            LispVal::Symbol(s) => LispVal::Atom(*s),
+            LispVal::List(v) => LispVal::List(v.iter().map(LispVal::to_code).collect()),
+            LispVal::Pair(_) => match pair::items(self) {
+                Some(items) => LispVal::List(items.iter().map(LispVal::to_code).collect()),
+                None => self.clone(),
+            },
+            other => other.clone(),
+        }
+    }
+}
+
+/// Lists are equal when their elements are, whether they are made of pairs or written
+/// in the source. Everything else is compared like `eqv?` does, except strings, which
+/// are compared by content.
+impl PartialEq for LispVal {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                LispVal::List(_) | LispVal::Pair(_) | LispVal::Values(_),
+                LispVal::List(_) | LispVal::Pair(_) | LispVal::Values(_),
+            ) => pair::equal(self, other),
+            (LispVal::Atom(a), LispVal::Atom(b)) => a == b,
+            (LispVal::Symbol(a), LispVal::Symbol(b)) => a == b,
+            (LispVal::Keyword(a), LispVal::Keyword(b)) => a == b,
+            (LispVal::Integer(a), LispVal::Integer(b)) => a == b,
+            (LispVal::Bool(a), LispVal::Bool(b)) => a == b,
+            (LispVal::Char(a), LispVal::Char(b)) => a == b,
+            (LispVal::String(a), LispVal::String(b)) => a == b,
+            (LispVal::HashTable(a), LispVal::HashTable(b)) => a == b,
+            (LispVal::Record(a), LispVal::Record(b)) => a == b,
+            (LispVal::RecordProcedure(a), LispVal::RecordProcedure(b)) => a == b,
+            (LispVal::Promise(a), LispVal::Promise(b)) => a == b,
+            (LispVal::StreamPair(a), LispVal::StreamPair(b)) => a == b,
+            (LispVal::Port(a), LispVal::Port(b)) => a == b,
+            (LispVal::Eof, LispVal::Eof) => true,
+            (LispVal::Unspecified, LispVal::Unspecified) => true,
+            (LispVal::Parameter(a), LispVal::Parameter(b)) => a == b,
+            (LispVal::Environment(a), LispVal::Environment(b)) => a == b,
+            (LispVal::Function(a), LispVal::Function(b)) => a == b,
+            (LispVal::CaseLambda(a), LispVal::CaseLambda(b)) => a == b,
+            (
+                LispVal::Primitive { name, arity },
+                LispVal::Primitive {
+                    name: other_name,
+                    arity: other_arity,
+                },
+            ) => name == other_name && arity == other_arity,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LispVal {}
+
+impl Hash for LispVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            LispVal::List(_) | LispVal::Pair(_) | LispVal::Values(_) => {
+                return pair::hash_structure(self, state)
+            }
+            _ => std::mem::discriminant(self).hash(state),
+        }
+        match self {
+            LispVal::Atom(s) => s.hash(state),
+            LispVal::Symbol(s) | LispVal::Keyword(s) => s.hash(state),
+            LispVal::Integer(i) => i.hash(state),
+            LispVal::Bool(b) => b.hash(state),
+            LispVal::Char(c) => c.hash(state),
+            LispVal::String(s) => s.hash(state),
+            LispVal::HashTable(t) => t.hash(state),
+            LispVal::Record(r) => r.hash(state),
+            LispVal::RecordProcedure(p) => p.hash(state),
+            LispVal::Promise(p) => p.hash(state),
+            LispVal::StreamPair(p) => p.hash(state),
+            LispVal::Port(p) => p.hash(state),
+            LispVal::Parameter(p) => p.hash(state),
+            LispVal::Environment(env) => env.hash(state),
+            LispVal::Function(f) => f.hash(state),
+            LispVal::CaseLambda(f) => f.hash(state),
+            LispVal::Primitive { name, arity } => (name, arity).hash(state),
+            LispVal::List(_)
+            | LispVal::Pair(_)
+            | LispVal::Values(_)
+            | LispVal::Eof
+            | LispVal::Unspecified => {}
+        }
+    }
+}
+
+/// Prints values the way `write` does in Scheme.
+impl Display for LispVal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LispVal::Atom(s) => write!(f, "{}", s),
+            LispVal::Symbol(s) => write!(f, "{}", s),
+            LispVal::Keyword(s) => write!(f, "{}:", s),
+            LispVal::List(_) | LispVal::Pair(_) => Printer::print(self, f, false),
+            LispVal::Integer(i) => write!(f, "{}", i),
+            LispVal::Bool(true) => write!(f, "#t"),
+            LispVal::Bool(false) => write!(f, "#f"),
+            LispVal::Char(' ') => write!(f, "#\\space"),
+            LispVal::Char('\n') => write!(f, "#\\newline"),
+            LispVal::Char('\t') => write!(f, "#\\tab"),
+            LispVal::Char('\0') => write!(f, "#\\null"),
+            LispVal::Char(c) if c.is_control() => write!(f, "#\\x{:x}", *c as u32),
+            LispVal::Char(c) => write!(f, "#\\{}", c),
+            LispVal::String(s) => s.write_literal(f),
+            LispVal::HashTable(_) => write!(f, "#<hash-table>"),
+            LispVal::Record(r) => write!(f, "#<record {}>", r.rtd().display_name()),
+            LispVal::RecordProcedure(p) => write!(f, "#<procedure {}>", p.name),
+            LispVal::Promise(_) => write!(f, "#<promise>"),
+            LispVal::StreamPair(_) => write!(f, "#<stream>"),
+            LispVal::Port(_) => write!(f, "#<port>"),
+            LispVal::Parameter(_) => write!(f, "#<parameter>"),
+            LispVal::Environment(_) => write!(f, "#<environment>"),
+            LispVal::Eof => write!(f, "#<eof>"),
+            LispVal::Unspecified => write!(f, "#<unspecified>"),
+            LispVal::Values(v) => {
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                Ok(())
+            }
+            LispVal::Function(_) | LispVal::CaseLambda(_) => write!(f, "#<procedure>"),
+            LispVal::Primitive { name, .. } => write!(f, "#<procedure {}>", name),
+        }
+    }
+}
+
+/// Prints values the way `display` does: strings and characters are not quoted.
+pub struct Displayed<'a>(pub &'a LispVal);
+
+impl Display for Displayed<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            LispVal::String(s) => write!(f, "{}", s),
+            LispVal::Char(c) => write!(f, "{}", c),
+            LispVal::List(_) | LispVal::Pair(_) => Printer::print(self.0, f, true),
+            v => write!(f, "{}", v),
+        }
+    }
 }
 
 pub struct Parser<'a> {
     lexer: Cursor<'a>,
+    /// how deeply lists and quotes may nest in the text read. Reading does not recurse,
+    /// but analyzing and compiling the code read do
+    max_nesting: Option<usize>,
+}
+
+/// A datum being read that the tokens read next go into.
+enum Open {
+    List(Vec<LispVal>),
+    Quote,
 }
 
 impl Parser<'_> {
-    pub fn new(text: &str) -> Parser {
+    #[cfg(test)]
+    pub fn new(text: &str) -> Parser<'_> {
+        Parser::with_max_nesting(text, None)
+    }
+
+    /// A parser failing on lists and quotes nested more than `max_nesting` deep.
+    pub fn with_max_nesting(text: &str, max_nesting: Option<usize>) -> Parser<'_> {
         Parser {
             lexer: Cursor::new(text),
+            max_nesting,
         }
     }
 
+    #[cfg(test)]
     pub fn parse(&mut self) -> LispVal {
-        let tok = self.lexer.get_next_token();
-        self.parse_literals(tok)
+        self.read()
+            .and_then(|v| v.ok_or(ParseError::Incomplete))
+            .unwrap_or_else(|e| panic!("{}", e))
     }
 
-    fn parse_literals(&mut self, l: Tokens) -> LispVal {
-        match l {
-            lexer::Tokens::RPAREN => todo!(),
-            lexer::Tokens::Float(_) => todo!(),
-            lexer::Tokens::QUOTE => {
-                LispVal::List(vec![LispVal::Atom("quote".to_string()), self.parse()])
-            }
-            lexer::Tokens::Char(_) => todo!(),
-            lexer::Tokens::Unknown => todo!(),
-            lexer::Tokens::EOF => todo!(),
-            Tokens::Atom(s) => LispVal::Atom(s),
-            Tokens::Int(i) => LispVal::Integer(i),
-            Tokens::Boolean(b) => LispVal::Bool(b),
-            Tokens::LPAREN => self.parse_list(),
+    /// Reads the next datum, or `None` when only whitespace is left.
+    pub fn read(&mut self) -> Result<Option<LispVal>, ParseError> {
+        match self.lexer.get_next_token() {
+            Tokens::EOF => Ok(None),
+            tok => self.parse_literals(tok).map(Some),
         }
     }
 
-    fn parse_list(&mut self) -> LispVal {
-        let mut list_children = Vec::new();
+    /// The text after the last datum read.
+    pub fn rest(&self) -> &str {
+        self.lexer.rest()
+    }
+
+    /// Reads the datum starting with `l`, keeping the lists and quotes it is in on a
+    /// stack rather than recursing.
+    fn parse_literals(&mut self, l: Tokens) -> Result<LispVal, ParseError> {
+        let mut open = Vec::new();
+        let mut token = l;
         loop {
-            match self.lexer.get_next_token() {
-                lexer::Tokens::RPAREN => break,
-                otherwise => list_children.push(self.parse_literals(otherwise)),
+            let opened = match token {
+                Tokens::LPAREN => Some(Open::List(Vec::new())),
+                Tokens::QUOTE => Some(Open::Quote),
+                _ => None,
+            };
+            if let Some(opened) = opened {
+                if let Some(max) = self.max_nesting.filter(|max| open.len() >= *max) {
+                    return Err(ParseError::Invalid(format!(
+                        "lists are nested more than {} deep",
+                        max
+                    )));
+                }
+                open.push(opened);
+                token = self.lexer.get_next_token();
+                continue;
+            }
+            let mut value = match token {
+                Tokens::RPAREN => match open.pop() {
+                    Some(Open::List(items)) => LispVal::List(items),
+                    _ => return Err(ParseError::Invalid("unexpected )".to_string())),
+                },
+                token => self.parse_token(token)?,
+            };
+            loop {
+                match open.last_mut() {
+                    None => return Ok(value),
+                    Some(Open::List(items)) => {
+                        items.push(value);
+                        break;
+                    }
+                    Some(Open::Quote) => {
+                        open.pop();
+                        value = LispVal::List(vec![LispVal::Atom(Symbol::intern("quote")), value]);
+                    }
+                }
+            }
+            token = self.lexer.get_next_token();
+        }
+    }
+
+    /// The datum of a token that does not start a list or a quotation.
+    fn parse_token(&mut self, l: Tokens) -> Result<LispVal, ParseError> {
+        Ok(match l {
+            lexer::Tokens::Float(f) => {
+                return Err(ParseError::Invalid(format!(
+                    "floating point numbers are not supported: {}",
+                    f
+                )))
+            }
+            lexer::Tokens::Char(c) => LispVal::Char(c),
+            lexer::Tokens::Str(s) => LispVal::String(LispString::immutable(&s)),
+            // an unfinished string or character may go on in text not seen yet
+            lexer::Tokens::Unknown if self.lexer.rest().is_empty() => {
+                return Err(ParseError::Incomplete)
+            }
+            lexer::Tokens::Unknown => {
+                return Err(ParseError::Invalid(format!(
+                    "bad token before {:?}",
+                    self.lexer.rest().lines().next().unwrap_or_default()
+                )))
             }
+            lexer::Tokens::EOF => return Err(ParseError::Incomplete),
+            Tokens::Atom(s) => match s.strip_suffix(':') {
+                Some(key) if !key.is_empty() => LispVal::Keyword(Symbol::intern(key)),
+                _ => LispVal::Atom(Symbol::intern(&s)),
+            },
+            Tokens::Int(i) => LispVal::Integer(i),
+            Tokens::Boolean(b) => LispVal::Bool(b),
+            Tokens::RPAREN | Tokens::LPAREN | Tokens::QUOTE => {
+                unreachable!("parse_literals handles {:?}", l)
+            }
+        })
+    }
+}
+
+/// Why `Parser::read` could not produce a datum.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// the text ends in the middle of a datum
+    Incomplete,
+    Invalid(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "unexpected end of input"),
+            ParseError::Invalid(e) => write!(f, "{}", e),
         }
-        LispVal::List(list_children)
     }
 }
 
@@ -84,7 +425,7 @@ mod tests {
     fn test_parse_easy_list() {
         let mut parser = Parser::new("(+ 1 1)");
         if let LispVal::List(v) = parser.parse() {
-            assert_eq!(v[0], LispVal::Atom("+".to_string()));
+            assert_eq!(v[0], LispVal::Atom("+".into()));
             assert_eq!(v[1], LispVal::Integer(1));
             assert_eq!(v[2], LispVal::Integer(1));
         } else {
@@ -96,7 +437,7 @@ mod tests {
     fn test_many_add() {
         let mut parser = Parser::new("(+ 1 1 1 1 1 1)");
         let equivlent_lispval = LispVal::List(vec![
-            LispVal::Atom("+".to_string()),
+            LispVal::Atom("+".into()),
             LispVal::Integer(1),
             LispVal::Integer(1),
             LispVal::Integer(1),
@@ -119,30 +460,30 @@ mod tests {
         );
 
         let equivlent_lispval = LispVal::List(vec![
-            LispVal::Atom("*".to_string()),
+            LispVal::Atom("*".into()),
             LispVal::List(vec![
-                LispVal::Atom("cond".to_string()),
+                LispVal::Atom("cond".into()),
                 LispVal::List(vec![
                     LispVal::List(vec![
-                        LispVal::Atom(">".to_string()),
-                        LispVal::Atom("a".to_string()),
-                        LispVal::Atom("b".to_string()),
+                        LispVal::Atom(">".into()),
+                        LispVal::Atom("a".into()),
+                        LispVal::Atom("b".into()),
                     ]),
-                    LispVal::Atom("a".to_string()),
+                    LispVal::Atom("a".into()),
                 ]),
                 LispVal::List(vec![
                     LispVal::List(vec![
-                        LispVal::Atom("<".to_string()),
-                        LispVal::Atom("a".to_string()),
-                        LispVal::Atom("b".to_string()),
+                        LispVal::Atom("<".into()),
+                        LispVal::Atom("a".into()),
+                        LispVal::Atom("b".into()),
                     ]),
-                    LispVal::Atom("b".to_string()),
+                    LispVal::Atom("b".into()),
                 ]),
-                LispVal::List(vec![LispVal::Atom("else".to_string()), LispVal::Integer(1)]),
+                LispVal::List(vec![LispVal::Atom("else".into()), LispVal::Integer(1)]),
             ]),
             LispVal::List(vec![
-                LispVal::Atom("+".to_string()),
-                LispVal::Atom("a".to_string()),
+                LispVal::Atom("+".into()),
+                LispVal::Atom("a".into()),
                 LispVal::Integer(1),
             ]),
         ]);
@@ -150,11 +491,22 @@ mod tests {
         assert_eq!(parser.parse(), equivlent_lispval);
     }
 
+    #[test]
+    fn test_parse_string_and_char() {
+        let mut parser = Parser::new(r#"(string-ref "abc" #\a)"#);
+        let equivlent_lispval = LispVal::List(vec![
+            LispVal::Atom("string-ref".into()),
+            LispVal::String(LispString::immutable("abc")),
+            LispVal::Char('a'),
+        ]);
+        assert_eq!(parser.parse(), equivlent_lispval);
+    }
+
     #[test]
     fn test_parse_quote() {
         let mut parser = Parser::new("'(1 2 3)");
         let equivlent_lispval = LispVal::List(vec![
-            LispVal::Atom("quote".to_string()),
+            LispVal::Atom("quote".into()),
             LispVal::List(vec![
                 LispVal::Integer(1),
                 LispVal::Integer(2),
@@ -163,4 +515,35 @@ mod tests {
         ]);
         assert_eq!(parser.parse(), equivlent_lispval);
     }
+
+    #[test]
+    fn test_read() {
+        let mut parser = Parser::new("(a \"b\") 1 ");
+        assert_eq!(
+            parser.read(),
+            Ok(Some(LispVal::List(vec![
+                LispVal::Atom("a".into()),
+                LispVal::String(LispString::immutable("b")),
+            ])))
+        );
+        assert_eq!(parser.rest(), " 1 ");
+        assert_eq!(parser.read(), Ok(Some(LispVal::Integer(1))));
+        assert_eq!(parser.read(), Ok(None));
+        assert_eq!(Parser::new("(a (b").read(), Err(ParseError::Incomplete));
+        assert_eq!(Parser::new("\"ab").read(), Err(ParseError::Incomplete));
+        assert_eq!(
+            Parser::new(")").read(),
+            Err(ParseError::Invalid("unexpected )".to_string()))
+        );
+
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Parser::new(&nested(1000)).read().is_ok());
+        assert!(Parser::with_max_nesting("'((1))", Some(3)).read().is_ok());
+        assert_eq!(
+            Parser::with_max_nesting("'(((1)))", Some(3)).read(),
+            Err(ParseError::Invalid(
+                "lists are nested more than 3 deep".to_string()
+            ))
+        );
+    }
 }
@@ -0,0 +1,369 @@
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+
+use crate::interpreter::{pair, LispVal};
+
+use super::{unexpected, Error};
+
+/// Converts a datum, or any value of the interpreter, into a `T`.
+pub fn from_value<T: DeserializeOwned>(value: &LispVal) -> Result<T, Error> {
+    T::deserialize(Deserializer(value.clone()))
+}
+
+/// Reads a Rust value from the datum it is written as.
+pub struct Deserializer(LispVal);
+
+impl Deserializer {
+    fn integer(&self) -> Result<i64, Error> {
+        match &self.0 {
+            LispVal::Integer(i) => Ok(*i),
+            // the reader takes negative numbers for identifiers
+            LispVal::Symbol(s) => s
+                .name()
+                .parse()
+                .map_err(|_| unexpected(&self.0, "an integer")),
+            v => Err(unexpected(v, "an integer")),
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        match &self.0 {
+            LispVal::String(s) => Some(s.to_string()),
+            LispVal::Symbol(s) | LispVal::Keyword(s) | LispVal::Atom(s) => {
+                Some(s.name().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    fn items(&self) -> Result<Vec<LispVal>, Error> {
+        pair::items(&self.0).ok_or_else(|| unexpected(&self.0, "a list"))
+    }
+
+    /// The entries of a hash table, the fields of a record or the pairs of an
+    /// association list.
+    fn entries(&self) -> Result<Vec<(LispVal, LispVal)>, Error> {
+        match &self.0 {
+            LispVal::HashTable(t) => Ok(t.entries()),
+            LispVal::Record(r) => Ok(r
+                .fields()
+                .into_iter()
+                .map(|(name, v)| (LispVal::Symbol(name), v))
+                .collect()),
+            v => pair::items(v)
+                .and_then(|items| items.iter().map(split).collect())
+                .ok_or_else(|| unexpected(v, "an association list")),
+        }
+    }
+}
+
+/// The `car` and `cdr` of a pair.
+fn split(v: &LispVal) -> Option<(LispVal, LispVal)> {
+    match v {
+        LispVal::Pair(p) => Some((p.car(), p.cdr())),
+        LispVal::List(l) => l
+            .split_first()
+            .map(|(car, cdr)| (car.clone(), LispVal::List(cdr.to_vec()))),
+        _ => None,
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.0 {
+            LispVal::Integer(i) => visitor.visit_i64(*i),
+            LispVal::Bool(b) => visitor.visit_bool(*b),
+            LispVal::Char(c) => visitor.visit_char(*c),
+            LispVal::String(s) => visitor.visit_string(s.to_string()),
+            LispVal::Symbol(_) | LispVal::Atom(_) | LispVal::Keyword(_) => match self.integer() {
+                Ok(i) => visitor.visit_i64(i),
+                Err(_) => visitor.visit_string(self.name().unwrap_or_default()),
+            },
+            LispVal::HashTable(_) | LispVal::Record(_) => self.deserialize_map(visitor),
+            LispVal::Unspecified => visitor.visit_unit(),
+            _ => self.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            LispVal::Bool(b) => visitor.visit_bool(b),
+            v => Err(unexpected(&v, "a boolean")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.integer()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f64(self.integer()? as f64)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            LispVal::Char(c) => visitor.visit_char(c),
+            v => Err(unexpected(&v, "a character")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.name() {
+            Some(s) => visitor.visit_string(s),
+            None => Err(unexpected(&self.0, "a string")),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            LispVal::Bool(false) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.0 {
+            LispVal::List(l) if l.is_empty() => visitor.visit_unit(),
+            LispVal::Unspecified => visitor.visit_unit(),
+            v => Err(unexpected(v, "()")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Seq(self.items()?.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(Map {
+            entries: self.entries()?.into_iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if let Some(variant) = self.name() {
+            return visitor.visit_enum(Enum {
+                variant,
+                content: None,
+            });
+        }
+        let (tag, content) =
+            split(&self.0).ok_or_else(|| unexpected(&self.0, "a variant or a tagged list"))?;
+        let variant = Deserializer(tag)
+            .name()
+            .ok_or_else(|| unexpected(&self.0, "a list tagged with a variant"))?;
+        visitor.visit_enum(Enum {
+            variant,
+            content: Some(content),
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct Seq(std::vec::IntoIter<LispVal>);
+
+impl<'de> de::SeqAccess<'de> for Seq {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|v| seed.deserialize(Deserializer(v)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct Map {
+    entries: std::vec::IntoIter<(LispVal, LispVal)>,
+    /// the value of the key read last
+    value: Option<LispVal>,
+}
+
+impl<'de> de::MapAccess<'de> for Map {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(value);
+        seed.deserialize(Deserializer(key)).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| Error("a map value was asked for before its key".to_string()))?;
+        seed.deserialize(Deserializer(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// A variant, with what follows it in the list it tags unless it stands alone.
+struct Enum {
+    variant: String,
+    content: Option<LispVal>,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(self.variant.clone().into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl Enum {
+    fn content(self, expected: &str) -> Result<Deserializer, Error> {
+        self.content
+            .map(Deserializer)
+            .ok_or_else(|| Error(format!("expected {} for {}", expected, self.variant)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Enum {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.content {
+            None => Ok(()),
+            Some(LispVal::List(l)) if l.is_empty() => Ok(()),
+            Some(v) => Err(unexpected(&v, &format!("nothing after {}", self.variant))),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let variant = self.variant.clone();
+        match self.content("a value")?.items()?.as_slice() {
+            [value] => seed.deserialize(Deserializer(value.clone())),
+            _ => Err(Error(format!("expected one value after {}", variant))),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.content("a list")?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.content("an association list")?, visitor)
+    }
+}
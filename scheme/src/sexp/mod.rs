@@ -0,0 +1,249 @@
+//! A serde data format for S-expressions, so that Rust data can be kept in `.scm` files.
+//!
+//! Structs and maps are written as association lists, `((name . "web") (port . 8080))`,
+//! and enums as lists tagged with the variant, `(circle 5)`, or as a bare symbol for
+//! unit variants; names that would not read back as symbols are written as strings.
+//! Sequences and tuples are lists and `None` is `#f`, so `Some` of a value written as
+//! `#f`, like `Some(false)`, cannot be written. Besides text,
+//! values of the interpreter can be converted directly, in which case records are read
+//! like association lists of their fields.
+
+mod de;
+mod ser;
+
+use std::fmt::Display;
+
+use crate::{interpreter::LispVal, parser::Parser};
+
+pub use de::{from_value, Deserializer};
+pub use ser::{to_value, Serializer};
+
+/// What went wrong converting between Rust data and S-expressions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Writes `value` as an S-expression that `from_str` reads back.
+pub fn to_string<T: serde::Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    Ok(to_value(value)?.to_string())
+}
+
+/// How deeply lists may nest in the text `from_str` reads, as deserializing recurses.
+const MAX_NESTING: usize = 128;
+
+/// Reads a `T` from text holding a single datum.
+pub fn from_str<T: serde::de::DeserializeOwned>(s: &str) -> Result<T, Error> {
+    let mut parser = Parser::with_max_nesting(s, Some(MAX_NESTING));
+    let datum = match parser.read() {
+        Ok(Some(datum)) => datum,
+        Ok(None) => return Err(Error("expected a datum, but got nothing".to_string())),
+        Err(e) => return Err(Error(e.to_string())),
+    };
+    if !parser.rest().trim().is_empty() {
+        return Err(Error(format!(
+            "unexpected text after the datum: {:?}",
+            parser.rest().trim()
+        )));
+    }
+    from_value(&datum.to_datum())
+}
+
+/// The error for finding `v` where `expected` was wanted.
+fn unexpected(v: &LispVal, expected: &str) -> Error {
+    Error(format!("expected {}, but got {}", expected, v))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(i64),
+        Point(i64, i64),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        port: u16,
+        verbose: bool,
+        hosts: Vec<String>,
+        timeout: Option<i32>,
+        shapes: Vec<Shape>,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config {
+            name: "web".to_string(),
+            port: 8080,
+            verbose: false,
+            hosts: vec!["a".to_string(), "b".to_string()],
+            timeout: None,
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(-5),
+                Shape::Point(1, 2),
+                Shape::Rect {
+                    width: 3,
+                    height: 4,
+                },
+            ],
+        };
+        let text = to_string(&config).unwrap();
+        assert_eq!(
+            text,
+            "((name . \"web\") (port . 8080) (verbose . #f) (hosts \"a\" \"b\") (timeout . #f) \
+             (shapes Empty (Circle -5) (Point 1 2) (Rect (width . 3) (height . 4))))"
+        );
+        assert_eq!(from_str::<Config>(&text), Ok(config));
+
+        let text = "tab\t bell\x07 nul\0 quote\" \u{1b}[0m λ";
+        assert_eq!(
+            to_string(text),
+            Ok("\"tab\\t bell\\a nul\\x0; quote\\\" \\x1b;[0m λ\"".to_string())
+        );
+        assert_eq!(
+            from_str::<String>(&to_string(text).unwrap()).as_deref(),
+            Ok(text)
+        );
+        assert_eq!(from_str::<char>(&to_string(&'\u{1}').unwrap()), Ok('\u{1}'));
+
+        let mut map = BTreeMap::new();
+        map.insert("x".to_string(), ('a', ()));
+        assert_eq!(to_string(&map), Ok("((\"x\" #\\a ()))".to_string()));
+        assert_eq!(from_str(&to_string(&map).unwrap()), Ok(map));
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Key {
+            #[serde(rename = "two words")]
+            Spaced,
+            #[serde(rename = "a:")]
+            Keyword {
+                #[serde(rename = "1st")]
+                first: u8,
+            },
+        }
+        let keys = vec![Key::Spaced, Key::Keyword { first: 1 }];
+        let text = to_string(&keys).unwrap();
+        assert_eq!(text, "(\"two words\" (\"a:\" (\"1st\" . 1)))");
+        assert_eq!(from_str(&text), Ok(keys));
+
+        assert_eq!(to_string(&Some(true)), Ok("#t".to_string()));
+        let ambiguous = Err(Error(
+            "Some(false) and Some(None) would be read back as None".to_string(),
+        ));
+        assert_eq!(to_string(&Some(false)), ambiguous);
+        assert_eq!(to_string(&Some(None::<i32>)), ambiguous);
+        assert_eq!(to_string(&Some(Some(3))), Ok("3".to_string()));
+    }
+
+    #[test]
+    fn test_reading() {
+        let config: Config = from_str(
+            "((hosts \"localhost\")
+              (name . \"api\")
+              (port . 80)
+              (verbose . #t)
+              (shapes (Rect (height . 1) (width . 2)) (Circle 3)))",
+        )
+        .unwrap();
+        assert_eq!(config.name, "api");
+        assert_eq!(config.timeout, None);
+        assert_eq!(
+            config.shapes,
+            vec![
+                Shape::Rect {
+                    width: 2,
+                    height: 1
+                },
+                Shape::Circle(3)
+            ]
+        );
+        assert_eq!(
+            from_str::<(char, Option<u8>)>("(#\\z 7)"),
+            Ok(('z', Some(7)))
+        );
+        assert_eq!(
+            from_str::<u8>("300"),
+            Err(Error(
+                "invalid value: integer `300`, expected u8".to_string()
+            ))
+        );
+        assert_eq!(
+            from_str::<Vec<i64>>("(1 2) 3"),
+            Err(Error("unexpected text after the datum: \"3\"".to_string()))
+        );
+        assert_eq!(
+            from_str::<Vec<i64>>("(1 2"),
+            Err(Error("unexpected end of input".to_string()))
+        );
+        assert_eq!(
+            from_str::<String>("(1)"),
+            Err(Error("expected a string, but got (1)".to_string()))
+        );
+        assert_eq!(
+            from_str::<Config>("((name . \"x\"))"),
+            Err(Error("missing field `port`".to_string()))
+        );
+        assert_eq!(
+            to_string(&1.5),
+            Err(Error(
+                "floating point numbers are not supported: 1.5".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_interpreter_values() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .interpret(
+                "(define-record-type point (make-point x y) point? (x point-x) (y point-y))
+                 (define origin (make-point 0 (- 0 1)))
+                 (define points (list (cons 'x 1) (cons 'y 2)))",
+            )
+            .unwrap();
+        let origin = interpreter.get::<LispVal>("origin").unwrap();
+        assert_eq!(from_value(&origin), Ok(Point { x: 0, y: -1 }));
+        let points = interpreter.get::<LispVal>("points").unwrap();
+        assert_eq!(from_value(&points), Ok(Point { x: 1, y: 2 }));
+        assert_eq!(
+            to_value(&Shape::Point(1, 2)).map(|v| v.to_string()),
+            Ok("(Point 1 2)".to_string())
+        );
+    }
+}
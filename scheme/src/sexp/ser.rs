@@ -0,0 +1,344 @@
+use serde::ser::{self, Serialize};
+
+use crate::interpreter::{pair, strings::LispString, symbol::Symbol, LispVal};
+
+use super::Error;
+
+/// Converts `value` into the datum it is written as.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<LispVal, Error> {
+    value.serialize(Serializer)
+}
+
+/// A field or variant name: a symbol when it reads back as one, else a string.
+fn name(s: &str) -> LispVal {
+    let symbol = match s.chars().next() {
+        Some(c) => !c.is_ascii_digit() && !matches!(c, '#' | '.'),
+        None => false,
+    } && !s.ends_with(':')
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '(' | ')' | '\'' | '"' | ';' | '`' | ','));
+    if symbol {
+        LispVal::Symbol(Symbol::intern(s))
+    } else {
+        LispVal::String(LispString::immutable(s))
+    }
+}
+
+/// Makes the datum a Rust value is written as.
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = LispVal;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeAlist;
+    type SerializeStruct = SerializeAlist;
+    type SerializeStructVariant = SerializeAlist;
+
+    fn serialize_bool(self, v: bool) -> Result<LispVal, Error> {
+        Ok(LispVal::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<LispVal, Error> {
+        Ok(LispVal::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<LispVal, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<LispVal, Error> {
+        i64::try_from(v)
+            .map(LispVal::Integer)
+            .map_err(|_| Error(format!("{} does not fit in an integer", v)))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<LispVal, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<LispVal, Error> {
+        Err(Error(format!(
+            "floating point numbers are not supported: {}",
+            v
+        )))
+    }
+
+    fn serialize_char(self, v: char) -> Result<LispVal, Error> {
+        Ok(LispVal::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<LispVal, Error> {
+        Ok(LispVal::String(LispString::immutable(v)))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<LispVal, Error> {
+        Ok(LispVal::List(
+            v.iter().map(|b| LispVal::Integer((*b).into())).collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<LispVal, Error> {
+        Ok(LispVal::Bool(false))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<LispVal, Error> {
+        match value.serialize(self)? {
+            LispVal::Bool(false) => Err(Error(
+                "Some(false) and Some(None) would be read back as None".to_string(),
+            )),
+            v => Ok(v),
+        }
+    }
+
+    fn serialize_unit(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(Vec::new()))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<LispVal, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<LispVal, Error> {
+        Ok(name(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<LispVal, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<LispVal, Error> {
+        Ok(LispVal::List(vec![name(variant), value.serialize(self)?]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None, len.unwrap_or_default()))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None, len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None, len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(Some(variant), len))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeAlist, Error> {
+        Ok(SerializeAlist::new(None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeAlist, Error> {
+        Ok(SerializeAlist::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeAlist, Error> {
+        Ok(SerializeAlist::new(Some(variant)))
+    }
+}
+
+/// A list, headed by the variant for the variants of enums.
+pub struct SerializeList {
+    items: Vec<LispVal>,
+}
+
+impl SerializeList {
+    fn new(variant: Option<&str>, len: usize) -> SerializeList {
+        let mut items = Vec::with_capacity(len + 1);
+        items.extend(variant.map(name));
+        SerializeList { items }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.items))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.items))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.items))
+    }
+}
+
+/// An association list of `(key . value)` pairs, headed by the variant for the
+/// variants of enums.
+pub struct SerializeAlist {
+    list: SerializeList,
+    /// the key waiting for its value
+    key: Option<LispVal>,
+}
+
+impl SerializeAlist {
+    fn new(variant: Option<&str>) -> SerializeAlist {
+        SerializeAlist {
+            list: SerializeList::new(variant, 0),
+            key: None,
+        }
+    }
+
+    fn entry<T: Serialize + ?Sized>(&mut self, key: LispVal, value: &T) -> Result<(), Error> {
+        self.list.items.push(pair::cons(key, to_value(value)?));
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for SerializeAlist {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(to_value(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error("a map value was given before its key".to_string()))?;
+        self.entry(key, value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.list.items))
+    }
+}
+
+impl ser::SerializeStruct for SerializeAlist {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entry(name(key), value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.list.items))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeAlist {
+    type Ok = LispVal;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entry(name(key), value)
+    }
+
+    fn end(self) -> Result<LispVal, Error> {
+        Ok(LispVal::List(self.list.items))
+    }
+}